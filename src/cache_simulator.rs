@@ -0,0 +1,207 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fmt;
+use glam::*;
+
+use crate::config::DatasetProvider;
+use crate::retiling::Job;
+
+// Fifo is what DatasetCache currently does: cache_resource only checks hits with the
+// immutable access, so a tile's timestamp is only set when it is (re)loaded
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    Fifo, Lru, Belady, CostAware
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct CacheConfiguration {
+    pub policy: EvictionPolicy,
+    pub capacity: usize
+}
+
+#[derive(Debug, Clone)]
+pub struct TileCosts {
+    // decoded size of one cache slot
+    pub tile_bytes: u64,
+    // bytes transferred per fetch, unless overridden for a specific tile
+    pub fetch_bytes: u64,
+    pub overrides: HashMap<IVec3, u64>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SimulationReport {
+    pub configuration: CacheConfiguration,
+    pub accesses: u64,
+    pub misses: u64,
+    pub bytes_fetched: u64,
+    pub peak_resident: usize,
+    // DatasetCache allocates every slot up front, so this is the whole capacity however few tiles were resident
+    pub peak_memory: u64
+}
+
+impl TileCosts {
+    pub fn uniform(tile_bytes: u64) -> Self {
+        TileCosts {
+            tile_bytes,
+            fetch_bytes: tile_bytes,
+            overrides: HashMap::new()
+        }
+    }
+    // compressed sizes aren't known up front, so the raw size is used as an upper bound
+    pub fn for_provider(dp: &DatasetProvider) -> Self {
+        Self::uniform(dp.codec.format.raw_size() as u64)
+    }
+    pub fn fetch_cost(&self, coord: IVec3) -> u64 {
+        *self.overrides.get(&coord).unwrap_or(&self.fetch_bytes)
+    }
+}
+
+impl CacheConfiguration {
+    pub fn sweep(policies: &[EvictionPolicy], capacities: &[usize]) -> Vec<Self> {
+        policies.iter().flat_map(|&policy| {
+            capacities.iter().map(move |&capacity| CacheConfiguration { policy, capacity })
+        }).collect()
+    }
+}
+
+impl fmt::Display for SimulationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f, "{:<10} {:>6} slots: {:>8} misses / {:>8} accesses, {:>12} bytes fetched, peak {:>12} bytes",
+            format!("{:?}", self.configuration.policy),
+            self.configuration.capacity,
+            self.misses,
+            self.accesses,
+            self.bytes_fetched,
+            self.peak_memory
+        )
+    }
+}
+
+// Order in which process_all_jobs calls cache_resource
+pub fn access_sequence(jobs: &[Job]) -> Vec<IVec3> {
+    jobs.iter().flat_map(|job| job.sample_regions.iter().map(|region| region.input_coord)).collect()
+}
+
+// For every access, the index of the next access to the same tile (or usize::MAX)
+fn next_uses(sequence: &[IVec3]) -> Vec<usize> {
+    let mut res = vec![usize::MAX; sequence.len()];
+    let mut last_seen = HashMap::<IVec3, usize>::new();
+    for (i, coord) in sequence.iter().enumerate().rev() {
+        if let Some(&next) = last_seen.get(coord) {
+            res[i] = next;
+        }
+        last_seen.insert(*coord, i);
+    }
+    res
+}
+
+pub fn simulate_sequence(sequence: &[IVec3], costs: &TileCosts, configuration: CacheConfiguration) -> SimulationReport {
+    let next = next_uses(sequence);
+
+    // per resident tile the eviction priority, lowest is evicted first
+    let mut resident = HashMap::<IVec3, f64>::new();
+    // GreedyDual inflation value for CostAware
+    let mut inflation = 0.0;
+
+    let mut report = SimulationReport {
+        configuration,
+        accesses: 0,
+        misses: 0,
+        bytes_fetched: 0,
+        peak_resident: 0,
+        peak_memory: configuration.capacity as u64 * costs.tile_bytes
+    };
+
+    for (time, &coord) in sequence.iter().enumerate() {
+        report.accesses += 1;
+
+        let cost = costs.fetch_cost(coord);
+        let priority = match configuration.policy {
+            EvictionPolicy::Fifo | EvictionPolicy::Lru => time as f64,
+            EvictionPolicy::Belady => -(next[time] as f64),
+            EvictionPolicy::CostAware => inflation + cost as f64 / costs.tile_bytes.max(1) as f64
+        };
+
+        if let Some(p) = resident.get_mut(&coord) {
+            if configuration.policy != EvictionPolicy::Fifo {
+                *p = priority;
+            }
+            continue;
+        }
+
+        report.misses += 1;
+        report.bytes_fetched += cost;
+
+        if configuration.capacity == 0 {
+            continue;
+        }
+
+        if resident.len() >= configuration.capacity {
+            let (&victim, &victim_priority)
+                =resident.iter()
+                .min_by(|a, b| a.1.partial_cmp(b.1).unwrap())
+                .unwrap();
+            resident.remove(&victim);
+            if configuration.policy == EvictionPolicy::CostAware {
+                inflation = victim_priority;
+            }
+        }
+
+        let priority = match configuration.policy {
+            EvictionPolicy::CostAware => inflation + cost as f64 / costs.tile_bytes.max(1) as f64,
+            _ => priority
+        };
+        resident.insert(coord, priority);
+        report.peak_resident = report.peak_resident.max(resident.len());
+    }
+
+    report
+}
+
+pub fn simulate(jobs: &[Job], costs: &TileCosts, configuration: CacheConfiguration) -> SimulationReport {
+    simulate_sequence(&access_sequence(jobs), costs, configuration)
+}
+
+pub fn simulate_all(jobs: &[Job], costs: &TileCosts, configurations: &[CacheConfiguration]) -> Vec<SimulationReport> {
+    let sequence = access_sequence(jobs);
+    configurations.iter().map(|&configuration| simulate_sequence(&sequence, costs, configuration)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sequence(names: &str) -> Vec<IVec3> {
+        names.bytes().map(|b| ivec3(b as i32, 0, 0)).collect()
+    }
+
+    fn misses(sequence: &[IVec3], costs: &TileCosts, policy: EvictionPolicy, capacity: usize) -> u64 {
+        simulate_sequence(sequence, costs, CacheConfiguration { policy, capacity }).misses
+    }
+
+    #[test]
+    fn belady_misses_least() {
+        let costs = TileCosts::uniform(100);
+        for (names, fifo, lru, belady) in [("abacab", 5, 4, 4), ("abcabc", 6, 6, 4), ("aaaa", 1, 1, 1)] {
+            let sequence = sequence(names);
+            let counts = [EvictionPolicy::Fifo, EvictionPolicy::Lru, EvictionPolicy::Belady].map(|policy| misses(&sequence[..], &costs, policy, 2));
+            assert_eq!(counts, [fifo, lru, belady], "{}", names);
+            assert!(counts[2] <= counts[1] && counts[1] <= counts[0]);
+        }
+    }
+
+    #[test]
+    fn cost_aware_keeps_expensive_tiles() {
+        let sequence = sequence("exye");
+        let mut costs = TileCosts::uniform(100);
+        costs.overrides.insert(ivec3(b'e' as i32, 0, 0), 1000);
+
+        let lru = simulate_sequence(&sequence[..], &costs, CacheConfiguration { policy: EvictionPolicy::Lru, capacity: 2 });
+        let cost_aware = simulate_sequence(&sequence[..], &costs, CacheConfiguration { policy: EvictionPolicy::CostAware, capacity: 2 });
+        assert_eq!((lru.misses, lru.bytes_fetched), (4, 2200));
+        assert_eq!((cost_aware.misses, cost_aware.bytes_fetched), (3, 1200));
+        // the cache allocates all of its slots whatever was resident
+        assert_eq!(cost_aware.peak_memory, 200);
+    }
+}
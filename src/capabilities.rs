@@ -0,0 +1,172 @@
+use serde::{Serialize, Deserialize};
+use glam::*;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::config::DatasetProvider;
+use crate::image::ImageCodec;
+use crate::tile_server::Dataset;
+use crate::tile_sink::{filetype_extension, WrittenExtent};
+
+// OGC's standardized rendering pixel size in meters
+const WMTS_PIXEL_SIZE: f64 = 0.00028;
+// meters per degree on the semi major axis of WGS 84, for scale denominators of geographic tile matrices
+const WMTS_METERS_PER_DEGREE: f64 = 2.0 * std::f64::consts::PI * 6_378_137.0 / 360.0;
+
+// Zooms and area a dataset has tiles for
+pub struct Coverage {
+    pub extent: WrittenExtent,
+    // per level, one past the largest tile coordinate
+    pub matrix_sizes: BTreeMap<i32, IVec2>
+}
+
+impl Coverage {
+    pub fn of(dataset: &Dataset, dp: &DatasetProvider) -> Self {
+        let mut extent = WrittenExtent::default();
+        let mut matrix_sizes: BTreeMap<i32, IVec2> = BTreeMap::new();
+        for coord in dp.manifest.coords() {
+            extent.add(coord, dataset.url_zoom(coord.z), &dp.tilespace);
+            let size = matrix_sizes.entry(coord.z).or_insert(IVec2::ZERO);
+            *size = size.max(ivec2(coord.x, coord.y) + 1);
+        }
+        Coverage { extent, matrix_sizes }
+    }
+
+    // (west, south, east, north) in degrees, None without a georeference
+    pub fn bounds(&self, dp: &DatasetProvider) -> Option<[f64; 4]> {
+        let georef = dp.tilespace.georef?;
+        let pixels = self.extent.pixel_bounds?;
+        let pixels = match dp.extent {
+            Some(extent) => pixels & extent,
+            None => pixels
+        };
+        Some(georef.bounds(pixels))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TileJson {
+    pub tilejson: String,
+    pub name: String,
+    // colour mapped png renderings
+    pub tiles: Vec<String>,
+    pub minzoom: i32,
+    pub maxzoom: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bounds: Option<[f64; 4]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub center: Option<[f64; 3]>,
+    pub scheme: String,
+    pub format: String,
+    #[serde(rename = "tileSize")]
+    pub tile_size: i32,
+    // not part of TileJSON, the tiles as stored and how to decode them
+    pub raw_tiles: Vec<String>,
+    pub codec: ImageCodec
+}
+
+// base_url is where the server is reachable from the client, without a trailing slash
+pub fn tilejson(dataset: &Dataset, dp: &DatasetProvider, base_url: &str) -> TileJson {
+    let coverage = Coverage::of(dataset, dp);
+    let (minzoom, maxzoom) = match coverage.extent.is_empty() {
+        true  => (0, 0),
+        false => (coverage.extent.min_zoom, coverage.extent.max_zoom)
+    };
+    let bounds = coverage.bounds(dp);
+    let tiles_url = format!("{}/tiles/{}/{{z}}/{{x}}/{{y}}", base_url, dataset.name);
+
+    TileJson {
+        tilejson: "3.0.0".to_string(),
+        name: dataset.name.clone(),
+        tiles: vec![format!("{}.png", tiles_url)],
+        minzoom,
+        maxzoom,
+        bounds,
+        center: bounds.map(|b| [(b[0] + b[2]) / 2.0, (b[1] + b[3]) / 2.0, minzoom as f64]),
+        scheme: "xyz".to_string(),
+        format: "png".to_string(),
+        tile_size: dp.codec.format.size.x,
+        raw_tiles: vec![format!("{}.{}", tiles_url, filetype_extension(dp.codec.filetype))],
+        codec: dp.codec
+    }
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&apos;")
+}
+
+// GetCapabilities for the RESTful binding, one layer and tile matrix set per dataset. Tile matrix identifiers are the
+// zooms of tile URLs and the sets are in EPSG:4326, datasets without a georeference are left out
+pub fn wmts_capabilities(datasets: &[(&Dataset, &DatasetProvider)], base_url: &str) -> String {
+    let mut layers = String::new();
+    let mut matrix_sets = String::new();
+
+    for (dataset, dp) in datasets.iter() {
+        let georef = match dp.tilespace.georef {
+            Some(georef) => georef,
+            None => continue
+        };
+        let coverage = Coverage::of(dataset, dp);
+        let bounds = match coverage.bounds(dp) {
+            Some(bounds) => bounds,
+            None => continue
+        };
+        let name = xml_escape(dataset.name.as_str());
+        let size = dp.codec.format.size;
+
+        let _ = write!(layers, r#"
+    <Layer>
+      <ows:Title>{name}</ows:Title>
+      <ows:WGS84BoundingBox>
+        <ows:LowerCorner>{} {}</ows:LowerCorner>
+        <ows:UpperCorner>{} {}</ows:UpperCorner>
+      </ows:WGS84BoundingBox>
+      <ows:Identifier>{name}</ows:Identifier>
+      <Style isDefault="true"><ows:Identifier>default</ows:Identifier></Style>
+      <Format>image/png</Format>
+      <TileMatrixSetLink><TileMatrixSet>{name}</TileMatrixSet></TileMatrixSetLink>
+      <ResourceURL format="image/png" resourceType="tile" template="{}/tiles/{name}/{{TileMatrix}}/{{TileCol}}/{{TileRow}}.png"/>
+    </Layer>"#,
+            bounds[0], bounds[1], bounds[2], bounds[3], xml_escape(base_url), name = name);
+
+        let _ = write!(matrix_sets, r#"
+    <TileMatrixSet>
+      <ows:Identifier>{}</ows:Identifier>
+      <ows:SupportedCRS>urn:ogc:def:crs:EPSG::4326</ows:SupportedCRS>"#, name);
+        // coarsest first, the order clients expect
+        for (&level, matrix_size) in coverage.matrix_sizes.iter().rev() {
+            let scale = (1i64 << level.max(0)) as f64;
+            let top_left = georef.pixel_to_geo(dp.tilespace.tile_pixels_level(ivec3(0, 0, level)).begin.as_dvec2());
+            let _ = write!(matrix_sets, r#"
+      <TileMatrix>
+        <ows:Identifier>{}</ows:Identifier>
+        <ScaleDenominator>{}</ScaleDenominator>
+        <TopLeftCorner>{} {}</TopLeftCorner>
+        <TileWidth>{}</TileWidth>
+        <TileHeight>{}</TileHeight>
+        <MatrixWidth>{}</MatrixWidth>
+        <MatrixHeight>{}</MatrixHeight>
+      </TileMatrix>"#,
+                dataset.url_zoom(level),
+                georef.pixel_size.x.abs() * scale * WMTS_METERS_PER_DEGREE / WMTS_PIXEL_SIZE,
+                // EPSG:4326 is latitude first
+                top_left.y, top_left.x,
+                size.x, size.y,
+                matrix_size.x, matrix_size.y);
+        }
+        matrix_sets.push_str("\n    </TileMatrixSet>");
+    }
+
+    format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<Capabilities xmlns="http://www.opengis.net/wmts/1.0" xmlns:ows="http://www.opengis.net/ows/1.1" xmlns:xlink="http://www.w3.org/1999/xlink" version="1.0.0">
+  <ows:ServiceIdentification>
+    <ows:Title>tiler</ows:Title>
+    <ows:ServiceType>OGC WMTS</ows:ServiceType>
+    <ows:ServiceTypeVersion>1.0.0</ows:ServiceTypeVersion>
+  </ows:ServiceIdentification>
+  <Contents>{}{}
+  </Contents>
+  <ServiceMetadataURL xlink:href="{}/wmts/1.0.0/WMTSCapabilities.xml"/>
+</Capabilities>
+"#, layers, matrix_sets, xml_escape(base_url))
+}
@@ -0,0 +1,83 @@
+use std::convert::TryInto;
+use std::fmt;
+use sha2::{Digest, Sha256};
+
+// Written in manifests as "sha256:<hex>" or "crc32:<hex>"
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Checksum {
+    Sha256([u8; 32]),
+    Crc32(u32)
+}
+
+impl Checksum {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let (kind, hex) = text.split_once(':').ok_or(format!("Checksum {} has no algorithm, expected sha256:<hex> or crc32:<hex>", text))?;
+        let bytes = decode_hex(hex).ok_or(format!("Checksum {} is not valid hex", text))?;
+        match kind.to_ascii_lowercase().as_str() {
+            "sha256" => Ok(Checksum::Sha256(bytes[..].try_into().map_err(|_| format!("sha256 checksum {} should be 32 bytes", text))?)),
+            "crc32" => Ok(Checksum::Crc32(u32::from_be_bytes(bytes[..].try_into().map_err(|_| format!("crc32 checksum {} should be 4 bytes", text))?))),
+            kind => Err(format!("Unknown checksum algorithm {}", kind))
+        }
+    }
+
+    // Same algorithm as self, over data
+    pub fn compute_like(&self, data: &[u8]) -> Self {
+        match self {
+            Checksum::Sha256(_) => Checksum::Sha256(sha256(data)),
+            Checksum::Crc32(_) => Checksum::Crc32(crc32fast::hash(data))
+        }
+    }
+
+    pub fn verify(&self, data: &[u8]) -> bool {
+        self.compute_like(data) == *self
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Checksum::Sha256(digest) => write!(f, "sha256:{}", encode_hex(&digest[..])),
+            Checksum::Crc32(crc) => write!(f, "crc32:{:08x}", crc)
+        }
+    }
+}
+
+// odd lengths fail on the last pair
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sha256_hex(data: &[u8]) -> String {
+        encode_hex(&sha256(data)[..])
+    }
+
+    #[test]
+    fn sha256_known_answers() {
+        assert_eq!(sha256_hex(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+
+    #[test]
+    fn checksums_round_trip() {
+        let checksum = Checksum::parse("sha256:BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD").unwrap();
+        assert!(checksum.verify(b"abc"));
+        assert!(!checksum.verify(b"abd"));
+        assert_eq!(checksum.to_string(), "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+
+        let crc = Checksum::parse("crc32:352441c2").unwrap();
+        assert!(crc.verify(b"abc"));
+        assert!(Checksum::parse("md5:00").is_err());
+        assert!(Checksum::parse("sha256:abc").is_err());
+    }
+}
@@ -0,0 +1,722 @@
+use glam::*;
+use std::convert::{TryFrom, TryInto};
+use std::io::Read;
+
+use crate::dataset::*;
+use crate::image::{ImageCodec, ImageFiletype, ImageFormat, PixelEncoding};
+use crate::network_util::HttpClient;
+use crate::range_reader::*;
+use crate::tile_source::*;
+use crate::manifest::{Manifest, TileMetadata};
+use crate::util::math::Dabb2;
+
+// Headers and IFDs of COGs are written up front, one read of this size usually covers all of them
+const HEADER_READ_SIZE: u64 = 64 * 1024;
+const MAX_IFDS: usize = 64;
+// Limits on what a file can make us read and allocate. Real COGs have a few dozen tags, the largest being the tile index
+const MAX_IFD_ENTRIES: u64 = 1024;
+const MAX_READ_SIZE: u64 = 64 * 1024 * 1024;
+const MAX_TILE_SIZE: i32 = 4096;
+const MAX_IMAGE_SIZE: u64 = 1 << 30;
+const MAX_CHANNELS: u64 = 4;
+
+const TAG_NEW_SUBFILE_TYPE: u16 = 254;
+const TAG_IMAGE_WIDTH: u16 = 256;
+const TAG_IMAGE_LENGTH: u16 = 257;
+const TAG_BITS_PER_SAMPLE: u16 = 258;
+const TAG_COMPRESSION: u16 = 259;
+const TAG_SAMPLES_PER_PIXEL: u16 = 277;
+const TAG_PLANAR_CONFIGURATION: u16 = 284;
+const TAG_PREDICTOR: u16 = 317;
+const TAG_TILE_WIDTH: u16 = 322;
+const TAG_TILE_LENGTH: u16 = 323;
+const TAG_TILE_OFFSETS: u16 = 324;
+const TAG_TILE_BYTE_COUNTS: u16 = 325;
+const TAG_SAMPLE_FORMAT: u16 = 339;
+const TAG_MODEL_PIXEL_SCALE: u16 = 33550;
+const TAG_MODEL_TIEPOINT: u16 = 33922;
+const TAG_MODEL_TRANSFORMATION: u16 = 34264;
+const TAG_GEO_KEY_DIRECTORY: u16 = 34735;
+const TAG_GDAL_NODATA: u16 = 42113;
+
+const GEO_KEY_RASTER_TYPE: u64 = 1025;
+const RASTER_PIXEL_IS_POINT: u64 = 2;
+
+// NewSubfileType bits
+const SUBFILE_REDUCED: u64 = 1;
+const SUBFILE_MASK: u64 = 4;
+
+const COMPRESSION_NONE: u64 = 1;
+const COMPRESSION_LZW: u64 = 5;
+const COMPRESSION_ADOBE_DEFLATE: u64 = 8;
+const COMPRESSION_DEFLATE: u64 = 32946;
+
+const PREDICTOR_NONE: u64 = 1;
+const PREDICTOR_HORIZONTAL: u64 = 2;
+
+const SAMPLE_FORMAT_UINT: u64 = 1;
+const SAMPLE_FORMAT_INT: u64 = 2;
+const SAMPLE_FORMAT_FLOAT: u64 = 3;
+
+#[derive(Debug, Copy, Clone)]
+struct IfdEntry {
+    tag: u16,
+    field_type: u16,
+    count: u64,
+    // inline value bytes, or the offset of the values when they don't fit
+    value: [u8; 8]
+}
+
+// One full resolution image or overview
+#[derive(Debug, Clone)]
+pub struct CogImage {
+    pub size: IVec2,
+    pub tile_size: IVec2,
+    pub tiles_across: i32,
+    pub tiles_down: i32,
+    pub compression: u64,
+    pub predictor: u64,
+    tile_offsets: Vec<u64>,
+    tile_byte_counts: Vec<u64>
+}
+
+struct TiffReader {
+    reader: Box<dyn RangeReader>,
+    head: Vec<u8>,
+    little_endian: bool,
+    big_tiff: bool
+}
+
+impl TiffReader {
+    async fn read(&self, offset: u64, length: u64) -> Result<Vec<u8>, String> {
+        let end = offset.checked_add(length).ok_or("TIFF offset is out of range")?;
+        if length > MAX_READ_SIZE {
+            return Err(format!("TIFF structure of {} bytes is larger than {}", length, MAX_READ_SIZE));
+        }
+        if end <= self.head.len() as u64 {
+            return Ok(self.head[offset as usize..end as usize].to_vec());
+        }
+        self.reader.read_range(offset, length).await
+    }
+
+    fn u16(&self, b: &[u8]) -> u16 {
+        let b = b[..2].try_into().unwrap();
+        match self.little_endian {
+            true  => u16::from_le_bytes(b),
+            false => u16::from_be_bytes(b)
+        }
+    }
+    fn u32(&self, b: &[u8]) -> u32 {
+        let b = b[..4].try_into().unwrap();
+        match self.little_endian {
+            true  => u32::from_le_bytes(b),
+            false => u32::from_be_bytes(b)
+        }
+    }
+    fn u64(&self, b: &[u8]) -> u64 {
+        let b = b[..8].try_into().unwrap();
+        match self.little_endian {
+            true  => u64::from_le_bytes(b),
+            false => u64::from_be_bytes(b)
+        }
+    }
+    fn offset(&self, b: &[u8]) -> u64 {
+        match self.big_tiff {
+            true  => self.u64(b),
+            false => self.u32(b) as u64
+        }
+    }
+
+    async fn read_ifd(&self, offset: u64) -> Result<(Vec<IfdEntry>, u64), String> {
+        let (count_size, entry_size, offset_size) = match self.big_tiff {
+            true  => (8, 20, 8),
+            false => (2, 12, 4)
+        };
+        let count_bytes = self.read(offset, count_size).await?;
+        let count = match self.big_tiff {
+            true  => self.u64(&count_bytes[..]),
+            false => self.u16(&count_bytes[..]) as u64
+        };
+        if count > MAX_IFD_ENTRIES {
+            return Err(format!("TIFF directory has {} entries, more than {}", count, MAX_IFD_ENTRIES));
+        }
+        let bytes = self.read(offset.checked_add(count_size).ok_or("TIFF offset is out of range")?, count * entry_size + offset_size).await?;
+
+        let mut entries = vec![];
+        for i in 0..count as usize {
+            let e = &bytes[i * entry_size as usize..(i + 1) * entry_size as usize];
+            let mut value = [0u8; 8];
+            let (count, value_bytes) = match self.big_tiff {
+                true  => (self.u64(&e[4..]), &e[12..20]),
+                false => (self.u32(&e[4..]) as u64, &e[8..12])
+            };
+            value[..value_bytes.len()].copy_from_slice(value_bytes);
+            entries.push(IfdEntry {
+                tag: self.u16(&e[0..]),
+                field_type: self.u16(&e[2..]),
+                count,
+                value
+            });
+        }
+        let next = self.offset(&bytes[(count * entry_size) as usize..]);
+        Ok((entries, next))
+    }
+
+    async fn entry_bytes(&self, entry: &IfdEntry) -> Result<Vec<u8>, String> {
+        let length
+            =entry.count.checked_mul(type_size(entry.field_type)?)
+            .ok_or_else(|| format!("Tag {} has too many values", entry.tag))?;
+        let inline = match self.big_tiff {
+            true  => 8,
+            false => 4
+        };
+        match length <= inline {
+            true  => Ok(entry.value[..length as usize].to_vec()),
+            false => self.read(self.offset(&entry.value[..]), length).await
+        }
+    }
+
+    async fn values_u64(&self, entry: &IfdEntry) -> Result<Vec<u64>, String> {
+        let bytes = self.entry_bytes(entry).await?;
+        let size = type_size(entry.field_type)? as usize;
+        bytes.chunks_exact(size).map(|b| match entry.field_type {
+            1 | 7 => Ok(b[0] as u64),
+            3 => Ok(self.u16(b) as u64),
+            4 | 13 => Ok(self.u32(b) as u64),
+            16 | 18 => Ok(self.u64(b)),
+            t => Err(format!("Tag {} has non integer type {}", entry.tag, t))
+        }).collect()
+    }
+
+    async fn values_f64(&self, entry: &IfdEntry) -> Result<Vec<f64>, String> {
+        let bytes = self.entry_bytes(entry).await?;
+        match entry.field_type {
+            12 => Ok(bytes.chunks_exact(8).map(|b| f64::from_bits(self.u64(b))).collect()),
+            11 => Ok(bytes.chunks_exact(4).map(|b| f32::from_bits(self.u32(b)) as f64).collect()),
+            _ => Ok(self.values_u64(entry).await?.iter().map(|&v| v as f64).collect())
+        }
+    }
+
+    // GTRasterTypeGeoKey, whether tiepoints are at the corners or centers of pixels
+    async fn raster_type(&self, entry: &IfdEntry) -> Result<Option<u64>, String> {
+        let keys = self.values_u64(entry).await?;
+        // a header of version, revision, minor revision and count, then (key, location, count, value) with inline values at location 0
+        Ok(keys.get(4..).unwrap_or(&[]).chunks_exact(4)
+            .find(|key| key[0] == GEO_KEY_RASTER_TYPE && key[1] == 0)
+            .map(|key| key[3]))
+    }
+
+    async fn ascii(&self, entry: &IfdEntry) -> Result<String, String> {
+        let bytes = self.entry_bytes(entry).await?;
+        Ok(String::from_utf8_lossy(&bytes[..]).trim_end_matches('\0').to_string())
+    }
+}
+
+fn type_size(field_type: u16) -> Result<u64, String> {
+    match field_type {
+        1 | 2 | 6 | 7 => Ok(1),
+        3 | 8 => Ok(2),
+        4 | 9 | 11 | 13 => Ok(4),
+        5 | 10 | 12 | 16 | 17 | 18 => Ok(8),
+        t => Err(format!("Unknown TIFF field type {}", t))
+    }
+}
+
+fn find(entries: &[IfdEntry], tag: u16) -> Option<&IfdEntry> {
+    entries.iter().find(|e| e.tag == tag)
+}
+
+async fn required_u64(tiff: &TiffReader, entries: &[IfdEntry], tag: u16) -> Result<u64, String> {
+    let entry = find(entries, tag).ok_or(format!("Missing TIFF tag {}", tag))?;
+    tiff.values_u64(entry).await?.first().copied().ok_or(format!("Empty TIFF tag {}", tag))
+}
+
+async fn optional_u64(tiff: &TiffReader, entries: &[IfdEntry], tag: u16, default: u64) -> Result<u64, String> {
+    match find(entries, tag) {
+        Some(entry) => Ok(tiff.values_u64(entry).await?.first().copied().unwrap_or(default)),
+        None => Ok(default)
+    }
+}
+
+// Stops at limit bytes of output, a tile inflating to more than it can hold is refused before it's all in memory
+fn decompress(data: Vec<u8>, compression: u64, limit: usize) -> Result<Vec<u8>, String> {
+    let too_large = || format!("Tile decompresses to more than {} bytes", limit);
+    match compression {
+        COMPRESSION_NONE => Ok(data),
+        COMPRESSION_ADOBE_DEFLATE | COMPRESSION_DEFLATE => {
+            let mut res = vec![];
+            flate2::read::ZlibDecoder::new(&data[..]).take(limit as u64 + 1).read_to_end(&mut res).map_err(|e| e.to_string())?;
+            match res.len() > limit {
+                true  => Err(too_large()),
+                false => Ok(res)
+            }
+        },
+        COMPRESSION_LZW => {
+            let mut decoder = weezl::decode::Decoder::with_tiff_size_switch(weezl::BitOrder::Msb, 8);
+            let mut res = vec![0; limit + 1];
+            let (mut read, mut written) = (0, 0);
+            loop {
+                let result = decoder.decode_bytes(&data[read..], &mut res[written..]);
+                read += result.consumed_in;
+                written += result.consumed_out;
+                match result.status.map_err(|e| e.to_string())? {
+                    _ if written > limit => return Err(too_large()),
+                    weezl::LzwStatus::Done => break,
+                    weezl::LzwStatus::NoProgress => return Err("LZW data ends without an end code".to_string()),
+                    weezl::LzwStatus::Ok => {}
+                }
+            }
+            res.truncate(written);
+            Ok(res)
+        },
+        c => Err(format!("Unsupported TIFF compression {}", c))
+    }
+}
+
+// Undoes horizontal differencing on native endian samples
+fn undo_predictor<T: Copy + num::traits::WrappingAdd>(samples: &mut [T], row_samples: usize, channels: usize) {
+    for row in samples.chunks_exact_mut(row_samples) {
+        for i in channels..row.len() {
+            row[i] = row[i].wrapping_add(&row[i - channels]);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CogTileSource {
+    pub uri: String,
+    pub codec: ImageCodec,
+    pub georef: Option<GeoTransform>,
+    pub nodata: Option<f64>,
+    // index is the level, 0 is full resolution and every following one an overview at half the resolution
+    pub levels: Vec<CogImage>,
+    little_endian: bool,
+    reader: Box<dyn RangeReader>
+}
+
+impl CogTileSource {
+    pub async fn open(uri: &str, client: HttpClient) -> Result<Self, String> {
+        let reader = open_range_reader(uri, client);
+
+        // a head read shorter than the file fails, fall back to reading just the header
+        let head = match reader.read_range(0, HEADER_READ_SIZE).await {
+            Ok(head) => head,
+            Err(_) => reader.read_range(0, 16).await?
+        };
+        let little_endian = match &head[0..2] {
+            b"II" => true,
+            b"MM" => false,
+            _ => return Err(format!("{} is not a TIFF file", uri))
+        };
+        let mut tiff = TiffReader { reader, head, little_endian, big_tiff: false };
+        let first_ifd = match tiff.u16(&tiff.head[2..]) {
+            42 => tiff.u32(&tiff.head[4..]) as u64,
+            43 => {
+                tiff.big_tiff = true;
+                tiff.u64(&tiff.head[8..])
+            },
+            v => return Err(format!("{} has unknown TIFF version {}", uri, v))
+        };
+
+        let mut ifds = vec![];
+        let mut next = first_ifd;
+        while next != 0 && ifds.len() < MAX_IFDS {
+            let (entries, n) = tiff.read_ifd(next).await?;
+            ifds.push(entries);
+            next = n;
+        }
+        let full = ifds.first().ok_or(format!("{} has no images", uri))?;
+
+        let bit_depth = required_u64(&tiff, full, TAG_BITS_PER_SAMPLE).await? as i32;
+        let channels = optional_u64(&tiff, full, TAG_SAMPLES_PER_PIXEL, 1).await?;
+        if !(1..=MAX_CHANNELS).contains(&channels) {
+            return Err(format!("{} has {} channels, at most {} are supported", uri, channels, MAX_CHANNELS));
+        }
+        let channels = channels as i32;
+        let signed = match optional_u64(&tiff, full, TAG_SAMPLE_FORMAT, SAMPLE_FORMAT_UINT).await? {
+            SAMPLE_FORMAT_UINT => false,
+            SAMPLE_FORMAT_INT => true,
+            // the tile pipeline has no float samples, GDAL can convert them with -ot Int16 or Int32 and a -scale
+            SAMPLE_FORMAT_FLOAT => return Err(format!("{} has floating point samples, only integer samples are supported", uri)),
+            f => return Err(format!("{} has sample format {}, only integer samples are supported", uri, f))
+        };
+        if bit_depth != 8 && bit_depth != 16 && bit_depth != 32 {
+            return Err(format!("{} has {} bit samples, only 8, 16 and 32 are supported", uri, bit_depth));
+        }
+        if optional_u64(&tiff, full, TAG_PLANAR_CONFIGURATION, 1).await? != 1 {
+            return Err(format!("{} stores channels in separate planes, only interleaved is supported", uri));
+        }
+
+        let mut levels: Vec<CogImage> = vec![];
+        for entries in ifds.iter() {
+            let subfile = optional_u64(&tiff, entries, TAG_NEW_SUBFILE_TYPE, 0).await?;
+            if subfile & SUBFILE_MASK != 0 || (levels.is_empty() && subfile & SUBFILE_REDUCED != 0) {
+                continue;
+            }
+            if find(entries, TAG_TILE_WIDTH).is_none() {
+                return Err(format!("{} is striped, only tiled TIFFs can be read", uri));
+            }
+
+            let size = u64vec2(
+                required_u64(&tiff, entries, TAG_IMAGE_WIDTH).await?,
+                required_u64(&tiff, entries, TAG_IMAGE_LENGTH).await?
+            );
+            let tile_size = u64vec2(
+                required_u64(&tiff, entries, TAG_TILE_WIDTH).await?,
+                required_u64(&tiff, entries, TAG_TILE_LENGTH).await?
+            );
+            if size.min_element() == 0 || size.max_element() > MAX_IMAGE_SIZE || tile_size.min_element() == 0 || tile_size.max_element() > MAX_TILE_SIZE as u64 {
+                return Err(format!("{} has an image of {} in tiles of {}, images can be {} and tiles {} wide at most", uri, size, tile_size, MAX_IMAGE_SIZE, MAX_TILE_SIZE));
+            }
+            let (size, tile_size) = (size.as_ivec2(), tile_size.as_ivec2());
+
+            // Overviews only map onto levels when they are exact halvings with the same tile size
+            if let Some(first) = levels.first() {
+                let divisor = match 1i32.checked_shl(levels.len() as u32) {
+                    Some(divisor) if divisor > 0 => divisor,
+                    _ => break
+                };
+                let expected = (first.size + divisor - 1) / divisor;
+                if tile_size != first.tile_size || (size - expected).abs().max_element() > 1 {
+                    break;
+                }
+            }
+
+            let tile_offsets = tiff.values_u64(find(entries, TAG_TILE_OFFSETS).ok_or("Missing tile offsets")?).await?;
+            let tile_byte_counts = tiff.values_u64(find(entries, TAG_TILE_BYTE_COUNTS).ok_or("Missing tile byte counts")?).await?;
+            let tiles_across = (size.x + tile_size.x - 1) / tile_size.x;
+            let tiles_down = (size.y + tile_size.y - 1) / tile_size.y;
+            if (tile_offsets.len() as i64) < tiles_across as i64 * tiles_down as i64 || tile_byte_counts.len() != tile_offsets.len() {
+                return Err(format!("{} has an incomplete tile index", uri));
+            }
+
+            levels.push(CogImage {
+                size,
+                tile_size,
+                tiles_across,
+                tiles_down,
+                compression: optional_u64(&tiff, entries, TAG_COMPRESSION, COMPRESSION_NONE).await?,
+                predictor: optional_u64(&tiff, entries, TAG_PREDICTOR, PREDICTOR_NONE).await?,
+                tile_offsets,
+                tile_byte_counts
+            });
+        }
+        let tile_size = levels.first().ok_or(format!("{} has no full resolution image", uri))?.tile_size;
+
+        // PixelIsPoint tiepoints are at pixel centers, the georeference here is at corners like GDAL's
+        let pixel_is_point = match find(full, TAG_GEO_KEY_DIRECTORY) {
+            Some(entry) => tiff.raster_type(entry).await? == Some(RASTER_PIXEL_IS_POINT),
+            None => false
+        };
+        let georef = match (
+            find(full, TAG_MODEL_PIXEL_SCALE),
+            find(full, TAG_MODEL_TIEPOINT),
+            find(full, TAG_MODEL_TRANSFORMATION)
+        ) {
+            (Some(scale), Some(tiepoint), _) => {
+                let scale = tiff.values_f64(scale).await?;
+                let tiepoint = tiff.values_f64(tiepoint).await?;
+                match (scale.len() >= 2, tiepoint.len() >= 6) {
+                    (true, true) => Some(GeoTransform {
+                        origin: dvec2(tiepoint[3] - tiepoint[0] * scale[0], tiepoint[4] + tiepoint[1] * scale[1]),
+                        pixel_size: dvec2(scale[0], -scale[1])
+                    }),
+                    _ => None
+                }
+            },
+            // only the axis aligned part, rotated rasters aren't supported
+            (_, _, Some(transform)) => {
+                let m = tiff.values_f64(transform).await?;
+                match m.len() >= 8 {
+                    true  => Some(GeoTransform {
+                        origin: dvec2(m[3], m[7]),
+                        pixel_size: dvec2(m[0], m[5])
+                    }),
+                    false => None
+                }
+            },
+            _ => None
+        };
+        let georef = match pixel_is_point {
+            true  => georef.map(|g| GeoTransform { origin: g.origin - g.pixel_size * 0.5, ..g }),
+            false => georef
+        };
+
+        let nodata = match find(full, TAG_GDAL_NODATA) {
+            Some(entry) => tiff.ascii(entry).await?.trim().parse::<f64>().ok(),
+            None => None
+        };
+
+        Ok(CogTileSource {
+            uri: uri.to_string(),
+            codec: ImageCodec {
+                format: ImageFormat {
+                    encoding: PixelEncoding {
+                        bit_depth,
+                        gamma: 1.0,
+                        channels,
+                        swap_endian: false,
+                        signed
+                    },
+                    size: tile_size
+                },
+                filetype: ImageFiletype::Raw
+            },
+            georef,
+            nodata,
+            levels,
+            little_endian,
+            reader: tiff.reader
+        })
+    }
+
+    fn tile_index(&self, coord: IVec3) -> Option<(&CogImage, usize)> {
+        let image = self.levels.get(usize::try_from(coord.z).ok()?)?;
+        if coord.x < 0 || coord.y < 0 || coord.x >= image.tiles_across || coord.y >= image.tiles_down {
+            return None;
+        }
+        let i = coord.y as usize * image.tiles_across as usize + coord.x as usize;
+        // sparse COGs leave empty tiles out
+        match image.tile_byte_counts[i] {
+            0 => None,
+            _ => Some((image, i))
+        }
+    }
+
+    // Decompressed tile bytes to native endian samples in the layout of self.codec
+    fn decode_tile(&self, image: &CogImage, data: Vec<u8>) -> Result<Vec<u8>, String> {
+        let raw_size = self.codec.format.raw_size();
+        let mut data = decompress(data, image.compression, raw_size)?;
+        if data.len() < raw_size {
+            return Err(format!("Tile decompressed to {} bytes, expected {}", data.len(), raw_size));
+        }
+        data.truncate(raw_size);
+
+        let encoding = self.codec.format.encoding;
+        let channels = encoding.channels as usize;
+        let row_samples = image.tile_size.x as usize * channels;
+        let predict = match image.predictor {
+            PREDICTOR_NONE => false,
+            PREDICTOR_HORIZONTAL => true,
+            p => return Err(format!("Unsupported TIFF predictor {}", p))
+        };
+        let swap = self.little_endian != cfg!(target_endian = "little");
+
+        match encoding.bit_depth {
+            8 => {
+                if predict {
+                    undo_predictor(&mut data[..], row_samples, channels);
+                }
+            },
+            16 => {
+                let mut samples: Vec<u16> = data.chunks_exact(2).map(|b| {
+                    let v = u16::from_ne_bytes([b[0], b[1]]);
+                    if swap { v.swap_bytes() } else { v }
+                }).collect();
+                if predict {
+                    undo_predictor(&mut samples[..], row_samples, channels);
+                }
+                data = samples.iter().flat_map(|v| v.to_ne_bytes()).collect();
+            },
+            32 => {
+                let mut samples: Vec<u32> = data.chunks_exact(4).map(|b| {
+                    let v = u32::from_ne_bytes([b[0], b[1], b[2], b[3]]);
+                    if swap { v.swap_bytes() } else { v }
+                }).collect();
+                if predict {
+                    undo_predictor(&mut samples[..], row_samples, channels);
+                }
+                data = samples.iter().flat_map(|v| v.to_ne_bytes()).collect();
+            },
+            bit_depth => return Err(format!("Unsupported bit depth {}", bit_depth))
+        }
+        Ok(data)
+    }
+}
+
+impl TileSource for CogTileSource {
+    fn fetch_tile(&self, coord: IVec3) -> FetchFuture<'_, Vec<u8>> {
+        Box::pin(async move {
+            let (image, i) = self.tile_index(coord).ok_or(FetchError::Missing)?;
+            if image.tile_byte_counts[i] > MAX_READ_SIZE {
+                return Err(FetchError::Invalid(format!("{} tile {:?} is {} bytes, larger than {}", self.uri, coord, image.tile_byte_counts[i], MAX_READ_SIZE)));
+            }
+            let data
+                =self.reader.read_range(image.tile_offsets[i], image.tile_byte_counts[i]).await
+                .map_err(|e| format!("{}: {}", self.uri, e))?;
+            Ok(self.decode_tile(image, data).map_err(|e| format!("{} tile {:?}: {}", self.uri, coord, e))?)
+        })
+    }
+    fn list_tiles(&self) -> SourceFuture<'_, Manifest> {
+        Box::pin(async move {
+            let mut res = Manifest::default();
+            for (level, image) in self.levels.iter().enumerate() {
+                for y in 0..image.tiles_down {
+                    for x in 0..image.tiles_across {
+                        // no size, the byte counts are of the compressed tiles while fetch_tile returns them decoded
+                        if image.tile_byte_counts[y as usize * image.tiles_across as usize + x as usize] != 0 {
+                            res.insert(ivec3(x, y, level as i32), TileMetadata::default());
+                        }
+                    }
+                }
+            }
+            Ok(res)
+        })
+    }
+    fn dataset_info(&self) -> Option<DatasetInfo> {
+        Some(DatasetInfo {
+            codec: self.codec,
+            tilespace: Tilespace {
+                size: self.codec.format.size,
+                offset: ivec2(0, 0),
+                georef: self.georef
+            },
+            max_zoom: self.levels.len() as i32 - 1,
+            nodata: self.nodata,
+            extent: self.levels.first().map(|image| Dabb2::bounds(IVec2::ZERO, image.size))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TYPE_SHORT: u16 = 3;
+    const TYPE_LONG: u16 = 4;
+    const TYPE_DOUBLE: u16 = 12;
+
+    struct Tag(u16, u16, Vec<u8>);
+
+    fn shorts(tag: u16, values: &[u16]) -> Tag {
+        Tag(tag, TYPE_SHORT, values.iter().flat_map(|v| v.to_le_bytes()).collect())
+    }
+    fn longs(tag: u16, values: &[u32]) -> Tag {
+        Tag(tag, TYPE_LONG, values.iter().flat_map(|v| v.to_le_bytes()).collect())
+    }
+    fn doubles(tag: u16, values: &[f64]) -> Tag {
+        Tag(tag, TYPE_DOUBLE, values.iter().flat_map(|v| v.to_le_bytes()).collect())
+    }
+
+    // Little endian TIFF with a 4x4 image of i16 in 2x2 tiles, tile i holding 10 * i to 10 * i + 3
+    fn fixture(extra: Vec<Tag>) -> Vec<u8> {
+        let tiles: Vec<u8> = (0..16i16).flat_map(|i| (i / 4 * 10 + i % 4).to_le_bytes()).collect();
+        let mut tags = vec![
+            shorts(TAG_IMAGE_WIDTH, &[4]),
+            shorts(TAG_IMAGE_LENGTH, &[4]),
+            shorts(TAG_BITS_PER_SAMPLE, &[16]),
+            shorts(TAG_TILE_WIDTH, &[2]),
+            shorts(TAG_TILE_LENGTH, &[2]),
+            longs(TAG_TILE_OFFSETS, &[0, 8, 16, 24]),
+            longs(TAG_TILE_BYTE_COUNTS, &[8, 8, 8, 8]),
+            shorts(TAG_SAMPLE_FORMAT, &[SAMPLE_FORMAT_INT as u16]),
+            doubles(TAG_MODEL_PIXEL_SCALE, &[0.5, 0.5, 0.0]),
+            doubles(TAG_MODEL_TIEPOINT, &[0.0, 0.0, 0.0, 10.0, 50.0, 0.0])
+        ];
+        for tag in extra {
+            tags.retain(|t| t.0 != tag.0);
+            tags.push(tag);
+        }
+        tags.sort_by_key(|t| t.0);
+
+        let ifd_len = 2 + tags.len() * 12 + 4;
+        let mut external = vec![];
+        let external_offset = 8 + ifd_len;
+        let tiles_offset = external_offset + tags.iter().map(|t| if t.2.len() > 4 { t.2.len() } else { 0 }).sum::<usize>();
+
+        let mut res = b"II".to_vec();
+        res.extend_from_slice(&42u16.to_le_bytes());
+        res.extend_from_slice(&8u32.to_le_bytes());
+        res.extend_from_slice(&(tags.len() as u16).to_le_bytes());
+        for Tag(tag, field_type, bytes) in tags.iter() {
+            let bytes = match *tag {
+                TAG_TILE_OFFSETS => (0..4).flat_map(|i| (tiles_offset as u32 + i * 8).to_le_bytes()).collect(),
+                _ => bytes.clone()
+            };
+            res.extend_from_slice(&tag.to_le_bytes());
+            res.extend_from_slice(&field_type.to_le_bytes());
+            res.extend_from_slice(&((bytes.len() as u64 / type_size(*field_type).unwrap()) as u32).to_le_bytes());
+            match bytes.len() > 4 {
+                true  => {
+                    res.extend_from_slice(&((external_offset + external.len()) as u32).to_le_bytes());
+                    external.extend_from_slice(&bytes[..]);
+                },
+                false => {
+                    let mut inline = bytes.clone();
+                    inline.resize(4, 0);
+                    res.extend_from_slice(&inline[..]);
+                }
+            }
+        }
+        res.extend_from_slice(&0u32.to_le_bytes());
+        res.extend_from_slice(&external[..]);
+        res.extend_from_slice(&tiles[..]);
+        res
+    }
+
+    async fn open(name: &str, tiff: Vec<u8>) -> Result<CogTileSource, String> {
+        let path = std::env::temp_dir().join(format!("tiler-{}-{}.tif", std::process::id(), name)).to_string_lossy().into_owned();
+        std::fs::write(path.as_str(), tiff).unwrap();
+        CogTileSource::open(path.as_str(), HttpClient::shared()).await
+    }
+
+    #[tokio::test]
+    async fn reads_tiles() {
+        let cog = open("tiles", fixture(vec![])).await.unwrap();
+        assert_eq!(cog.levels.len(), 1);
+        assert_eq!(cog.codec.format.size, ivec2(2, 2));
+        assert!(cog.codec.format.encoding.signed);
+        assert_eq!(cog.list_tiles().await.unwrap().len(), 4);
+
+        let tile = cog.fetch_tile(ivec3(1, 1, 0)).await.unwrap();
+        let samples: Vec<i16> = tile.chunks_exact(2).map(|b| i16::from_ne_bytes([b[0], b[1]])).collect();
+        assert_eq!(samples, vec![30, 31, 32, 33]);
+        assert_eq!(cog.fetch_tile(ivec3(2, 0, 0)).await, Err(FetchError::Missing));
+    }
+
+    #[tokio::test]
+    async fn shifts_pixel_is_point_to_corners() {
+        let area = open("area", fixture(vec![])).await.unwrap().georef.unwrap();
+        assert_eq!((area.origin, area.pixel_size), (dvec2(10.0, 50.0), dvec2(0.5, -0.5)));
+
+        let keys = shorts(TAG_GEO_KEY_DIRECTORY, &[1, 1, 0, 1, GEO_KEY_RASTER_TYPE as u16, 0, 1, RASTER_PIXEL_IS_POINT as u16]);
+        let point = open("point", fixture(vec![keys])).await.unwrap().georef.unwrap();
+        assert_eq!(point.origin, dvec2(9.75, 50.25));
+    }
+
+    #[test]
+    fn stops_decompressing_at_the_tile_size() {
+        use std::io::Write;
+        let zeros = vec![0u8; 100_000];
+
+        let mut deflate = flate2::write::ZlibEncoder::new(vec![], flate2::Compression::best());
+        deflate.write_all(&zeros[..]).unwrap();
+        let deflate = deflate.finish().unwrap();
+        assert!(decompress(deflate.clone(), COMPRESSION_DEFLATE, 8).unwrap_err().contains("more than 8 bytes"));
+        assert_eq!(decompress(deflate, COMPRESSION_DEFLATE, zeros.len()).unwrap(), zeros);
+
+        let lzw = weezl::encode::Encoder::with_tiff_size_switch(weezl::BitOrder::Msb, 8).encode(&zeros[..]).unwrap();
+        assert!(decompress(lzw.clone(), COMPRESSION_LZW, 8).unwrap_err().contains("more than 8 bytes"));
+        assert_eq!(decompress(lzw, COMPRESSION_LZW, zeros.len()).unwrap(), zeros);
+    }
+
+    #[tokio::test]
+    async fn rejects_what_it_cant_read() {
+        let float = open("float", fixture(vec![shorts(TAG_SAMPLE_FORMAT, &[SAMPLE_FORMAT_FLOAT as u16])])).await;
+        assert!(float.unwrap_err().contains("floating point"));
+        assert!(open("huge-tiles", fixture(vec![longs(TAG_TILE_WIDTH, &[1 << 20])])).await.is_err());
+        assert!(open("empty-tiles", fixture(vec![shorts(TAG_TILE_WIDTH, &[0])])).await.is_err());
+
+        // a directory claiming more entries than any real one
+        let mut entries = fixture(vec![]);
+        entries[8..10].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert!(open("entries", entries).await.is_err());
+
+        // tile offsets claiming 2^30 values
+        let mut index = fixture(vec![]);
+        let at = 10 + 12 * 5;
+        assert_eq!(u16::from_le_bytes([index[at], index[at + 1]]), TAG_TILE_OFFSETS);
+        index[at + 4..at + 8].copy_from_slice(&(1u32 << 30).to_le_bytes());
+        assert!(open("index", index).await.is_err());
+    }
+}
@@ -1,76 +1,305 @@
-use serde::{Serialize, Deserialize};
 use crate::dataset_cache::*;
-use crate::image::{ImageBacked, ImageCodec};
+use crate::image::{sample_reader, ImageBacked, ImageCodec, ImageFormat, ImageOwned};
+use crate::util::math::*;
 use glam::*;
-use core::time::Duration;
-use crate::network_util::*;
 use crate::dataset::*;
+use crate::tile_source::*;
+use crate::manifest::Manifest;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Debug)]
 pub struct DatasetProvider {
-    pub tile_uri_format: String,
+    pub source: Box<dyn TileSource>,
     pub codec: ImageCodec,
     pub tilespace: Tilespace,
-    pub manifest: Vec<IVec3>,
-    pub cache: DatasetCache
+    pub manifest: Manifest,
+    pub cache: DatasetCache,
+    // samples with this value or outside extent are treated as missing by point queries
+    pub nodata: Option<f64>,
+    pub extent: Option<Dabb2>,
+    // fetches that didn't match the manifest's size or checksum, including ones that succeeded when refetched
+    pub corrupt_fetches: usize,
+    // tiles fetched from the source and their encoded bytes, refetches included and cache hits not
+    pub tiles_fetched: usize,
+    pub bytes_fetched: u64,
+    // highest level in the manifest, None when it's empty
+    pub max_level: Option<i32>
 }
 
-impl TileURIProvider for DatasetProvider {
-    fn get_resource_uri(&self, coord: IVec3) -> String {
-        match format_tile_string(self.tile_uri_format.as_str(), coord) {
-            Ok(str) => str,
-            Err(_) => panic!("format string is expected to be valid")
-        }            
+// Tries per tile when fetched bytes don't match the manifest
+const VERIFY_ATTEMPTS: usize = 3;
+
+fn cache_key(coord: IVec3) -> String {
+    format!("{}_{}_{}", coord.x, coord.y, coord.z)
+}
+
+// Finest levels are 0, past this a level 0 offset or pixel no longer fits the scale in an i32
+pub const MAX_LEVEL: i32 = 30;
+
+// Level 0 pixels across a pixel of level, None for levels no dataset can have
+fn level_scale(level: i32) -> Option<i32> {
+    match (0..=MAX_LEVEL).contains(&level) {
+        true  => 1i32.checked_shl(level as u32),
+        false => None
     }
 }
 
 impl DatasetProvider {
     // tilespace will be whatever size code has, with an offset of 0
     pub async fn create(tile_uri_format: &str, codec: ImageCodec, manifest_uri: &str) -> Result<Self, String> {
-        // Verify that tile format can produce a valid result
-        format_tile_string(tile_uri_format, ivec3(0,0,0))?;
+        Self::open(&TileSourceConfig::from_uris(tile_uri_format, manifest_uri), codec).await
+    }
+    pub async fn open(config: &TileSourceConfig, codec: ImageCodec) -> Result<Self, String> {
+        Self::from_source(config.open().await?, codec).await
+    }
+    // Uses the codec and tilespace the source describes itself with
+    pub async fn open_described(config: &TileSourceConfig) -> Result<Self, String> {
+        let source = config.open().await?;
+        let codec = source.dataset_info().ok_or("Tile source has no dataset description, a codec has to be configured")?.codec;
+        Self::from_source(source, codec).await
+    }
+    pub async fn from_source(source: Box<dyn TileSource>, codec: ImageCodec) -> Result<Self, String> {
+        let manifest = source.list_tiles().await?;
+
+        let tilespace = match source.dataset_info() {
+            Some(info) if info.tilespace.size == codec.format.size => info.tilespace,
+            _ => Tilespace {
+                offset: ivec2(0,0),
+                size: codec.format.size,
+                georef: None
+            }
+        };
+
+        let nodata = source.dataset_info().and_then(|info| info.nodata);
+        let extent = source.dataset_info().and_then(|info| info.extent);
+        let max_level = manifest.coords().map(|coord| coord.z).max();
 
         Ok(DatasetProvider {
-            tile_uri_format: tile_uri_format.to_string(),
+            source,
             codec,
-            tilespace: Tilespace {
-                offset: ivec2(0,0),
-                size: codec.format.size
-            },
-            manifest: parse_json_from_uri(manifest_uri).await?,
-            cache: DatasetCache::new(codec.format.raw_size(), 16)
+            tilespace,
+            manifest,
+            cache: DatasetCache::new(codec.format.raw_size(), 16),
+            nodata,
+            extent,
+            corrupt_fetches: 0,
+            tiles_fetched: 0,
+            bytes_fetched: 0,
+            max_level
         })
     }
-    pub async fn cache_resource(&mut self, coord: IVec3) -> Result<(),String> {
-        if !self.manifest.iter().any(|&c| c == coord) {
-            return Ok(());
+    pub async fn cache_resource(&mut self, coord: IVec3) -> Result<(), FetchError> {
+        if !self.manifest.contains(coord) {
+            return Err(FetchError::Missing);
         }
-        let uri = self.get_resource_uri(coord);
-        if let Some(_) = self.cache.access(uri.as_str()) {
+        let key = cache_key(coord);
+        if let Some(_) = self.cache.access(key.as_str()) {
             return Ok(());
         }
 
-        let client
-            =reqwest::Client::builder()
-            .timeout(Duration::from_secs(30))
-            .build().unwrap();
-        
-        let bytes
-            =client
-            .get(uri.clone())
-            .send().await.map_err(|e| e.to_string())?
-            .error_for_status().map_err(|e| e.to_string())?
-            .bytes().await.map_err(|e| e.to_string())?;
+        let bytes = self.fetch_verified(coord).await?;
 
-        let backing = match self.cache.access_mut(uri.as_str()) {
+        let backing = match self.cache.access_mut(key.as_str()) {
             DatasetCacheResult::Invalid(backing) => backing,
             DatasetCacheResult::Valid(_) => panic!("Result should be invalid, it was invalid on the immutable version of this call")
         };
         
-        ImageBacked::decode_into(self.codec, &bytes[..], backing)
-        .map(|_| Ok(()))?
+        if let Err(e) = ImageBacked::decode_into(self.codec, &bytes[..], backing) {
+            self.cache.invalidate(key.as_str());
+            return Err(FetchError::Invalid(e));
+        }
+        Ok(())
+    }
+    // Encoded tile as the source stores it, checked against the manifest and refetched when it doesn't match
+    pub async fn fetch_verified(&mut self, coord: IVec3) -> Result<Vec<u8>, FetchError> {
+        if !self.manifest.contains(coord) {
+            return Err(FetchError::Missing);
+        }
+        let mut attempt = 1;
+        let bytes = loop {
+            let bytes = self.source.fetch_tile(coord).await?;
+            self.tiles_fetched += 1;
+            self.bytes_fetched += bytes.len() as u64;
+            let verified = match self.manifest.get(coord) {
+                Some(metadata) => metadata.verify(&bytes[..]),
+                None => Ok(())
+            };
+            match verified {
+                Ok(()) => break bytes,
+                Err(reason) => {
+                    self.corrupt_fetches += 1;
+                    if attempt >= VERIFY_ATTEMPTS {
+                        return Err(FetchError::Invalid(format!("Tile {:?}: {} after {} attempts", coord, reason, attempt)));
+                    }
+                    println!("Refetching {:?}: {}", coord, reason);
+                    attempt += 1;
+                }
+            }
+        };
+        Ok(bytes)
+    }
+    // Levels a request may read, from 0 to the highest the manifest has
+    pub fn check_level(&self, level: i32) -> Result<(), String> {
+        let max_level = self.max_level.ok_or("Dataset has no tiles")?.min(MAX_LEVEL);
+        match (0..=max_level).contains(&level) {
+            true  => Ok(()),
+            false => Err(format!("Level {} is outside the dataset's levels 0 to {}", level, max_level))
+        }
+    }
+    // Pixel grid of a level, level 0 is self.tilespace. Levels past MAX_LEVEL only come from unchecked requests and get an
+    // offset of 0 rather than a panic
+    fn level_grid(&self, level: i32) -> Tilespace {
+        Tilespace {
+            size: self.codec.format.size,
+            offset: match level_scale(level) {
+                Some(scale) => self.tilespace.offset / scale,
+                None => IVec2::ZERO
+            },
+            georef: None
+        }
+    }
+    // Pixels of a tile in the grid of its level, as taken by read_region
+    pub fn tile_region(&self, coord: IVec3) -> Dabb2 {
+        self.level_grid(coord.z).tile_pixels(ivec2(coord.x, coord.y))
+    }
+    // Pixels of region in the grid of level, stitched from every tile it touches. Tiles the dataset doesn't have are left zeroed,
+    // Missing only when it has none of them
+    pub async fn read_region(&mut self, region: Dabb2, level: i32) -> Result<ImageOwned, FetchError> {
+        Ok(self.read_region_covered(region, level).await?.0)
+    }
+    // read_region along with the parts of region that came from tiles
+    pub async fn read_region_covered(&mut self, region: Dabb2, level: i32) -> Result<(ImageOwned, Vec<Dabb2>), FetchError> {
+        if self.check_level(level).is_err() {
+            return Err(FetchError::Missing);
+        }
+        let format = self.codec.format;
+        let pixel_size = (format.encoding.bit_depth / 8 * format.encoding.channels) as usize;
+        let size = (region.end.as_i64vec2() - region.begin.as_i64vec2()).max(I64Vec2::ZERO);
+        if size.max_element() > i32::MAX as i64 {
+            return Err(FetchError::Failed(format!("Region {:?} is too large", region)));
+        }
+        let size = size.as_ivec2();
+        let mut res = ImageOwned::empty_new(ImageFormat { encoding: format.encoding, size });
+        if size.x == 0 || size.y == 0 {
+            return Ok((res, vec![]));
+        }
+
+        let grid = self.level_grid(level);
+        let mut covered = vec![];
+        for tile in &grid.get_covered_tiles(region - grid.offset) {
+            let coord = ivec3(tile.x, tile.y, level);
+            match self.cache_resource(coord).await {
+                Ok(()) => {},
+                Err(FetchError::Missing) => continue,
+                Err(e) => return Err(e)
+            }
+            let image = self.access_cached_resource(coord).ok_or(FetchError::Missing)?;
+
+            let tile_pixels = grid.tile_pixels(tile);
+            let overlap = region & tile_pixels;
+            covered.push(overlap);
+            let row_len = (overlap.end.x - overlap.begin.x) as usize * pixel_size;
+            for y in overlap.begin.y..overlap.end.y {
+                let src = ((y - tile_pixels.begin.y) as usize * format.size.x as usize + (overlap.begin.x - tile_pixels.begin.x) as usize) * pixel_size;
+                let dst = ((y - region.begin.y) as usize * size.x as usize + (overlap.begin.x - region.begin.x) as usize) * pixel_size;
+                res.data[dst..dst + row_len].copy_from_slice(&image.data[src..src + row_len]);
+            }
+        }
+        match covered.is_empty() {
+            true  => Err(FetchError::Missing),
+            false => Ok((res, covered))
+        }
+    }
+    // Whether a pixel in the grid of level is inside the extent the source describes, always without one
+    fn in_extent(&self, pixel: IVec2, level: i32) -> bool {
+        match self.extent {
+            Some(extent) => {
+                // a level pixel has data when any of the level 0 pixels it covers do
+                let scale = match level_scale(level) {
+                    Some(scale) => scale as i64,
+                    None => return false
+                };
+                let begin = pixel.as_i64vec2() * scale;
+                !((begin + scale).cmple(extent.begin.as_i64vec2()).any() || begin.cmpge(extent.end.as_i64vec2()).any())
+            },
+            None => true
+        }
+    }
+    // First channel of every pixel of region in the grid of level, row by row. None where pixel_value would be
+    pub async fn read_region_values(&mut self, region: Dabb2, level: i32) -> Result<Vec<Option<f64>>, FetchError> {
+        let (image, covered) = self.read_region_covered(region, level).await?;
+        let read = sample_reader(self.codec.format.encoding).map_err(FetchError::Invalid)?;
+        let pixel_size = self.codec.format.encoding.pixel_size();
+
+        let mut res = vec![None; image.format.size.x as usize * image.format.size.y as usize];
+        for part in covered.iter() {
+            for y in part.begin.y..part.end.y {
+                for x in part.begin.x..part.end.x {
+                    if !self.in_extent(ivec2(x, y), level) {
+                        continue;
+                    }
+                    let i = (y - region.begin.y) as usize * image.format.size.x as usize + (x - region.begin.x) as usize;
+                    let value = read(&image.data[i * pixel_size..]);
+                    res[i] = match self.nodata {
+                        Some(nodata) if value == nodata => None,
+                        _ => Some(value)
+                    };
+                }
+            }
+        }
+        Ok(res)
+    }
+    // First channel of a pixel in the grid of level, None where the dataset has no tile or the pixel is nodata
+    pub async fn pixel_value(&mut self, pixel: IVec2, level: i32) -> Result<Option<f64>, FetchError> {
+        if self.check_level(level).is_err() || !self.in_extent(pixel, level) {
+            return Ok(None);
+        }
+        let grid = self.level_grid(level);
+        let tile = (pixel - grid.offset).floor_on_interval(grid.size) / grid.size;
+        let coord = ivec3(tile.x, tile.y, level);
+        match self.cache_resource(coord).await {
+            Ok(()) => {},
+            Err(FetchError::Missing) => return Ok(None),
+            Err(e) => return Err(e)
+        }
+        let image = self.access_cached_resource(coord).ok_or(FetchError::Missing)?;
+        let read = sample_reader(self.codec.format.encoding).map_err(FetchError::Invalid)?;
+
+        let local = pixel - grid.tile_pixels(tile).begin;
+        let i = (local.y as usize * grid.size.x as usize + local.x as usize) * self.codec.format.encoding.pixel_size();
+        let value = read(&image.data[i..]);
+        match self.nodata {
+            Some(nodata) if value == nodata => Ok(None),
+            _ => Ok(Some(value))
+        }
+    }
+    // Georeference of an image returned by read_region
+    pub fn region_georef(&self, region: Dabb2, level: i32) -> Option<GeoTransform> {
+        let scale = 2f64.powi(level.max(0));
+        self.tilespace.georef.map(|georef| GeoTransform {
+            origin: georef.pixel_to_geo(region.begin.as_dvec2() * scale),
+            pixel_size: georef.pixel_size * scale
+        })
+    }
+    pub fn set_cache_capacity(&mut self, tiles: usize) {
+        self.cache = DatasetCache::new(self.codec.format.raw_size(), tiles);
     }
     pub fn access_cached_resource<'a>(&'a self, coord: IVec3) -> Option<ImageBacked<'a>> {
-        Some(ImageBacked::from_view(self.codec.format, self.cache.access(self.get_resource_uri(coord).as_str())?).unwrap())
+        Some(ImageBacked::from_view(self.codec.format, self.cache.access(cache_key(coord).as_str())?).unwrap())
+    }
+    pub fn save_cache(&self, path: &str) -> Result<(), String> {
+        self.cache.save_snapshot(path)
     }
-}
\ No newline at end of file
+    // Warm start from a snapshot written by save_cache, the slot size must match this codec
+    pub fn load_cache(&mut self, path: &str) -> Result<(), String> {
+        let cache = DatasetCache::load_snapshot(path)?;
+        if cache.slot_size() != self.codec.format.raw_size() {
+            return Err(format!(
+                "Cache snapshot slot size {} does not match codec size {}",
+                cache.slot_size(),
+                self.codec.format.raw_size()
+            ));
+        }
+        self.cache = cache;
+        Ok(())
+    }
+}
@@ -0,0 +1,256 @@
+use serde::{Serialize, Deserialize};
+use glam::*;
+use std::collections::HashMap;
+
+use crate::config::DatasetProvider;
+use crate::mvt::{LineFeature, LineLayer};
+use crate::retiling::{RetilingObserver, RetilingProgress, RetilingSummary};
+use crate::tile_sink::TileSink;
+use crate::tile_source::FetchError;
+use crate::util::math::Dabb2;
+
+// More contour levels than this in one region or tile is taken as an interval that's too fine for the data
+pub const MAX_CONTOUR_LEVELS: usize = 1000;
+
+// Tile coordinates across a vector tile, the usual MVT extent
+const MVT_EXTENT: u32 = 4096;
+
+fn default_interval() -> f64 {
+    10.0
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct ContourOptions {
+    // sample units between lines, lines are at base + k * interval
+    #[serde(default = "default_interval")]
+    pub interval: f64,
+    #[serde(default)]
+    pub base: f64
+}
+
+impl Default for ContourOptions {
+    fn default() -> Self {
+        ContourOptions {
+            interval: default_interval(),
+            base: 0.0
+        }
+    }
+}
+
+impl ContourOptions {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.interval.is_finite() || self.interval <= 0.0 {
+            return Err(format!("Contour interval {} has to be positive", self.interval));
+        }
+        if !self.base.is_finite() {
+            return Err(format!("Contour base {} has to be finite", self.base));
+        }
+        Ok(())
+    }
+
+    // Levels from min to max, both included
+    fn levels(&self, min: f64, max: f64) -> Result<Vec<f64>, String> {
+        let first = ((min - self.base) / self.interval).ceil();
+        let last = ((max - self.base) / self.interval).floor();
+        if last - first >= MAX_CONTOUR_LEVELS as f64 {
+            return Err(format!("Values from {} to {} are more than {} contours at an interval of {}", min, max, MAX_CONTOUR_LEVELS, self.interval));
+        }
+        Ok((first as i64..=last as i64).map(|k| self.base + k as f64 * self.interval).collect())
+    }
+}
+
+// Lines at one elevation, in continuous pixels of the level the values came from, pixel centers at .5
+#[derive(Debug, Clone)]
+pub struct Contour {
+    pub elevation: f64,
+    pub lines: Vec<Vec<DVec2>>
+}
+
+// Edges between horizontally (false) or vertically (true) neighbouring pixels, named by the top or left one
+type EdgeKey = (IVec2, bool);
+
+// Corners are top left, top right, bottom right, bottom left and edges top, right, bottom, left
+const CORNERS: [IVec2; 4] = [IVec2::new(0, 0), IVec2::new(1, 0), IVec2::new(1, 1), IVec2::new(0, 1)];
+const EDGE_CORNERS: [(usize, usize); 4] = [(0, 1), (1, 2), (3, 2), (0, 3)];
+const TOP: usize = 0;
+const RIGHT: usize = 1;
+const BOTTOM: usize = 2;
+const LEFT: usize = 3;
+
+// Edges joined within a cell for corners at or above the level, indexed by tl << 3 | tr << 2 | br << 1 | bl.
+// Saddles, 5 and 10, are split by the average of the corners and listed twice, first for a center below the level
+fn cell_segments(case: usize, center_above: bool) -> &'static [(usize, usize)] {
+    match (case, center_above) {
+        (0, _) | (15, _) => &[],
+        (1, _) | (14, _) => &[(LEFT, BOTTOM)],
+        (2, _) | (13, _) => &[(BOTTOM, RIGHT)],
+        (3, _) | (12, _) => &[(LEFT, RIGHT)],
+        (4, _) | (11, _) => &[(TOP, RIGHT)],
+        (6, _) | (9, _) => &[(TOP, BOTTOM)],
+        (7, _) | (8, _) => &[(LEFT, TOP)],
+        (5, false) => &[(TOP, RIGHT), (LEFT, BOTTOM)],
+        (5, true) => &[(LEFT, TOP), (BOTTOM, RIGHT)],
+        (10, false) => &[(LEFT, TOP), (BOTTOM, RIGHT)],
+        (10, true) => &[(TOP, RIGHT), (LEFT, BOTTOM)],
+        _ => &[]
+    }
+}
+
+// Marching squares over the cells between pixel centers of region, values as from DatasetProvider::read_region_values.
+// Cells with a corner without data are skipped. Neighbouring regions that share a row or column of pixels produce the same
+// points along it, so lines from tiles read with a border meet exactly
+pub fn trace(values: &[Option<f64>], region: Dabb2, options: &ContourOptions) -> Result<Vec<Contour>, String> {
+    options.validate()?;
+    let size = region.end - region.begin;
+    let value = |p: IVec2| values[p.y as usize * size.x as usize + p.x as usize];
+
+    let known = values.iter().flatten();
+    let min = known.clone().cloned().fold(f64::INFINITY, f64::min);
+    let max = known.cloned().fold(f64::NEG_INFINITY, f64::max);
+    if min > max {
+        return Ok(vec![]);
+    }
+
+    let mut res = vec![];
+    for elevation in options.levels(min, max)? {
+        let mut points: HashMap<EdgeKey, DVec2> = HashMap::new();
+        let mut segments: Vec<(EdgeKey, EdgeKey)> = vec![];
+
+        for y in 0..size.y - 1 {
+            for x in 0..size.x - 1 {
+                let cell = ivec2(x, y);
+                let corners = match (value(cell), value(cell + CORNERS[1]), value(cell + CORNERS[2]), value(cell + CORNERS[3])) {
+                    (Some(a), Some(b), Some(c), Some(d)) => [a, b, c, d],
+                    _ => continue
+                };
+                let case = corners.iter().fold(0, |case, v| case << 1 | (*v >= elevation) as usize);
+                let center_above = corners.iter().sum::<f64>() / 4.0 >= elevation;
+
+                let mut edge = |edge: usize| -> EdgeKey {
+                    let (a, b) = EDGE_CORNERS[edge];
+                    let key = (cell + CORNERS[a], edge == LEFT || edge == RIGHT);
+                    points.entry(key).or_insert_with(|| {
+                        let t = ((elevation - corners[a]) / (corners[b] - corners[a])).clamp(0.0, 1.0);
+                        let pa = (region.begin + cell + CORNERS[a]).as_dvec2() + 0.5;
+                        let pb = (region.begin + cell + CORNERS[b]).as_dvec2() + 0.5;
+                        pa.lerp(pb, t)
+                    });
+                    key
+                };
+                for (a, b) in cell_segments(case, center_above).iter() {
+                    segments.push((edge(*a), edge(*b)));
+                }
+            }
+        }
+
+        let lines: Vec<Vec<DVec2>> = join(&segments[..]).into_iter().map(|edges| edges.iter().map(|e| points[e]).collect()).collect();
+        if !lines.is_empty() {
+            res.push(Contour { elevation, lines });
+        }
+    }
+    Ok(res)
+}
+
+// Chains segments sharing an edge into lines, closed rings end on the edge they started from
+fn join(segments: &[(EdgeKey, EdgeKey)]) -> Vec<Vec<EdgeKey>> {
+    let mut at_edge: HashMap<EdgeKey, Vec<usize>> = HashMap::new();
+    for (i, (a, b)) in segments.iter().enumerate() {
+        at_edge.entry(*a).or_default().push(i);
+        at_edge.entry(*b).or_default().push(i);
+    }
+    let mut used = vec![false; segments.len()];
+
+    // follows unused segments from edge, returning the edges passed
+    let walk = |used: &mut Vec<bool>, mut edge: EdgeKey| -> Vec<EdgeKey> {
+        let mut res = vec![];
+        while let Some(&next) = at_edge.get(&edge).and_then(|s| s.iter().find(|&&s| !used[s])) {
+            used[next] = true;
+            let (a, b) = segments[next];
+            edge = if a == edge { b } else { a };
+            res.push(edge);
+        }
+        res
+    };
+
+    let mut res = vec![];
+    for i in 0..segments.len() {
+        if used[i] {
+            continue;
+        }
+        used[i] = true;
+        let (a, b) = segments[i];
+        let forward = walk(&mut used, b);
+        let backward = walk(&mut used, a);
+        let mut line: Vec<EdgeKey> = backward.into_iter().rev().collect();
+        line.push(a);
+        line.push(b);
+        line.extend(forward);
+        res.push(line);
+    }
+    res
+}
+
+// Contours of a dataset tile as a vector tile with one contours layer, one feature per elevation. The tile is read with a
+// border of a pixel so lines run half a pixel past its edges and meet the ones of its neighbours
+pub async fn render_contour_tile(dp: &mut DatasetProvider, coord: IVec3, options: &ContourOptions) -> Result<Vec<u8>, FetchError> {
+    let tile = dp.tile_region(coord);
+    let region = Dabb2::bounds(tile.begin - 1, tile.end + 1);
+    let values = dp.read_region_values(region, coord.z).await?;
+    let contours = trace(&values[..], region, options).map_err(FetchError::Invalid)?;
+
+    let scale = MVT_EXTENT as f64 / (tile.end - tile.begin).as_dvec2();
+    let features = contours.into_iter().map(|contour| LineFeature {
+        properties: vec![("elevation".to_string(), contour.elevation)],
+        lines: contour.lines.iter().map(|line| {
+            line.iter().map(|p| ((*p - tile.begin.as_dvec2()) * scale).round().as_ivec2()).collect()
+        }).collect()
+    }).collect();
+
+    Ok(crate::mvt::encode(&[LineLayer {
+        name: "contours".to_string(),
+        extent: MVT_EXTENT,
+        features
+    }]))
+}
+
+// Vector tiles for every tile the dataset has, in its own tile grid, written with the coordinates zoom gives.
+// Failed tiles are recorded in the summary like retiling inputs and the run goes on
+pub async fn write_contour_tiles(
+    dp: &mut DatasetProvider,
+    sink: &dyn TileSink,
+    options: &ContourOptions,
+    zoom: &dyn Fn(i32) -> i32,
+    observer: &mut RetilingObserver<'_>
+) -> RetilingSummary {
+    let mut coords: Vec<IVec3> = dp.manifest.coords().collect();
+    // row by row within a level so the border pixels of the previous tiles are mostly still cached
+    coords.sort_by_key(|c| (c.z, c.y, c.x));
+
+    let mut summary = RetilingSummary::default();
+    let fetched_before = (dp.tiles_fetched, dp.bytes_fetched);
+    let start = std::time::Instant::now();
+    let mut proceed = observer(&RetilingProgress::of(&summary, coords.len(), dp, fetched_before, start));
+    for coord in coords.iter() {
+        if !proceed {
+            summary.cancelled = true;
+            break;
+        }
+        match render_contour_tile(dp, *coord, options).await {
+            Ok(tile) => match sink.write_tile(ivec3(coord.x, coord.y, zoom(coord.z)), &tile[..]) {
+                Ok(()) => summary.tiles_written += 1,
+                Err(e) => println!("Unexpected tile write error: {}", e)
+            },
+            Err(FetchError::Missing) => summary.tiles_missing += 1,
+            Err(e) => {
+                summary.failures.push((*coord, e.to_string()));
+                summary.failed_outputs.push(*coord);
+            }
+        }
+        summary.jobs += 1;
+        proceed = observer(&RetilingProgress::of(&summary, coords.len(), dp, fetched_before, start));
+    }
+    if let Err(e) = sink.finish() {
+        println!("Unexpected dataset finish error: {}", e);
+    }
+    summary
+}
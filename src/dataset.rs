@@ -1,11 +1,50 @@
 use serde::{Serialize, Deserialize};
 use glam::*;
 use crate::util::math::*;
+use crate::image::ImageCodec;
+use crate::uri_format::{Formattable, IntegerSpec, UriTemplate};
+use regex::Regex;
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
 
-#[derive(Serialize, Deserialize, Debug)]
+const METERS_PER_DEGREE: f64 = 111_320.0;
+
+// Maps level 0 pixel coordinates to geographic degrees, origin is the (lon, lat) of the corner of pixel (0, 0)
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct GeoTransform {
+    pub origin: DVec2,
+    pub pixel_size: DVec2
+}
+
+impl GeoTransform {
+    pub fn pixel_to_geo(&self, pixel: DVec2) -> DVec2 {
+        self.origin + pixel * self.pixel_size
+    }
+    pub fn geo_to_pixel(&self, geo: DVec2) -> DVec2 {
+        (geo - self.origin) / self.pixel_size
+    }
+    // Ground size of a level 0 pixel in meters, on a sphere
+    pub fn pixel_size_meters(&self, pixel: DVec2) -> DVec2 {
+        let lat = self.pixel_to_geo(pixel).y.to_radians();
+        dvec2(
+            self.pixel_size.x.abs() * METERS_PER_DEGREE * lat.cos(),
+            self.pixel_size.y.abs() * METERS_PER_DEGREE
+        )
+    }
+    // (west, south, east, north)
+    pub fn bounds(&self, pixels: Dabb2) -> [f64; 4] {
+        let a = self.pixel_to_geo(pixels.begin.as_dvec2());
+        let b = self.pixel_to_geo(pixels.end.as_dvec2());
+        [a.x.min(b.x), a.y.min(b.y), a.x.max(b.x), a.y.max(b.y)]
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Tilespace {
     pub size: IVec2,
-    pub offset: IVec2
+    pub offset: IVec2,
+    #[serde(default)]
+    pub georef: Option<GeoTransform>
 }
 
 impl Tilespace {
@@ -23,14 +62,298 @@ impl Tilespace {
     }
 }
 
+// Levels count down from full resolution at 0 while web tile schemes count zoom up from the whole world at 0
+pub fn level_to_zoom(level: i32, max_zoom: i32) -> i32 {
+    max_zoom - level
+}
+
+pub fn zoom_to_level(zoom: i32, max_zoom: i32) -> i32 {
+    max_zoom - zoom
+}
+
+// TMS rows count up from the bottom, XYZ rows count down from the top
+pub fn tms_flip_y(y: i32, zoom: i32) -> i32 {
+    (1 << zoom) - 1 - y
+}
+
+// Stored alongside single file archives so they can be read back as a dataset
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DatasetInfo {
+    pub codec: ImageCodec,
+    pub tilespace: Tilespace,
+    pub max_zoom: i32,
+    // sample value marking pixels without data
+    #[serde(default)]
+    pub nodata: Option<f64>,
+    // level 0 pixels with data, edge tiles may be padded past it
+    #[serde(default)]
+    pub extent: Option<Dabb2>
+}
+
 pub trait TileURIProvider {
-    fn get_resource_uri(&self, coord: IVec3) -> String;
+    fn get_resource_uri(&self, coord: IVec3) -> Result<String, String>;
+}
+
+fn subdomain_choices(fmt: &str) -> Vec<String> {
+    match (fmt, fmt.contains(',')) {
+        ("", _) => vec!["a", "b", "c"].into_iter().map(|s| s.to_string()).collect(),
+        (fmt, true) => fmt.split(',').map(|s| s.to_string()).collect(),
+        (fmt, false) => fmt.chars().map(|c| c.to_string()).collect()
+    }
+}
+
+// Picks one of the subdomains in the format, e.g. {s:abc} or {s:a1,a2,a3}. The choice only depends on the tile so it stays cacheable
+pub struct Subdomain(pub i32);
+
+impl Formattable for Subdomain {
+    fn format(&self, fmt: &str) -> Result<String, String> {
+        let choices = subdomain_choices(fmt);
+        Ok(choices[self.0.rem_euclid(choices.len() as i32) as usize].clone())
+    }
+}
+
+// Bing maps quadkey, treating z as the zoom
+pub fn quadkey(coord: IVec3) -> String {
+    (1..=coord.z).rev().map(|i| {
+        let mask = 1 << (i - 1);
+        let digit = ((coord.x & mask != 0) as u8) + 2 * ((coord.y & mask != 0) as u8);
+        (b'0' + digit) as char
+    }).collect()
+}
+
+// None past MAX_TEMPLATE_ZOOM digits, which would overflow the coordinates
+pub fn parse_quadkey(q: &str) -> Option<IVec3> {
+    if q.len() > MAX_TEMPLATE_ZOOM as usize {
+        return None;
+    }
+    let mut res = ivec3(0, 0, q.len() as i32);
+    for c in q.chars() {
+        let digit = c.to_digit(4)? as i32;
+        res.x = res.x * 2 + (digit & 1);
+        res.y = res.y * 2 + (digit >> 1);
+    }
+    Some(res)
+}
+
+// SRTM style name of the 1 degree cell's south west corner, e.g. N37W122. Tiles are laid out with x = lon + 180 and y = 89 - lat
+pub fn srtm_name(coord: IVec3) -> String {
+    let lat = 89 - coord.y;
+    let lon = coord.x - 180;
+    format!(
+        "{}{:02}{}{:03}",
+        if lat < 0 { 'S' } else { 'N' }, lat.abs(),
+        if lon < 0 { 'W' } else { 'E' }, lon.abs()
+    )
+}
+
+pub fn parse_srtm_name(name: &str) -> Option<IVec2> {
+    let lat: i32 = name.get(1..3)?.parse().ok()?;
+    let lon: i32 = name.get(4..7)?.parse().ok()?;
+    let lat = match name.get(0..1)? { "N" => lat, "S" => -lat, _ => return None };
+    let lon = match name.get(3..4)? { "E" => lon, "W" => -lon, _ => return None };
+    Some(ivec2(lon + 180, 89 - lat))
 }
 
+// Keys are x, y and z, -y for TMS rows, q for quadkeys, s for subdomains and srtm for SRTM cell names.
+// z, -y and q are in zooms, which are the levels unless max_zoom is set. Web tile servers count zoom up from the whole world,
+// so their templates need max_zoom to put level 0 at it. Unknown keys and bad specs are rejected when the template is created
+#[derive(Debug, Clone)]
+pub struct TileTemplate {
+    pub template: UriTemplate,
+    reverse: Regex,
+    pub max_zoom: Option<i32>
+}
+
+// Zooms -y and q can be written for, rows and quadkey digits past it don't fit an i32
+const MAX_TEMPLATE_ZOOM: i32 = 30;
+
+impl TileTemplate {
+    pub fn new(template: &str) -> Result<Self, String> {
+        let template = UriTemplate::parse(template)?;
+        let reverse = template.reverse_regex(|key, spec| match key {
+            "x" | "y" | "z" | "-y" => Ok(IntegerSpec::parse(spec)?.pattern()),
+            "q" | "srtm" if !spec.is_empty() => Err(format!("{{{}}} takes no format, got {}", key, spec)),
+            "q" => Ok(format!("[0-3]{{0,{}}}", MAX_TEMPLATE_ZOOM)),
+            "srtm" => Ok("[NS][0-9]{2}[EW][0-9]{3}".to_string()),
+            "s" => Ok(subdomain_choices(spec).iter().map(|c| regex::escape(c.as_str())).collect::<Vec<_>>().join("|")),
+            key => Err(format!("Unknown key {} in {}", key, template.source))
+        })?;
+        Ok(TileTemplate { template, reverse, max_zoom: None })
+    }
+
+    pub fn with_max_zoom(mut self, max_zoom: Option<i32>) -> Self {
+        self.max_zoom = max_zoom;
+        self
+    }
+
+    fn uses(&self, key: &str) -> bool {
+        self.template.keys().any(|(k, _)| k == key)
+    }
+
+    // Tile coordinates as the template writes them, with z as the zoom
+    fn zoomed(&self, coord: IVec3) -> IVec3 {
+        match self.max_zoom {
+            Some(max_zoom) => ivec3(coord.x, coord.y, level_to_zoom(coord.z, max_zoom)),
+            None => coord
+        }
+    }
+
+    pub fn format(&self, level_coord: IVec3) -> Result<String, String> {
+        let coord = self.zoomed(level_coord);
+        // only worked out for templates that have them, they're limited to zooms web tiles have
+        let web_zoom = |key: &str| match (0..=MAX_TEMPLATE_ZOOM).contains(&coord.z) {
+            true  => Ok(coord.z),
+            false => Err(format!("{{{}}} can't be written for zoom {} of tile {:?}", key, coord.z, level_coord))
+        };
+        let flipped_y = match self.uses("-y") {
+            true  => Some(tms_flip_y(coord.y, web_zoom("-y")?)),
+            false => None
+        };
+        let q = match self.uses("q") {
+            true  => Some(quadkey(ivec3(coord.x, coord.y, web_zoom("q")?))),
+            false => None
+        };
+        let s = Subdomain(coord.x.wrapping_add(coord.y));
+        let srtm = match self.uses("srtm") {
+            true  => Some(srtm_name(coord)),
+            false => None
+        };
+
+        let mut values = HashMap::<&str, &dyn Formattable>::new();
+        values.insert("x", &coord.x);
+        values.insert("y", &coord.y);
+        values.insert("z", &coord.z);
+        if let Some(flipped_y) = &flipped_y {
+            values.insert("-y", flipped_y);
+        }
+        if let Some(q) = &q {
+            values.insert("q", q);
+        }
+        if let Some(srtm) = &srtm {
+            values.insert("srtm", srtm);
+        }
+        values.insert("s", &s);
+        self.template.format(&values)
+    }
+
+    // The tile a formatted URI refers to, None if it doesn't match or keys disagree. z is 0 when the template has none
+    pub fn parse_coord(&self, uri: &str) -> Option<IVec3> {
+        let mut x = None;
+        let mut y = None;
+        let mut z = None;
+        let mut flipped_y = None;
+
+        fn set(slot: &mut Option<i32>, value: i32) -> Option<()> {
+            match *slot {
+                Some(v) if v != value => None,
+                _ => {
+                    *slot = Some(value);
+                    Some(())
+                }
+            }
+        }
+
+        for (key, spec, text) in self.template.captures(&self.reverse, uri)? {
+            match key {
+                "x" => set(&mut x, IntegerSpec::parse(spec).ok()?.read(text)?)?,
+                "y" => set(&mut y, IntegerSpec::parse(spec).ok()?.read(text)?)?,
+                "z" => set(&mut z, IntegerSpec::parse(spec).ok()?.read(text)?)?,
+                "-y" => set(&mut flipped_y, IntegerSpec::parse(spec).ok()?.read(text)?)?,
+                "q" => {
+                    let coord = parse_quadkey(text)?;
+                    set(&mut x, coord.x)?;
+                    set(&mut y, coord.y)?;
+                    set(&mut z, coord.z)?;
+                },
+                "srtm" => {
+                    let cell = parse_srtm_name(text)?;
+                    set(&mut x, cell.x)?;
+                    set(&mut y, cell.y)?;
+                },
+                _ => {}
+            }
+        }
+
+        let z = z.unwrap_or(0);
+        if let Some(flipped_y) = flipped_y {
+            if !(0..=MAX_TEMPLATE_ZOOM).contains(&z) {
+                return None;
+            }
+            set(&mut y, tms_flip_y(flipped_y, z))?;
+        }
+        Some(ivec3(x?, y?, match self.max_zoom {
+            Some(max_zoom) => zoom_to_level(z, max_zoom),
+            None => z
+        }))
+    }
+}
+
+// Templates are parsed the first time they're used and kept for later calls
 pub fn format_tile_string(template: &str, coord: IVec3) -> Result<String, String> {
-    crate::uri_fmt!(&template, {
-        "x" => coord.x,
-        "y" => coord.y,
-        "z" => coord.z
-    })
-}
\ No newline at end of file
+    static TEMPLATES: OnceLock<Mutex<HashMap<String, TileTemplate>>> = OnceLock::new();
+    let mut templates = TEMPLATES.get_or_init(|| Mutex::new(HashMap::new())).lock().unwrap();
+    if !templates.contains_key(template) {
+        templates.insert(template.to_string(), TileTemplate::new(template)?);
+    }
+    templates[template].format(coord)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quadkeys_round_trip_up_to_the_template_zoom() {
+        assert_eq!(quadkey(ivec3(3, 5, 3)), "213");
+        assert_eq!(parse_quadkey("213"), Some(ivec3(3, 5, 3)));
+        assert_eq!(parse_quadkey(""), Some(ivec3(0, 0, 0)));
+        assert_eq!(parse_quadkey("4"), None);
+        let deepest = ivec3((1 << 30) - 1, 0, MAX_TEMPLATE_ZOOM);
+        assert_eq!(parse_quadkey(quadkey(deepest).as_str()), Some(deepest));
+        assert_eq!(parse_quadkey("3".repeat(31).as_str()), None);
+
+        let template = TileTemplate::new("/{q}.png").unwrap();
+        assert_eq!(template.parse_coord("/213.png"), Some(ivec3(3, 5, 3)));
+        assert_eq!(template.parse_coord(format!("/{}.png", "1".repeat(40)).as_str()), None);
+    }
+
+    #[test]
+    fn rejects_unknown_keys_and_bad_specs() {
+        for template in ["/{w}.png", "/{x:abc}.png", "/{q:3}.png", "/{srtm:x}.hgt", "/{x"] {
+            assert!(TileTemplate::new(template).is_err(), "{}", template);
+        }
+    }
+
+    #[test]
+    fn templates_round_trip() {
+        let cases = [
+            ("/{z}/{x}/{y}.png", ivec3(3, 5, 4), "/4/3/5.png"),
+            ("/{z}/{x:4}/{y:+3}", ivec3(-3, -5, 2), "/2/00-3/-005"),
+            ("/{z}/{x:x}/{y:4X}", ivec3(255, 171, 9), "/9/ff/00AB"),
+            ("/{z}/{x}/{-y}.png", ivec3(1, 0, 2), "/2/1/3.png"),
+            ("/{q}.jpeg", ivec3(3, 5, 3), "/213.jpeg"),
+            ("/{srtm}.hgt", ivec3(58, 52, 0), "/N37W122.hgt"),
+            ("/{srtm}.hgt", ivec3(181, 90, 0), "/S01E001.hgt"),
+            ("{{{s}}}/{x}_{y}", ivec3(2, 0, 0), "{c}/2_0")
+        ];
+        for (template, coord, uri) in cases.iter() {
+            let template = TileTemplate::new(template).unwrap();
+            assert_eq!(template.format(*coord).unwrap(), *uri);
+            assert_eq!(template.parse_coord(uri), Some(*coord), "{}", uri);
+        }
+
+        // level 0 is zoom 10, so level 2 is written as zoom 8
+        let template = TileTemplate::new("/{z}/{x}/{-y}").unwrap().with_max_zoom(Some(10));
+        assert_eq!(template.format(ivec3(1, 2, 2)).unwrap(), "/8/1/253");
+        assert_eq!(template.parse_coord("/8/1/253"), Some(ivec3(1, 2, 2)));
+        assert!(template.format(ivec3(0, 0, 11)).is_err());
+
+        // keys that disagree about a coordinate
+        let template = TileTemplate::new("/{q}/{z}").unwrap();
+        assert_eq!(template.parse_coord("/213/3"), Some(ivec3(3, 5, 3)));
+        assert_eq!(template.parse_coord("/213/2"), None);
+        assert_eq!(format_tile_string("/{z}/{x}", ivec3(1, 0, 2)).unwrap(), "/2/1");
+        assert_eq!(format_tile_string("/{z}/{x}", ivec3(3, 0, 4)).unwrap(), "/4/3");
+        assert!(format_tile_string("/{w}", ivec3(0, 0, 0)).is_err());
+    }
+}
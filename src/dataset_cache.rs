@@ -1,6 +1,10 @@
 use serde::{Serialize, Deserialize};
+use std::cell::Cell;
 use std::collections::HashMap;
 use std::vec::Vec;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::convert::TryInto;
 //use std::ops::{Index, IndexMut};
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -12,6 +16,15 @@ pub struct DatasetCache {
     
 }
 
+// Snapshot layout, all integers little endian:
+//   magic, version, slot size, slot count, time, entry count, data offset
+//   entries: key length (u32), key bytes, slot offset, time
+//   slot data from data offset, same layout as DatasetCache::data
+// Snapshots are read rather than mapped, the page padding earlier ones put before the data is skipped
+const SNAPSHOT_MAGIC: &[u8; 8] = b"TLRCACHE";
+// 2: keys are the x_y_z of DatasetProvider instead of the tile file names
+const SNAPSHOT_VERSION: u32 = 2;
+
 #[derive(Debug)]
 pub enum DatasetCacheResult<'a> {
     Valid(&'a [u8]),
@@ -68,4 +81,158 @@ impl DatasetCache {
             }
         }
     }
-}
\ No newline at end of file
+
+    // Drops a key whose slot holds bad data, the slot goes back to being a free one
+    pub fn invalidate(&mut self, key: &str) {
+        if let Some((i, _)) = self.existing.remove(key) {
+            self.existing.insert(format!("{:?}", i / self.size.max(1)), (i, 0));
+        }
+    }
+
+    pub fn slot_size(&self) -> usize {
+        self.size
+    }
+
+    pub fn save_snapshot(&self, path: &str) -> Result<(), String> {
+        let mut index = Vec::<u8>::new();
+        for (key, (offset, time)) in self.existing.iter() {
+            index.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            index.extend_from_slice(key.as_bytes());
+            index.extend_from_slice(&(*offset as u64).to_le_bytes());
+            index.extend_from_slice(&time.to_le_bytes());
+        }
+
+        let header_len = SNAPSHOT_MAGIC.len() as u64 + 4 + 8 * 5;
+        let data_offset = header_len + index.len() as u64;
+
+        let mut out = BufWriter::new(File::create(path).map_err(|e| e.to_string())?);
+        let mut write = |bytes: &[u8]| out.write_all(bytes).map_err(|e| e.to_string());
+        write(SNAPSHOT_MAGIC)?;
+        write(&SNAPSHOT_VERSION.to_le_bytes())?;
+        write(&(self.size as u64).to_le_bytes())?;
+        write(&((self.data.len() / self.size.max(1)) as u64).to_le_bytes())?;
+        write(&self.time.to_le_bytes())?;
+        write(&(self.existing.len() as u64).to_le_bytes())?;
+        write(&data_offset.to_le_bytes())?;
+        write(&index)?;
+        write(&self.data)?;
+        out.flush().map_err(|e| e.to_string())
+    }
+
+    pub fn load_snapshot(path: &str) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| e.to_string())?;
+        // every length in the snapshot is checked against this before anything is allocated for it
+        let file_len = file.metadata().map_err(|e| e.to_string())?.len();
+        let mut file = BufReader::new(file);
+        let consumed = Cell::new(0u64);
+        let mut read = |len: u64| -> Result<Vec<u8>, String> {
+            if len > file_len - consumed.get() {
+                return Err(format!("{} is truncated", path));
+            }
+            let mut buf = vec![0; len as usize];
+            file.read_exact(&mut buf[..]).map_err(|e| e.to_string())?;
+            consumed.set(consumed.get() + len);
+            Ok(buf)
+        };
+        let read_u64 = |bytes: Vec<u8>| u64::from_le_bytes(bytes[..].try_into().unwrap());
+
+        if read(SNAPSHOT_MAGIC.len() as u64)?[..] != SNAPSHOT_MAGIC[..] {
+            return Err(format!("{} is not a cache snapshot", path));
+        }
+        let version = u32::from_le_bytes(read(4)?[..].try_into().unwrap());
+        if version != SNAPSHOT_VERSION {
+            return Err(format!("Unsupported cache snapshot version {}", version));
+        }
+        let size        = read_u64(read(8)?);
+        let count       = read_u64(read(8)?);
+        let time        = read_u64(read(8)?);
+        let entries     = read_u64(read(8)?);
+        let data_offset = read_u64(read(8)?);
+
+        let data_len = match size.checked_mul(count) {
+            Some(data_len) if data_offset.checked_add(data_len).is_some_and(|end| end <= file_len) => data_len,
+            _ => return Err(format!("Cache snapshot of {} slots of {} bytes doesn't fit {}", count, size, path))
+        };
+        // the smallest entry is an empty key with its offset and time
+        if entries > file_len / 20 {
+            return Err(format!("Cache snapshot has more entries than fit {}", path));
+        }
+
+        let mut existing = HashMap::new();
+        for _ in 0..entries {
+            let key_len = u32::from_le_bytes(read(4)?[..].try_into().unwrap());
+            let key = String::from_utf8(read(key_len as u64)?).map_err(|e| e.to_string())?;
+            let offset = read_u64(read(8)?);
+            let entry_time = read_u64(read(8)?);
+            if offset.checked_add(size).is_none_or(|end| end > data_len) {
+                return Err(format!("Cache snapshot entry {} is out of bounds", key));
+            }
+            existing.insert(key, (offset as usize, entry_time));
+        }
+
+        if data_offset < consumed.get() {
+            return Err("Cache snapshot index overlaps its data".to_string());
+        }
+        read(data_offset - consumed.get())?;
+        let data = read(data_len)?;
+        let size = size as usize;
+
+        Ok(DatasetCache {
+            time,
+            existing,
+            data,
+            size
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("tiler-{}-{}", std::process::id(), name)).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn snapshots_round_trip() {
+        let path = temp_path("round-trip.cache");
+        let mut cache = DatasetCache::new(4, 2);
+        if let DatasetCacheResult::Invalid(slot) = cache.access_mut("1_2_0") {
+            slot.copy_from_slice(b"tile");
+        }
+        cache.save_snapshot(path.as_str()).unwrap();
+
+        let loaded = DatasetCache::load_snapshot(path.as_str()).unwrap();
+        assert_eq!(loaded.access("1_2_0"), Some(&b"tile"[..]));
+        assert_eq!(loaded.slot_size(), 4);
+
+        // padding between the index and the data offset
+        let mut bytes = std::fs::read(path.as_str()).unwrap();
+        let data_offset = u64::from_le_bytes(bytes[44..52].try_into().unwrap());
+        bytes[44..52].copy_from_slice(&4096u64.to_le_bytes());
+        bytes.splice(data_offset as usize..data_offset as usize, vec![0; 4096 - data_offset as usize]);
+        std::fs::write(path.as_str(), &bytes).unwrap();
+        let loaded = DatasetCache::load_snapshot(path.as_str()).unwrap();
+        assert_eq!(loaded.access("1_2_0"), Some(&b"tile"[..]));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn rejects_sizes_past_the_file() {
+        let path = temp_path("huge.cache");
+        DatasetCache::new(4, 2).save_snapshot(path.as_str()).unwrap();
+        let original = std::fs::read(path.as_str()).unwrap();
+
+        // slot size, slot count, entry count
+        for (at, value) in [(12, u64::MAX), (20, u64::MAX), (12, 1 << 40), (36, 1 << 40)] {
+            let mut bytes = original.clone();
+            bytes[at..at + 8].copy_from_slice(&value.to_le_bytes());
+            std::fs::write(path.as_str(), &bytes).unwrap();
+            assert!(DatasetCache::load_snapshot(path.as_str()).is_err());
+        }
+        std::fs::write(path.as_str(), &original[..original.len() - 1]).unwrap();
+        assert!(DatasetCache::load_snapshot(path.as_str()).is_err());
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,166 @@
+use std::convert::TryFrom;
+
+use crate::dataset::GeoTransform;
+use crate::image::ImageFormat;
+
+const TAG_IMAGE_WIDTH: u16 = 256;
+const TAG_IMAGE_LENGTH: u16 = 257;
+const TAG_BITS_PER_SAMPLE: u16 = 258;
+const TAG_COMPRESSION: u16 = 259;
+const TAG_PHOTOMETRIC: u16 = 262;
+const TAG_STRIP_OFFSETS: u16 = 273;
+const TAG_SAMPLES_PER_PIXEL: u16 = 277;
+const TAG_ROWS_PER_STRIP: u16 = 278;
+const TAG_STRIP_BYTE_COUNTS: u16 = 279;
+const TAG_PLANAR_CONFIGURATION: u16 = 284;
+const TAG_SAMPLE_FORMAT: u16 = 339;
+const TAG_MODEL_PIXEL_SCALE: u16 = 33550;
+const TAG_MODEL_TIEPOINT: u16 = 33922;
+const TAG_MODEL_TRANSFORMATION: u16 = 34264;
+const TAG_GEO_KEY_DIRECTORY: u16 = 34735;
+
+const TYPE_SHORT: u16 = 3;
+const TYPE_LONG: u16 = 4;
+const TYPE_DOUBLE: u16 = 12;
+
+// GTModelTypeGeoKey = geographic, GTRasterTypeGeoKey = pixel is area, GeographicTypeGeoKey = WGS 84
+const GEO_KEYS: [u16; 16] = [
+    1, 1, 0, 3,
+    1024, 0, 1, 2,
+    1025, 0, 1, 1,
+    2048, 0, 1, 4326
+];
+
+enum Values {
+    Short(Vec<u16>),
+    Long(Vec<u32>),
+    Double(Vec<f64>)
+}
+
+struct TiffWriter {
+    little_endian: bool
+}
+
+impl TiffWriter {
+    fn u16(&self, v: u16) -> [u8; 2] {
+        match self.little_endian {
+            true  => v.to_le_bytes(),
+            false => v.to_be_bytes()
+        }
+    }
+    fn u32(&self, v: u32) -> [u8; 4] {
+        match self.little_endian {
+            true  => v.to_le_bytes(),
+            false => v.to_be_bytes()
+        }
+    }
+    fn f64(&self, v: f64) -> [u8; 8] {
+        match self.little_endian {
+            true  => v.to_le_bytes(),
+            false => v.to_be_bytes()
+        }
+    }
+    fn bytes(&self, values: &Values) -> (u16, u32, Vec<u8>) {
+        match values {
+            Values::Short(v) => (TYPE_SHORT, v.len() as u32, v.iter().flat_map(|v| self.u16(*v).to_vec()).collect()),
+            Values::Long(v) => (TYPE_LONG, v.len() as u32, v.iter().flat_map(|v| self.u32(*v).to_vec()).collect()),
+            Values::Double(v) => (TYPE_DOUBLE, v.len() as u32, v.iter().flat_map(|v| self.f64(*v).to_vec()).collect())
+        }
+    }
+}
+
+// Uncompressed single strip TIFF of decoded samples, with GeoTIFF tags when georef is given.
+// Samples are written in the byte order they are in, native unless the encoding says they're swapped
+pub fn encode(format: ImageFormat, data: &[u8], georef: Option<GeoTransform>) -> Result<Vec<u8>, String> {
+    let encoding = format.encoding;
+    if data.len() != format.raw_size() {
+        return Err(format!("Image has {} bytes, expected {}", data.len(), format.raw_size()));
+    }
+    if ![8, 16, 32, 64].contains(&encoding.bit_depth) {
+        return Err(format!("Can't write {} bit samples to TIFF", encoding.bit_depth));
+    }
+    let data_len = u32::try_from(data.len()).map_err(|_| "Image is too large for TIFF".to_string())?;
+    let writer = TiffWriter {
+        little_endian: cfg!(target_endian = "little") != encoding.swap_endian
+    };
+
+    let mut entries: Vec<(u16, Values)> = vec![
+        (TAG_IMAGE_WIDTH, Values::Long(vec![format.size.x as u32])),
+        (TAG_IMAGE_LENGTH, Values::Long(vec![format.size.y as u32])),
+        (TAG_BITS_PER_SAMPLE, Values::Short(vec![encoding.bit_depth as u16; encoding.channels as usize])),
+        (TAG_COMPRESSION, Values::Short(vec![1])),
+        // RGB for colour, min is black for anything else
+        (TAG_PHOTOMETRIC, Values::Short(vec![if encoding.channels == 3 { 2 } else { 1 }])),
+        // filled in with the data offset when the IFD is written
+        (TAG_STRIP_OFFSETS, Values::Long(vec![0])),
+        (TAG_SAMPLES_PER_PIXEL, Values::Short(vec![encoding.channels as u16])),
+        (TAG_ROWS_PER_STRIP, Values::Long(vec![format.size.y as u32])),
+        (TAG_STRIP_BYTE_COUNTS, Values::Long(vec![data_len])),
+        (TAG_PLANAR_CONFIGURATION, Values::Short(vec![1])),
+        (TAG_SAMPLE_FORMAT, Values::Short(vec![if encoding.signed { 2 } else { 1 }; encoding.channels as usize]))
+    ];
+    if let Some(georef) = georef {
+        // scale and tiepoint can only express north up images, anything else needs the full matrix
+        if georef.pixel_size.x > 0.0 && georef.pixel_size.y < 0.0 {
+            entries.push((TAG_MODEL_PIXEL_SCALE, Values::Double(vec![georef.pixel_size.x, -georef.pixel_size.y, 0.0])));
+            entries.push((TAG_MODEL_TIEPOINT, Values::Double(vec![0.0, 0.0, 0.0, georef.origin.x, georef.origin.y, 0.0])));
+        } else {
+            entries.push((TAG_MODEL_TRANSFORMATION, Values::Double(vec![
+                georef.pixel_size.x, 0.0, 0.0, georef.origin.x,
+                0.0, georef.pixel_size.y, 0.0, georef.origin.y,
+                0.0, 0.0, 0.0, 0.0,
+                0.0, 0.0, 0.0, 1.0
+            ])));
+        }
+        entries.push((TAG_GEO_KEY_DIRECTORY, Values::Short(GEO_KEYS.to_vec())));
+    }
+    entries.sort_by_key(|(tag, _)| *tag);
+
+    let ifd_offset = 8u32;
+    let ifd_size = 2 + 12 * entries.len() as u32 + 4;
+    let encoded: Vec<(u16, u16, u32, Vec<u8>)>
+        =entries.iter()
+        .map(|(tag, values)| {
+            let (field_type, count, bytes) = writer.bytes(values);
+            (*tag, field_type, count, bytes)
+        })
+        .collect();
+    // values that don't fit in an entry follow the IFD, word aligned
+    let mut extra_len = 0u32;
+    for (_, _, _, bytes) in encoded.iter() {
+        if bytes.len() > 4 {
+            extra_len += (bytes.len() as u32 + 1) & !1;
+        }
+    }
+    let data_offset = ifd_offset + ifd_size + extra_len;
+
+    let mut res = Vec::with_capacity(data_offset as usize + data.len());
+    res.extend_from_slice(if writer.little_endian { b"II" } else { b"MM" });
+    res.extend_from_slice(&writer.u16(42));
+    res.extend_from_slice(&writer.u32(ifd_offset));
+
+    res.extend_from_slice(&writer.u16(encoded.len() as u16));
+    let mut extra = vec![];
+    for (tag, field_type, count, bytes) in encoded.iter() {
+        res.extend_from_slice(&writer.u16(*tag));
+        res.extend_from_slice(&writer.u16(*field_type));
+        res.extend_from_slice(&writer.u32(*count));
+        if *tag == TAG_STRIP_OFFSETS {
+            res.extend_from_slice(&writer.u32(data_offset));
+        } else if bytes.len() <= 4 {
+            let mut inline = bytes.clone();
+            inline.resize(4, 0);
+            res.extend_from_slice(&inline[..]);
+        } else {
+            res.extend_from_slice(&writer.u32(ifd_offset + ifd_size + extra.len() as u32));
+            extra.extend_from_slice(&bytes[..]);
+            if extra.len() % 2 == 1 {
+                extra.push(0);
+            }
+        }
+    }
+    res.extend_from_slice(&writer.u32(0));
+    res.extend_from_slice(&extra[..]);
+    res.extend_from_slice(data);
+    Ok(res)
+}
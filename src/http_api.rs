@@ -1,10 +1,23 @@
 use serde::{Serialize, Deserialize};
 use glam::*;
 use warp::*;
+use std::sync::Arc;
 
 use crate::image::*;
+use crate::preview::*;
+use crate::sampling::*;
 use crate::serde_json_warp;
 use crate::config::*;
+use crate::network_util::{local_path, HttpCredentials};
+use crate::tile_source::{FetchError, TileSourceConfig};
+use crate::tile_sink::filetype_extension;
+use crate::tile_server::*;
+use crate::dataset::TileTemplate;
+use crate::contours::{render_contour_tile, trace, ContourOptions};
+use crate::http_cache::{conditions, derived_etag, CachedResponse, Conditions, ResponseCache};
+use crate::checksum::{encode_hex, sha256};
+use crate::jobs::{JobEvent, JobQueue, RetilingSpec};
+use crate::util::math::Dabb2;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PreviewRequest {
@@ -12,7 +25,19 @@ pub struct PreviewRequest {
     pub decode_info: Option<ImageCodec>,
     pub manifest_uri: String,
     pub coord: IVec3,
-    pub range: Vec2
+    // sample values at the ends of the color map, stretched over the tile when not given
+    #[serde(default)]
+    pub range: Option<Vec2>,
+    #[serde(default)]
+    pub stretch: Stretch,
+    #[serde(default)]
+    pub color_map: ColorMap,
+    // auth and headers for the dataset's server, secrets have to be given inline. Other HTTP settings are the server's defaults
+    #[serde(default)]
+    pub http: HttpCredentials,
+    // renders a hillshade instead of the colour ramp, range still applies when blending
+    #[serde(default)]
+    pub hillshade: Option<HillshadeOptions>
 }
 
 macro_rules! warp_reject {
@@ -35,42 +60,766 @@ macro_rules! warp_reject {
 }
 
 warp_reject!(PreviewGenerateError);
+warp_reject!(String as BadRequest);
+warp_reject!(String as NotFound);
 warp_reject!(String as UriFormatError);
 warp_reject!(String as ImageDecodeError);
-warp_reject!(reqwest::Error as ReqwestError);
+warp_reject!(String as TileFetchError);
+warp_reject!(String as UpstreamTimeout);
+warp_reject!(String as Conflict);
 
-async fn get_preview(r: PreviewRequest) -> Result<impl warp::reply::Reply, warp::Rejection> {
-    let codec = r.decode_info.ok_or(reject())?;
+async fn get_preview(r: PreviewRequest, conditions: Conditions, cache: Arc<ResponseCache>) -> Result<impl warp::reply::Reply, warp::Rejection> {
+    // a hash of the whole request, credentials included, so a cached preview is only served to requests that could have made it
+    // without keeping the credentials themselves
+    let request = serde_json::to_string(&r).map_err(|e| BadRequest(e.to_string()))?;
+    let key = format!("preview {}", encode_hex(&sha256(request.as_bytes())));
+    if let Some(cached) = cache.get(key.as_str()) {
+        return Ok(cache.reply(&cached, &conditions));
+    }
+
+    let codec = r.decode_info.ok_or_else(|| BadRequest("decode_info is required".to_string()))?;
+
+    // Requests may only point at remote datasets, never at files on the server
+    if local_path(r.tile_uri_format.as_str()).is_some() || local_path(r.manifest_uri.as_str()).is_some() {
+        return Err(BadRequest("tile_uri_format and manifest_uri have to be remote URLs".to_string()).into());
+    }
+    let http = r.http.config();
+    if !http.only_literal_secrets() {
+        return Err(BadRequest("Secrets in requests have to be given as literals".to_string()).into());
+    }
+    TileTemplate::new(r.tile_uri_format.as_str()).map_err(UriFormatError)?;
+    r.color_map.validate().map_err(BadRequest)?;
+
+    let source = TileSourceConfig::Http {
+        tile_uri_format: r.tile_uri_format,
+        manifest_uri: Some(r.manifest_uri),
+        probe: None,
+        http,
+        max_zoom: None
+    };
 
     let mut dp
-    =DatasetProvider::create(r.tile_uri_format.as_str(), codec, r.manifest_uri.as_str()).await
-    .map_err(|_| reject())?;
+    =DatasetProvider::open(&source, codec).await
+    .map_err(TileFetchError)?;
 
-    dp.cache_resource(r.coord).await
-    .map_err(|_es| PreviewGenerateError)?;
+    let coord = r.coord;
+    dp.cache_resource(coord).await
+    .map_err(|e| fetch_rejection(coord, e))?;
+
+    let range = match r.range {
+        Some(range) => range,
+        None => {
+            let image
+                =dp.access_cached_resource(coord)
+                .ok_or(PreviewGenerateError)?;
+            let values = sample_values(&image).map_err(ImageDecodeError)?;
+            stretch_range(&values[..], r.stretch).ok_or(PreviewGenerateError)?
+        }
+    };
+
+    let preview = match r.hillshade {
+        Some(options) => {
+            render_hillshade_tile(&mut dp, coord, &options, range.x, range.y, &r.color_map).await
+            .map_err(|e| fetch_rejection(coord, e))?
+        },
+        None => {
+            let image
+                =dp.access_cached_resource(coord)
+                .ok_or(PreviewGenerateError)?;
+
+            make_preview_mapped(&image, range.x, range.y, &r.color_map)
+            .ok_or(PreviewGenerateError)?
+        }
+    };
     
+    // the range actually used, so a legend can be labelled to match
+    let response
+        =CachedResponse::new(preview.data, "image/png", std::time::SystemTime::now())
+        .with_header("X-Value-Range", format!("{},{}", range.x, range.y))
+        .private();
+    cache.insert(key, response.clone());
+    Ok(cache.reply(&response, &conditions))
+}
+
+fn default_legend_size() -> IVec2 {
+    ivec2(256, 16)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LegendRequest {
+    #[serde(default)]
+    pub color_map: ColorMap,
+    #[serde(default = "default_legend_size")]
+    pub size: IVec2,
+    #[serde(default)]
+    pub vertical: bool
+}
+
+const MAX_LEGEND_SIZE: i32 = 4096;
+
+fn legend_response(map: &ColorMap, size: IVec2, vertical: bool) -> Result<warp::http::Response<Vec<u8>>, warp::Rejection> {
+    map.validate().map_err(BadRequest)?;
+    if size.min_element() <= 0 || size.max_element() > MAX_LEGEND_SIZE {
+        return Err(BadRequest(format!("Legend size has to be between 1 and {}", MAX_LEGEND_SIZE)).into());
+    }
+    let legend = make_legend(map, size, vertical).ok_or(PreviewGenerateError)?;
+    warp::http::Response::builder()
+    .header("Content-Type", "image/png")
+    .body(legend.data)
+    .map_err(|_| PreviewGenerateError.into())
+}
+
+// Gradient of a color map, to be labelled with the range a preview was rendered with
+async fn get_legend(r: LegendRequest) -> Result<impl warp::reply::Reply, warp::Rejection> {
+    legend_response(&r.color_map, r.size, r.vertical)
+}
+
+// Legend of a dataset's tiles, its range is in X-Value-Range
+async fn get_dataset_legend(name_ext: String, datasets: Datasets) -> Result<impl warp::reply::Reply, warp::Rejection> {
+    let (name, ext) = name_ext.rsplit_once('.').ok_or_else(|| BadRequest(format!("{} has no extension", name_ext)))?;
+    if ext != "png" {
+        return Err(NotFound(format!("Legends can't be served as {}", ext)).into());
+    }
+    let dataset = find_dataset(&datasets, name)?;
+    let range = dataset.config.preview_range.unwrap_or(DEFAULT_PREVIEW_RANGE);
+    let mut response = legend_response(&dataset.config.color_map, default_legend_size(), false)?;
+    if let Ok(value) = format!("{},{}", range.x, range.y).parse() {
+        response.headers_mut().insert("X-Value-Range", value);
+    }
+    Ok(response)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RegionRequest {
+    // pixels in the grid of level
+    pub region: Dabb2,
+    #[serde(default)]
+    pub level: i32
+}
+
+// Keeps a single request from allocating more than a few hundred megabytes
+const MAX_REGION_PIXELS: i64 = 8192 * 8192;
+
+// Arbitrary pixel rectangle of a dataset as raw samples, a 16 bit png or a GeoTIFF
+async fn get_region(name_ext: String, r: RegionRequest, datasets: Datasets) -> Result<impl warp::reply::Reply, warp::Rejection> {
+    let (name, ext) = name_ext.rsplit_once('.').ok_or_else(|| BadRequest(format!("{} has no extension", name_ext)))?;
+    let dataset = datasets.get(name).ok_or_else(|| NotFound(format!("No dataset named {}", name)))?;
+    let size = r.region.end.as_i64vec2() - r.region.begin.as_i64vec2();
+    if size.x <= 0 || size.y <= 0 {
+        return Err(BadRequest(format!("Region {:?} is empty", r.region)).into());
+    }
+    if size.x * size.y > MAX_REGION_PIXELS {
+        return Err(BadRequest(format!("Region {:?} is larger than {} pixels", r.region, MAX_REGION_PIXELS)).into());
+    }
+
+    let mut dp = dataset.provider.lock().await;
+    dp.check_level(r.level).map_err(BadRequest)?;
+    let image = match dp.read_region(r.region, r.level).await {
+        Ok(image) => image,
+        Err(FetchError::Missing) => return Err(NotFound(format!("Dataset {} has no tiles in {:?} at level {}", name, r.region, r.level)).into()),
+        Err(e) => return Err(fetch_rejection(ivec3(r.region.begin.x, r.region.begin.y, r.level), e))
+    };
+
+    let (body, content_type) = match ext {
+        "raw" => (image.data, "application/octet-stream"),
+        "png" => (image.compress(ImageFiletype::PNG).map_err(BadRequest)?, "image/png"),
+        "tif" | "tiff" => (
+            crate::geotiff::encode(image.format, &image.data[..], dp.region_georef(r.region, r.level)).map_err(BadRequest)?,
+            "image/tiff"
+        ),
+        ext => return Err(NotFound(format!("Regions can't be served as {}", ext)).into())
+    };
+
+    Ok(
+        warp::http::Response::builder()
+        .header("Content-Type", content_type)
+        .body(body)
+    )
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ContourRequest {
+    // pixels in the grid of level
+    pub region: Dabb2,
+    #[serde(default)]
+    pub level: i32,
+    // override the dataset's contour options
+    #[serde(default)]
+    pub interval: Option<f64>,
+    #[serde(default)]
+    pub base: Option<f64>
+}
+
+// Contours need a value per pixel on top of the samples, so regions are kept smaller than for /regions
+const MAX_CONTOUR_REGION_PIXELS: i64 = 2048 * 2048;
+
+// Contours of a region as a GeoJSON FeatureCollection of MultiLineStrings in lon/lat, one per elevation
+async fn get_contours(name_ext: String, r: ContourRequest, datasets: Datasets) -> Result<impl warp::reply::Reply, warp::Rejection> {
+    let (name, ext) = name_ext.rsplit_once('.').ok_or_else(|| BadRequest(format!("{} has no extension", name_ext)))?;
+    if ext != "geojson" && ext != "json" {
+        return Err(NotFound(format!("Contours of regions can't be served as {}", ext)).into());
+    }
+    let dataset = find_dataset(&datasets, name)?;
+    let size = r.region.end.as_i64vec2() - r.region.begin.as_i64vec2();
+    if size.x <= 0 || size.y <= 0 {
+        return Err(BadRequest(format!("Region {:?} is empty", r.region)).into());
+    }
+    if size.x * size.y > MAX_CONTOUR_REGION_PIXELS {
+        return Err(BadRequest(format!("Region {:?} is larger than {} pixels", r.region, MAX_CONTOUR_REGION_PIXELS)).into());
+    }
+    let options = ContourOptions {
+        interval: r.interval.unwrap_or(dataset.config.contours.interval),
+        base: r.base.unwrap_or(dataset.config.contours.base)
+    };
+
+    let mut dp = dataset.provider.lock().await;
+    check_sampleable(&dataset, &dp, r.level)?;
+    let values = match dp.read_region_values(r.region, r.level).await {
+        Ok(values) => values,
+        Err(FetchError::Missing) => return Err(NotFound(format!("Dataset {} has no tiles in {:?} at level {}", name, r.region, r.level)).into()),
+        Err(e) => return Err(fetch_rejection(ivec3(r.region.begin.x, r.region.begin.y, r.level), e))
+    };
+    let contours = trace(&values[..], r.region, &options).map_err(BadRequest)?;
+
+    let georef = dp.tilespace.georef.ok_or(PreviewGenerateError)?;
+    let scale = 2f64.powi(r.level);
+    let features: Vec<serde_json::Value> = contours.iter().map(|contour| serde_json::json!({
+        "type": "Feature",
+        "properties": { "elevation": contour.elevation },
+        "geometry": {
+            "type": "MultiLineString",
+            "coordinates": contour.lines.iter().map(|line| {
+                line.iter().map(|p| {
+                    let geo = georef.pixel_to_geo(*p * scale);
+                    [geo.x, geo.y]
+                }).collect::<Vec<_>>()
+            }).collect::<Vec<_>>()
+        }
+    })).collect();
+
+    Ok(warp::reply::json(&serde_json::json!({
+        "type": "FeatureCollection",
+        "features": features
+    })))
+}
+
+// Contours of a tile as a Mapbox Vector Tile, with the dataset's contour options
+async fn get_contour_tile(name: String, z: i32, x: i32, y_ext: String, conditions: Conditions, cache: Arc<ResponseCache>, datasets: Datasets) -> Result<impl warp::reply::Reply, warp::Rejection> {
+    let (y, ext) = split_row(y_ext.as_str())?;
+    if ext != "mvt" && ext != "pbf" {
+        return Err(NotFound(format!("Contour tiles can't be served as {}", ext)).into());
+    }
+    let dataset = find_dataset(&datasets, name.as_str())?;
+    let coord = dataset.url_coord(z, x, y);
+
+    let key = format!("contours/{}/{}/{}/{}", name, z, x, y_ext);
+    let etag = derived_etag(key.as_str(), dataset.opened);
+    if let Some(not_modified) = cache.revalidate(etag.as_str(), dataset.opened, &conditions) {
+        return Ok(not_modified);
+    }
+    if let Some(cached) = cache.get(key.as_str()) {
+        return Ok(cache.reply(&cached, &conditions));
+    }
+
+    let mut dp = dataset.provider.lock().await;
+    if !dp.manifest.contains(coord) {
+        return Err(fetch_rejection(coord, FetchError::Missing));
+    }
+    let tile
+        =render_contour_tile(&mut dp, coord, &dataset.config.contours).await
+        .map_err(|e| fetch_rejection(coord, e))?;
+
+    let response = CachedResponse::new(tile, "application/vnd.mapbox-vector-tile", dataset.opened).with_etag(etag);
+    cache.insert(key, response.clone());
+    Ok(cache.reply(&response, &conditions))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PointRequest {
+    pub lon: f64,
+    pub lat: f64,
+    #[serde(default)]
+    pub level: i32,
+    #[serde(default)]
+    pub interpolation: Interpolation
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PointsRequest {
+    // (lon, lat)
+    pub points: Vec<DVec2>,
+    #[serde(default)]
+    pub level: i32,
+    #[serde(default)]
+    pub interpolation: Interpolation
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PointsResponse {
+    pub samples: Vec<PointSample>
+}
+
+const MAX_BATCH_POINTS: usize = 100_000;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProfileRequest {
+    pub line: ProfileLine,
+    pub spacing: ProfileSpacing,
+    #[serde(default)]
+    pub level: i32,
+    #[serde(default)]
+    pub interpolation: Interpolation
+}
+
+fn check_sampleable(dataset: &Dataset, dp: &DatasetProvider, level: i32) -> Result<(), warp::Rejection> {
+    if dp.tilespace.georef.is_none() {
+        return Err(BadRequest(format!("Dataset {} has no georeference to sample by", dataset.name)).into());
+    }
+    dp.check_level(level).map_err(|e| BadRequest(e).into())
+}
+
+// Height at a single lon/lat
+async fn get_point(name: String, r: PointRequest, datasets: Datasets) -> Result<impl warp::reply::Reply, warp::Rejection> {
+    let dataset = find_dataset(&datasets, name.as_str())?;
+    let mut dp = dataset.provider.lock().await;
+    check_sampleable(&dataset, &dp, r.level)?;
+    let geo = dvec2(r.lon, r.lat);
+    let sample
+        =sample_point(&mut dp, geo, r.level, r.interpolation).await
+        .map_err(|e| missing_rejection(format!("Level {} of {} has no data to sample", r.level, name), e))?;
+    Ok(warp::reply::json(&sample))
+}
+
+// Heights at many lon/lats, in the order given
+async fn post_points(name: String, r: PointsRequest, datasets: Datasets) -> Result<impl warp::reply::Reply, warp::Rejection> {
+    if r.points.len() > MAX_BATCH_POINTS {
+        return Err(BadRequest(format!("At most {} points can be sampled at once", MAX_BATCH_POINTS)).into());
+    }
+    let dataset = find_dataset(&datasets, name.as_str())?;
+    let mut dp = dataset.provider.lock().await;
+    check_sampleable(&dataset, &dp, r.level)?;
+    let samples
+        =sample_points(&mut dp, &r.points[..], r.level, r.interpolation).await
+        .map_err(|e| missing_rejection(format!("Level {} of {} has no data to sample", r.level, name), e))?;
+    Ok(warp::reply::json(&PointsResponse { samples }))
+}
+
+// Elevation along a line with its length, ascent and descent
+async fn post_profile(name: String, r: ProfileRequest, datasets: Datasets) -> Result<impl warp::reply::Reply, warp::Rejection> {
+    let vertices = r.line.vertices().map_err(BadRequest)?;
+    let positions = r.spacing.positions(&vertices[..]).map_err(BadRequest)?;
+
+    let dataset = find_dataset(&datasets, name.as_str())?;
+    let mut dp = dataset.provider.lock().await;
+    check_sampleable(&dataset, &dp, r.level)?;
+    let profile
+        =sample_profile(&mut dp, &positions[..], r.level, r.interpolation).await
+        .map_err(|e| missing_rejection(format!("Level {} of {} has no data to sample", r.level, name), e))?;
+    Ok(warp::reply::json(&profile))
+}
+
+// Used for rendered tiles when the dataset doesn't configure a range, meters of elevation
+const DEFAULT_PREVIEW_RANGE: Vec2 = Vec2::new(0.0, 4000.0);
+
+fn content_type(ext: &str) -> &'static str {
+    match ext {
+        "png"  => "image/png",
+        "tiff" => "image/tiff",
+        _      => "application/octet-stream"
+    }
+}
+
+fn fetch_rejection(coord: IVec3, e: FetchError) -> warp::Rejection {
+    missing_rejection(format!("Tile {:?} is not in the dataset", coord), e)
+}
+
+// For fetches that aren't of one tile, missing says what wasn't found
+fn missing_rejection(missing: String, e: FetchError) -> warp::Rejection {
+    match e {
+        FetchError::Missing => warp::reject::custom(NotFound(missing)),
+        FetchError::Failed(reason) => warp::reject::custom(TileFetchError(reason)),
+        FetchError::Timeout(reason) => warp::reject::custom(UpstreamTimeout(reason)),
+        FetchError::Invalid(reason) => warp::reject::custom(ImageDecodeError(reason))
+    }
+}
+
+// The row and extension of the last segment of a tile URL
+fn split_row(y_ext: &str) -> Result<(i32, &str), warp::Rejection> {
+    let (y, ext) = y_ext.split_once('.').ok_or_else(|| BadRequest(format!("{} has no extension", y_ext)))?;
+    let y: i32 = y.parse().map_err(|_| BadRequest(format!("{} is not a row number", y)))?;
+    Ok((y, ext))
+}
+
+fn find_dataset(datasets: &Datasets, name: &str) -> Result<Arc<Dataset>, warp::Rejection> {
+    Ok(datasets.get(name).ok_or_else(|| NotFound(format!("No dataset named {}", name)))?.clone())
+}
+
+// The stored tile when ext is the dataset's own format, a colour mapped rendering for png otherwise
+async fn get_tile(name: String, z: i32, x: i32, y_ext: String, conditions: Conditions, cache: Arc<ResponseCache>, datasets: Datasets) -> Result<impl warp::reply::Reply, warp::Rejection> {
+    let (y, ext) = split_row(y_ext.as_str())?;
+    let dataset = find_dataset(&datasets, name.as_str())?;
+    let coord = dataset.url_coord(z, x, y);
+
+    let key = format!("tiles/{}/{}/{}/{}", name, z, x, y_ext);
+    let etag = derived_etag(key.as_str(), dataset.opened);
+    if let Some(not_modified) = cache.revalidate(etag.as_str(), dataset.opened, &conditions) {
+        return Ok(not_modified);
+    }
+    if let Some(cached) = cache.get(key.as_str()) {
+        return Ok(cache.reply(&cached, &conditions));
+    }
+
+    let mut dp = dataset.provider.lock().await;
+
+    let body = match ext {
+        ext if ext == filetype_extension(dp.codec.filetype) => {
+            dp.fetch_verified(coord).await.map_err(|e| fetch_rejection(coord, e))?
+        },
+        "png" => {
+            dp.cache_resource(coord).await.map_err(|e| fetch_rejection(coord, e))?;
+            let image
+                =dp.access_cached_resource(coord)
+                .ok_or(PreviewGenerateError)?;
+            let range = dataset.config.preview_range.unwrap_or(DEFAULT_PREVIEW_RANGE);
+            make_preview_mapped(&image, range.x, range.y, &dataset.config.color_map)
+            .ok_or(PreviewGenerateError)?
+            .data
+        },
+        ext => return Err(NotFound(format!("Dataset {} can't be served as {}", name, ext)).into())
+    };
+
+    let response = CachedResponse::new(body, content_type(ext), dataset.opened).with_etag(etag);
+    cache.insert(key, response.clone());
+    Ok(cache.reply(&response, &conditions))
+}
+
+// Hillshade rendering of a tile, lit as the dataset configures
+async fn get_hillshade_tile(name: String, z: i32, x: i32, y_ext: String, conditions: Conditions, cache: Arc<ResponseCache>, datasets: Datasets) -> Result<impl warp::reply::Reply, warp::Rejection> {
+    let (y, ext) = split_row(y_ext.as_str())?;
+    if ext != "png" {
+        return Err(NotFound(format!("Hillshades can't be served as {}", ext)).into());
+    }
+    let dataset = find_dataset(&datasets, name.as_str())?;
+    let coord = dataset.url_coord(z, x, y);
+
+    let key = format!("hillshade/{}/{}/{}/{}", name, z, x, y_ext);
+    let etag = derived_etag(key.as_str(), dataset.opened);
+    if let Some(not_modified) = cache.revalidate(etag.as_str(), dataset.opened, &conditions) {
+        return Ok(not_modified);
+    }
+    if let Some(cached) = cache.get(key.as_str()) {
+        return Ok(cache.reply(&cached, &conditions));
+    }
+    let range = dataset.config.preview_range.unwrap_or(DEFAULT_PREVIEW_RANGE);
+
+    let mut dp = dataset.provider.lock().await;
     let image
-        =dp.access_cached_resource(r.coord)
-        .ok_or(PreviewGenerateError)?;
+        =render_hillshade_tile(&mut dp, coord, &dataset.config.hillshade, range.x, range.y, &dataset.config.color_map).await
+        .map_err(|e| fetch_rejection(coord, e))?;
+
+    let response = CachedResponse::new(image.data, "image/png", dataset.opened).with_etag(etag);
+    cache.insert(key, response.clone());
+    Ok(cache.reply(&response, &conditions))
+}
+
+#[derive(Serialize, Debug)]
+struct ErrorResponse {
+    status: u16,
+    error: &'static str,
+    message: String
+}
+
+// Every rejection becomes a JSON error body, causes are logged since clients only see the message
+async fn handle_rejection(err: warp::Rejection) -> Result<impl warp::reply::Reply, std::convert::Infallible> {
+    use warp::http::StatusCode;
+
+    let (status, error, message) = if let Some(e) = err.find::<BadRequest>() {
+        (StatusCode::BAD_REQUEST, "bad_request", e.to_string())
+    } else if let Some(e) = err.find::<serde_json_warp::InvalidQuery>() {
+        (StatusCode::BAD_REQUEST, "invalid_query", e.0.clone())
+    } else if let Some(e) = err.find::<warp::body::BodyDeserializeError>() {
+        (StatusCode::BAD_REQUEST, "invalid_body", e.to_string())
+    } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+        (StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large", "Request body is too large".to_string())
+    } else if let Some(e) = err.find::<UriFormatError>() {
+        (StatusCode::BAD_REQUEST, "invalid_uri_format", e.to_string())
+    } else if let Some(e) = err.find::<Conflict>() {
+        (StatusCode::CONFLICT, "conflict", e.to_string())
+    } else if let Some(e) = err.find::<NotFound>() {
+        (StatusCode::NOT_FOUND, "not_found", e.to_string())
+    } else if err.is_not_found() {
+        (StatusCode::NOT_FOUND, "not_found", "No such route".to_string())
+    } else if let Some(e) = err.find::<TileFetchError>() {
+        (StatusCode::BAD_GATEWAY, "fetch_failed", e.to_string())
+    } else if let Some(e) = err.find::<ImageDecodeError>() {
+        (StatusCode::BAD_GATEWAY, "invalid_tile", e.to_string())
+    } else if let Some(e) = err.find::<UpstreamTimeout>() {
+        (StatusCode::GATEWAY_TIMEOUT, "upstream_timeout", e.to_string())
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        (StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed", "Method not allowed".to_string())
+    } else if err.find::<PreviewGenerateError>().is_some() {
+        (StatusCode::INTERNAL_SERVER_ERROR, "preview_failed", "Couldn't render the preview".to_string())
+    } else {
+        println!("Unhandled rejection: {:?}", err);
+        (StatusCode::INTERNAL_SERVER_ERROR, "internal", "Internal error".to_string())
+    };
+
+    println!("{} {}: {}", status.as_u16(), error, message);
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&ErrorResponse { status: status.as_u16(), error, message }),
+        status
+    ))
+}
+
+// TileJSON for MapLibre and similar clients
+async fn get_tilejson(name_ext: String, base_url: String, datasets: Datasets) -> Result<impl warp::reply::Reply, warp::Rejection> {
+    let (name, ext) = name_ext.rsplit_once('.').ok_or_else(|| BadRequest(format!("{} has no extension", name_ext)))?;
+    if ext != "json" {
+        return Err(NotFound(format!("TileJSON can't be served as {}", ext)).into());
+    }
+    let dataset = find_dataset(&datasets, name)?;
+    let dp = dataset.provider.lock().await;
+    Ok(warp::reply::json(&crate::capabilities::tilejson(&dataset, &dp, base_url.as_str())))
+}
+
+// WMTS capabilities of every dataset, for QGIS and similar clients
+async fn get_wmts_capabilities(base_url: String, datasets: Datasets) -> Result<impl warp::reply::Reply, warp::Rejection> {
+    let mut names: Vec<&String> = datasets.keys().collect();
+    names.sort();
+    let mut locked = vec![];
+    for name in names {
+        let dataset = &datasets[name];
+        locked.push((dataset.as_ref(), dataset.provider.lock().await));
+    }
+    let providers: Vec<(&Dataset, &DatasetProvider)> = locked.iter().map(|(d, dp)| (*d, &**dp)).collect();
 
-    let preview
-    =crate::preview::make_preview(&image, r.range.x, r.range.y)
-    .ok_or(PreviewGenerateError)?;
-    
     Ok(
         warp::http::Response::builder()
-        .header("Content-Type", "image/png")
-        .body(preview.data)
+        .header("Content-Type", "application/xml")
+        .body(crate::capabilities::wmts_capabilities(&providers[..], base_url.as_str()))
     )
 }
 
-pub async fn run() {
-    let routes
+fn job_queue(jobs: Option<Arc<JobQueue>>) -> Result<Arc<JobQueue>, warp::Rejection> {
+    jobs.ok_or_else(|| NotFound("Retiling jobs aren't enabled, see jobs in the server config".to_string()).into())
+}
+
+async fn post_job(spec: RetilingSpec, jobs: Option<Arc<JobQueue>>) -> Result<impl warp::reply::Reply, warp::Rejection> {
+    let record = job_queue(jobs)?.submit(spec).map_err(BadRequest)?;
+    Ok(warp::reply::with_status(warp::reply::json(&record), warp::http::StatusCode::ACCEPTED))
+}
+
+async fn get_jobs(jobs: Option<Arc<JobQueue>>) -> Result<impl warp::reply::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&job_queue(jobs)?.list()))
+}
+
+async fn get_job(id: u64, jobs: Option<Arc<JobQueue>>) -> Result<impl warp::reply::Reply, warp::Rejection> {
+    let record = job_queue(jobs)?.get(id).ok_or_else(|| NotFound(format!("No job {}", id)))?;
+    Ok(warp::reply::json(&record))
+}
+
+async fn cancel_job(id: u64, jobs: Option<Arc<JobQueue>>) -> Result<impl warp::reply::Reply, warp::Rejection> {
+    let record
+        =job_queue(jobs)?.cancel(id)
+        .map_err(Conflict)?
+        .ok_or_else(|| NotFound(format!("No job {}", id)))?;
+    Ok(warp::reply::json(&record))
+}
+
+// Server-Sent Events with the job's state and progress, starting with where it is now and ending once it finishes.
+// Event names are the job's state
+async fn job_events(id: u64, jobs: Option<Arc<JobQueue>>) -> Result<impl warp::reply::Reply, warp::Rejection> {
+    use tokio::sync::broadcast::error::RecvError;
+
+    let queue = job_queue(jobs)?;
+    // subscribed before looking the job up so nothing in between is missed
+    let receiver = queue.subscribe();
+    let current = queue.get(id).ok_or_else(|| NotFound(format!("No job {}", id)))?;
+
+    let events = futures_util::stream::unfold((Some(JobEvent::of(&current)), receiver, false), move |(pending, mut receiver, finished)| async move {
+        if finished {
+            return None;
+        }
+        let event = match pending {
+            Some(event) => event,
+            None => loop {
+                match receiver.recv().await {
+                    Ok(event) if event.id == id => break event,
+                    Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None
+                }
+            }
+        };
+        let finished = event.state.is_finished();
+        Some((warp::sse::Event::default().event(event.state.name()).json_data(&event), (None, receiver, finished)))
+    });
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)))
+}
+
+// public_url when configured, otherwise whatever host the client used to reach us
+fn with_base_url(public_url: Option<String>, address: std::net::SocketAddr) -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("host")
+    .map(move |host: Option<String>| {
+        match (&public_url, host) {
+            (Some(url), _) => url.trim_end_matches('/').to_string(),
+            (None, Some(host)) => format!("http://{}", host),
+            (None, None) => format!("http://{}", address)
+        }
+    })
+}
+
+fn with_datasets(datasets: Datasets) -> impl Filter<Extract = (Datasets,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || datasets.clone())
+}
+
+fn with_cache(cache: Arc<ResponseCache>) -> impl Filter<Extract = (Arc<ResponseCache>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || cache.clone())
+}
+
+fn with_jobs(jobs: Option<Arc<JobQueue>>) -> impl Filter<Extract = (Option<Arc<JobQueue>>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || jobs.clone())
+}
+
+pub async fn run(config: ServerConfig) -> Result<(), String> {
+    let datasets = open_datasets(&config).await?;
+    let jobs = match &config.jobs {
+        Some(jobs_config) => Some(JobQueue::start(jobs_config.clone(), datasets.clone())?),
+        None => None
+    };
+    let cache = Arc::new(ResponseCache::new(config.caching.clone()));
+
+    let preview
     =warp::get()
+    .and(warp::path::end())
     .and(serde_json_warp::query::<PreviewRequest>())
+    .and(conditions())
+    .and(with_cache(cache.clone()))
     .and_then(get_preview);
 
-    warp::serve(routes)
-    .run(([127, 0, 0, 1], 3000))
-    .await
+    let tiles
+    =warp::get()
+    .and(warp::path!("tiles" / String / i32 / i32 / String))
+    .and(conditions())
+    .and(with_cache(cache.clone()))
+    .and(with_datasets(datasets.clone()))
+    .and_then(get_tile);
+
+    let hillshade
+    =warp::get()
+    .and(warp::path!("hillshade" / String / i32 / i32 / String))
+    .and(conditions())
+    .and(with_cache(cache.clone()))
+    .and(with_datasets(datasets.clone()))
+    .and_then(get_hillshade_tile);
+
+    let contour_tiles
+    =warp::get()
+    .and(warp::path!("contours" / String / i32 / i32 / String))
+    .and(conditions())
+    .and(with_cache(cache))
+    .and(with_datasets(datasets.clone()))
+    .and_then(get_contour_tile);
+
+    let contours
+    =warp::get()
+    .and(warp::path!("contours" / String))
+    .and(serde_json_warp::query::<ContourRequest>())
+    .and(with_datasets(datasets.clone()))
+    .and_then(get_contours);
+
+    let legend
+    =warp::get()
+    .and(warp::path!("legend"))
+    .and(serde_json_warp::query::<LegendRequest>())
+    .and_then(get_legend);
+
+    let dataset_legend
+    =warp::get()
+    .and(warp::path!("legend" / String))
+    .and(with_datasets(datasets.clone()))
+    .and_then(get_dataset_legend);
+
+    let point
+    =warp::get()
+    .and(warp::path!("points" / String))
+    .and(serde_json_warp::query::<PointRequest>())
+    .and(with_datasets(datasets.clone()))
+    .and_then(get_point);
+
+    let points
+    =warp::post()
+    .and(warp::path!("points" / String))
+    .and(warp::body::content_length_limit(16 * 1024 * 1024))
+    .and(warp::body::json::<PointsRequest>())
+    .and(with_datasets(datasets.clone()))
+    .and_then(post_points);
+
+    let profile
+    =warp::post()
+    .and(warp::path!("profile" / String))
+    .and(warp::body::content_length_limit(16 * 1024 * 1024))
+    .and(warp::body::json::<ProfileRequest>())
+    .and(with_datasets(datasets.clone()))
+    .and_then(post_profile);
+
+    let base_url = with_base_url(config.public_url.clone(), config.address);
+
+    let tilejson
+    =warp::get()
+    .and(warp::path!("tilejson" / String))
+    .and(base_url.clone())
+    .and(with_datasets(datasets.clone()))
+    .and_then(get_tilejson);
+
+    let wmts
+    =warp::get()
+    .and(warp::path!("wmts" / "1.0.0" / "WMTSCapabilities.xml"))
+    .and(base_url)
+    .and(with_datasets(datasets.clone()))
+    .and_then(get_wmts_capabilities);
+
+    let submit_job
+    =warp::post()
+    .and(warp::path!("jobs"))
+    .and(warp::body::content_length_limit(1024 * 1024))
+    .and(warp::body::json::<RetilingSpec>())
+    .and(with_jobs(jobs.clone()))
+    .and_then(post_job);
+
+    let list_jobs
+    =warp::get()
+    .and(warp::path!("jobs"))
+    .and(with_jobs(jobs.clone()))
+    .and_then(get_jobs);
+
+    let job
+    =warp::get()
+    .and(warp::path!("jobs" / u64))
+    .and(with_jobs(jobs.clone()))
+    .and_then(get_job);
+
+    let job_event_stream
+    =warp::get()
+    .and(warp::path!("jobs" / u64 / "events"))
+    .and(with_jobs(jobs.clone()))
+    .and_then(job_events);
+
+    let delete_job
+    =warp::delete()
+    .and(warp::path!("jobs" / u64))
+    .and(with_jobs(jobs))
+    .and_then(cancel_job);
+
+    let regions
+    =warp::get()
+    .and(warp::path!("regions" / String))
+    .and(serde_json_warp::query::<RegionRequest>())
+    .and(with_datasets(datasets))
+    .and_then(get_region);
+
+    warp::serve(preview.or(tiles).or(hillshade).or(legend).or(dataset_legend).or(regions).or(point).or(points).or(profile).or(tilejson).or(wmts).or(submit_job).or(list_jobs).or(job).or(job_event_stream).or(delete_job).or(contour_tiles).or(contours).recover(handle_rejection))
+    .run(config.address)
+    .await;
+    Ok(())
 }
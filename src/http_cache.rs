@@ -0,0 +1,215 @@
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use warp::Filter;
+use warp::http::{Response, StatusCode};
+use warp::hyper::body::Bytes;
+
+use crate::checksum::{encode_hex, sha256};
+
+fn default_max_age() -> u64 {
+    3600
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CachingConfig {
+    // seconds clients and proxies may reuse tiles and previews without asking again
+    #[serde(default = "default_max_age")]
+    pub max_age: u64,
+    // rendered responses kept in memory, least recently used first out. 0 turns it off
+    #[serde(default)]
+    pub response_cache_bytes: usize
+}
+
+impl Default for CachingConfig {
+    fn default() -> Self {
+        CachingConfig {
+            max_age: default_max_age(),
+            response_cache_bytes: 0
+        }
+    }
+}
+
+fn etag_of(bytes: &[u8]) -> String {
+    format!("\"{}\"", encode_hex(&sha256(bytes)[..12]))
+}
+
+// ETag of a response that only depends on what's at key since last_modified, known before the response is rendered
+pub fn derived_etag(key: &str, last_modified: SystemTime) -> String {
+    let since = last_modified.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+    etag_of(format!("{} {}", key, since).as_bytes())
+}
+
+// A response as it's kept in the cache, headers that depend on the request are added when replying
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    pub body: Bytes,
+    pub content_type: &'static str,
+    pub headers: Vec<(&'static str, String)>,
+    pub etag: String,
+    pub last_modified: SystemTime,
+    // only the requesting client may keep it, shared caches may not
+    pub private: bool
+}
+
+impl CachedResponse {
+    pub fn new(body: Vec<u8>, content_type: &'static str, last_modified: SystemTime) -> Self {
+        CachedResponse {
+            etag: etag_of(&body[..]),
+            body: Bytes::from(body),
+            content_type,
+            headers: vec![],
+            last_modified,
+            private: false
+        }
+    }
+
+    pub fn with_header(mut self, name: &'static str, value: String) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    pub fn with_etag(mut self, etag: String) -> Self {
+        self.etag = etag;
+        self
+    }
+
+    pub fn private(mut self) -> Self {
+        self.private = true;
+        self
+    }
+
+    // If-None-Match wins over If-Modified-Since when both are given
+    fn not_modified(&self, conditions: &Conditions) -> bool {
+        if let Some(if_none_match) = &conditions.if_none_match {
+            return if_none_match.split(',').map(|tag| tag.trim()).any(|tag| {
+                tag == "*" || tag.trim_start_matches("W/") == self.etag
+            });
+        }
+        // HTTP dates have whole seconds
+        let seconds = |time: SystemTime| time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        match conditions.if_modified_since.as_deref().map(httpdate::parse_http_date) {
+            Some(Ok(since)) => seconds(self.last_modified) <= seconds(since),
+            _ => false
+        }
+    }
+}
+
+// Validators from the request headers
+#[derive(Debug, Clone, Default)]
+pub struct Conditions {
+    pub if_none_match: Option<String>,
+    pub if_modified_since: Option<String>
+}
+
+pub fn conditions() -> impl Filter<Extract = (Conditions,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("if-none-match")
+    .and(warp::header::optional::<String>("if-modified-since"))
+    .map(|if_none_match, if_modified_since| Conditions { if_none_match, if_modified_since })
+}
+
+#[derive(Debug, Default)]
+struct Lru {
+    // key to (last use, response)
+    entries: HashMap<String, (u64, CachedResponse)>,
+    uses: BTreeMap<u64, String>,
+    clock: u64,
+    bytes: usize
+}
+
+#[derive(Debug)]
+pub struct ResponseCache {
+    config: CachingConfig,
+    lru: Mutex<Lru>
+}
+
+impl ResponseCache {
+    pub fn new(config: CachingConfig) -> Self {
+        ResponseCache {
+            config,
+            lru: Mutex::new(Lru::default())
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<CachedResponse> {
+        let mut lru = self.lru.lock().unwrap();
+        lru.clock += 1;
+        let clock = lru.clock;
+        let (last_use, response) = lru.entries.get_mut(key)?;
+        let previous_use = std::mem::replace(last_use, clock);
+        let response = response.clone();
+        lru.uses.remove(&previous_use);
+        lru.uses.insert(clock, key.to_string());
+        Some(response)
+    }
+
+    // Responses bigger than the whole cache aren't kept
+    pub fn insert(&self, key: String, response: CachedResponse) {
+        let size = response.body.len();
+        if size > self.config.response_cache_bytes {
+            return;
+        }
+        let mut lru = self.lru.lock().unwrap();
+        lru.clock += 1;
+        let clock = lru.clock;
+        if let Some((last_use, previous)) = lru.entries.insert(key.clone(), (clock, response)) {
+            lru.uses.remove(&last_use);
+            lru.bytes -= previous.body.len();
+        }
+        lru.uses.insert(clock, key);
+        lru.bytes += size;
+
+        while lru.bytes > self.config.response_cache_bytes {
+            let oldest = match lru.uses.keys().next() {
+                Some(&oldest) => oldest,
+                None => break
+            };
+            if let Some(key) = lru.uses.remove(&oldest) {
+                if let Some((_, evicted)) = lru.entries.remove(&key) {
+                    lru.bytes -= evicted.body.len();
+                }
+            }
+        }
+    }
+
+    fn validated(&self, etag: &str, last_modified: SystemTime, private: bool) -> warp::http::response::Builder {
+        let scope = match private {
+            true  => "private",
+            false => "public"
+        };
+        Response::builder()
+        .header("ETag", etag)
+        .header("Last-Modified", httpdate::fmt_http_date(last_modified))
+        .header("Cache-Control", format!("{}, max-age={}", scope, self.config.max_age))
+    }
+
+    // 304 for a client already holding the public response tagged etag, answered before the response is looked up or rendered.
+    // Only an exact tag counts, the tag is only handed out along with a response that exists
+    pub fn revalidate(&self, etag: &str, last_modified: SystemTime, conditions: &Conditions) -> Option<Response<Bytes>> {
+        let if_none_match = conditions.if_none_match.as_deref()?;
+        match if_none_match.split(',').any(|tag| tag.trim().trim_start_matches("W/") == etag) {
+            true  => self.validated(etag, last_modified, false).status(StatusCode::NOT_MODIFIED).body(Bytes::new()).ok(),
+            false => None
+        }
+    }
+
+    // 304 without a body when the client's copy is still good
+    pub fn reply(&self, response: &CachedResponse, conditions: &Conditions) -> Response<Bytes> {
+        let not_modified = response.not_modified(conditions);
+        let mut builder = self.validated(response.etag.as_str(), response.last_modified, response.private);
+        if not_modified {
+            builder = builder.status(StatusCode::NOT_MODIFIED);
+        } else {
+            builder = builder.header("Content-Type", response.content_type);
+            for (name, value) in response.headers.iter() {
+                builder = builder.header(*name, value.as_str());
+            }
+        }
+        let body = match not_modified {
+            true  => Bytes::new(),
+            false => response.body.clone()
+        };
+        builder.body(body).unwrap_or_else(|_| Response::new(Bytes::new()))
+    }
+}
@@ -57,6 +57,35 @@ impl PixelEncoding {
     }
 }
 
+impl PixelEncoding {
+    pub fn pixel_size(&self) -> usize {
+        (self.bit_depth / 8 * self.channels) as usize
+    }
+}
+
+// Reads the first channel of a pixel's bytes, samples are native endian unless the encoding says they're swapped
+pub fn sample_reader(encoding: PixelEncoding) -> Result<impl Fn(&[u8]) -> f64, String> {
+    let bytes = (encoding.bit_depth / 8) as usize;
+    let read: fn([u8; 4]) -> f64 = match (encoding.bit_depth, encoding.signed) {
+        (8 , false) => |b| b[0] as f64,
+        (8 , true ) => |b| b[0] as i8 as f64,
+        (16, false) => |b| u16::from_ne_bytes([b[0], b[1]]) as f64,
+        (16, true ) => |b| i16::from_ne_bytes([b[0], b[1]]) as f64,
+        (32, false) => |b| u32::from_ne_bytes(b) as f64,
+        (32, true ) => |b| i32::from_ne_bytes(b) as f64,
+        (bit_depth, signed) => return Err(format!("Can't read {} {} bit samples", if signed { "signed" } else { "unsigned" }, bit_depth))
+    };
+    let swap = encoding.swap_endian;
+    Ok(move |px: &[u8]| {
+        let mut b = [0u8; 4];
+        b[..bytes].copy_from_slice(&px[..bytes]);
+        if swap {
+            b[..bytes].reverse();
+        }
+        read(b)
+    })
+}
+
 impl ImageCodec {
     pub fn srtm() -> Self {
         Self {
@@ -69,16 +98,30 @@ impl ImageCodec {
     }
 }
 
-fn decode_image(codec: ImageCodec, dst: &mut[u8], src: &[u8]) {
+fn decode_image(codec: ImageCodec, dst: &mut[u8], src: &[u8]) -> Result<(), String> {
     match codec.filetype {
         ImageFiletype::Raw => {
             if codec.format.raw_size() == src.len() {
                 dst.copy_from_slice(src);
+                Ok(())
             } else {
-                panic!("{}", "Image incorrectly encoded".to_string());
+                Err(format!("Image incorrectly encoded, expected {} bytes but got {}", codec.format.raw_size(), src.len()))
             }
         },
-        _ => panic!("not implemented")
+        ImageFiletype::PNG | ImageFiletype::TIFF => {
+            let decoded = image_ext::load_from_memory(src).map_err(|e| e.to_string())?;
+            if decoded.width() as i32 != codec.format.size.x || decoded.height() as i32 != codec.format.size.y {
+                return Err(format!("Image is {}x{}, expected {:?}", decoded.width(), decoded.height(), codec.format.size));
+            }
+            let bytes: Vec<u8> = match (codec.format.encoding.bit_depth, codec.format.encoding.channels) {
+                (8 , 1) => decoded.into_luma8().into_raw(),
+                (8 , 3) => decoded.into_rgb8().into_raw(),
+                (16, 1) => decoded.into_luma16().into_raw().iter().flat_map(|v| v.to_ne_bytes()).collect(),
+                (bit_depth, channels) => return Err(format!("Can't decode {} bit images with {} channels", bit_depth, channels))
+            };
+            dst.copy_from_slice(&bytes[..]);
+            Ok(())
+        }
     }
 }
 
@@ -117,13 +160,13 @@ pub trait Image {
                         image_ext::ImageBuffer::<image_ext::Luma::<u16>, Vec::<u16>>::from_raw(w, h, self.backing_as::<u16>().to_vec())
                         .ok_or("Couldn't create ImageBuffer::<image_ext::Luma::<u16>, Vec::<u16>>")?
                     ),
-                    _ => panic!()
+                    (bit_depth, channels) => return Err(format!("Can't encode {} bit images with {} channels as PNG", bit_depth, channels))
                 }
                 .write_to(&mut res, image_ext::ImageOutputFormat::Png)
                 .map_err(|e| e.to_string())?;
                 Ok(res.into_inner())
             },
-            ImageFiletype::TIFF => panic!()
+            ImageFiletype::TIFF => crate::geotiff::encode(fmt, self.backing(), None)
         }
     }
     fn get_pixel_mem_shared<T: num::Integer>(&self, mem_index: usize) -> &T {
@@ -166,7 +209,7 @@ impl<'a> Image for ImageBacked<'a> {
 
 impl<'a> ImageBacked<'a> {
     pub fn decode_into(decode_info: ImageCodec, data: &[u8], backing: &'a mut[u8]) -> Result<Self, String> {
-        decode_image(decode_info, backing, data);
+        decode_image(decode_info, backing, data)?;
         Ok(Self {
             format: decode_info.format,
             data: backing
@@ -188,7 +231,7 @@ pub struct ImageOwned {
 impl ImageOwned {
     pub fn decode_new(decode_info: ImageCodec, data: &[u8]) -> Result<Self, String> {
         let mut vec: Vec<u8> = vec![0; decode_info.format.raw_size()];
-        decode_image(decode_info, &mut vec[..], data);
+        decode_image(decode_info, &mut vec[..], data)?;
         Ok(ImageOwned {
             format: decode_info.format,
             data: vec
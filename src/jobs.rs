@@ -0,0 +1,420 @@
+use serde::{Serialize, Deserialize};
+use glam::*;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::config::{DatasetProvider, MAX_LEVEL};
+use crate::dataset::Tilespace;
+use crate::dataset_writer::DatasetWriter;
+use crate::image::{ImageCodec, ImageFiletype};
+use crate::retiling::{gen_jobs, process_all_jobs_observed, RetilingProgress, RetilingSummary};
+use crate::tile_server::Datasets;
+use crate::tile_sink::TileSinkConfig;
+use crate::tile_source::TileSourceConfig;
+use crate::util::math::Dabb2;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JobsConfig {
+    // job history, rewritten whenever a job changes state
+    pub history_path: String,
+    // paths of job outputs are relative to this
+    pub output_dir: String
+}
+
+// What a retiling job reads
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum JobInput {
+    // a dataset of the server, read through a provider of its own so tile requests aren't held up
+    Dataset(String),
+    // remote sources only, secrets have to be given inline
+    Source {
+        source: Box<TileSourceConfig>,
+        // defaults to the codec the source describes itself with
+        codec: Option<ImageCodec>
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RetilingSpec {
+    pub input: JobInput,
+    pub output: TileSinkConfig,
+    pub codec: ImageCodec,
+    pub filetype: ImageFiletype,
+    // defaults to tiles of the codec's size from the origin
+    #[serde(default)]
+    pub tilespace: Option<Tilespace>,
+    // level 0 pixels
+    pub region: Dabb2,
+    // levels from end_level up to and including begin_level
+    pub begin_level: i32,
+    pub end_level: i32
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+    Cancelled
+}
+
+impl JobState {
+    // as serialized
+    pub fn name(&self) -> &'static str {
+        match self {
+            JobState::Queued    => "queued",
+            JobState::Running   => "running",
+            JobState::Completed => "completed",
+            JobState::Failed    => "failed",
+            JobState::Cancelled => "cancelled"
+        }
+    }
+    pub fn is_finished(&self) -> bool {
+        matches!(self, JobState::Completed | JobState::Failed | JobState::Cancelled)
+    }
+}
+
+// Running jobs publish their progress at most this often, state changes go out right away
+const EVENT_INTERVAL: Duration = Duration::from_millis(250);
+
+// Output tiles of a job over all its levels, the list of them is built before the job starts
+const MAX_JOB_TILES: i64 = 1_000_000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JobRecord {
+    pub id: u64,
+    pub spec: RetilingSpec,
+    pub state: JobState,
+    // jobs_total is known once the job has started
+    pub progress: RetilingProgress,
+    // also kept for cancelled runs, covering what was done
+    pub summary: Option<RetilingSummary>,
+    pub error: Option<String>,
+    // unix seconds
+    pub created: u64,
+    pub started: Option<u64>,
+    pub finished: Option<u64>
+}
+
+// What subscribers to a job's events get on every change
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JobEvent {
+    pub id: u64,
+    pub state: JobState,
+    pub progress: RetilingProgress,
+    pub error: Option<String>
+}
+
+impl JobEvent {
+    pub fn of(record: &JobRecord) -> Self {
+        JobEvent {
+            id: record.id,
+            state: record.state,
+            progress: record.progress.clone(),
+            error: record.error.clone()
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// Runs submitted retiling specs one at a time in the background
+#[derive(Debug)]
+pub struct JobQueue {
+    config: JobsConfig,
+    datasets: Datasets,
+    records: Mutex<Vec<JobRecord>>,
+    cancel_flags: Mutex<HashMap<u64, Arc<AtomicBool>>>,
+    queue: mpsc::UnboundedSender<u64>,
+    events: broadcast::Sender<JobEvent>
+}
+
+impl JobQueue {
+    // Loads the history and starts the worker. Jobs still queued when the server stopped are queued again,
+    // ones that were running are failed since their output is incomplete
+    pub fn start(config: JobsConfig, datasets: Datasets) -> Result<Arc<Self>, String> {
+        let mut records: Vec<JobRecord> = match fs::read_to_string(config.history_path.as_str()) {
+            Ok(text) => serde_json::from_str(text.as_str()).map_err(|e| format!("{}: {}", config.history_path, e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(format!("{}: {}", config.history_path, e))
+        };
+        fs::create_dir_all(config.output_dir.as_str()).map_err(|e| format!("{}: {}", config.output_dir, e))?;
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        for record in records.iter_mut() {
+            match record.state {
+                JobState::Queued => { let _ = sender.send(record.id); },
+                JobState::Running => {
+                    record.state = JobState::Failed;
+                    record.error = Some("Interrupted by a server restart".to_string());
+                    record.finished = Some(now());
+                },
+                _ => ()
+            }
+        }
+
+        let queue = Arc::new(JobQueue {
+            config,
+            datasets,
+            records: Mutex::new(records),
+            cancel_flags: Mutex::new(HashMap::new()),
+            queue: sender,
+            events: broadcast::channel(256).0
+        });
+        queue.save();
+        tokio::spawn(queue.clone().work(receiver));
+        Ok(queue)
+    }
+
+    pub fn submit(&self, spec: RetilingSpec) -> Result<JobRecord, String> {
+        self.validate(&spec)?;
+        let record = {
+            let mut records = self.records.lock().unwrap();
+            let record = JobRecord {
+                id: records.iter().map(|r| r.id + 1).max().unwrap_or(1),
+                spec,
+                state: JobState::Queued,
+                progress: RetilingProgress::default(),
+                summary: None,
+                error: None,
+                created: now(),
+                started: None,
+                finished: None
+            };
+            records.push(record.clone());
+            record
+        };
+        self.save();
+        self.queue.send(record.id).map_err(|_| "Job worker has stopped".to_string())?;
+        Ok(record)
+    }
+
+    pub fn list(&self) -> Vec<JobRecord> {
+        self.records.lock().unwrap().clone()
+    }
+
+    pub fn get(&self, id: u64) -> Option<JobRecord> {
+        self.records.lock().unwrap().iter().find(|r| r.id == id).cloned()
+    }
+
+    // Events of every job from now on. Slow subscribers miss events rather than hold up jobs, the next one has the totals anyway
+    pub fn subscribe(&self) -> broadcast::Receiver<JobEvent> {
+        self.events.subscribe()
+    }
+
+    // Queued jobs are cancelled right away, running ones after the output tile they're on.
+    // None for unknown jobs, Err for ones that already finished
+    pub fn cancel(&self, id: u64) -> Result<Option<JobRecord>, String> {
+        let record = {
+            let mut records = self.records.lock().unwrap();
+            let record = match records.iter_mut().find(|r| r.id == id) {
+                Some(record) => record,
+                None => return Ok(None)
+            };
+            match record.state {
+                JobState::Queued => {
+                    record.state = JobState::Cancelled;
+                    record.finished = Some(now());
+                },
+                JobState::Running => {
+                    if let Some(flag) = self.cancel_flags.lock().unwrap().get(&id) {
+                        flag.store(true, Ordering::Relaxed);
+                    }
+                },
+                _ => return Err(format!("Job {} has already finished", id))
+            }
+            record.clone()
+        };
+        let _ = self.events.send(JobEvent::of(&record));
+        self.save();
+        Ok(Some(record))
+    }
+
+    fn validate(&self, spec: &RetilingSpec) -> Result<(), String> {
+        if spec.end_level < 0 || spec.begin_level < spec.end_level || spec.begin_level > MAX_LEVEL {
+            return Err(format!("Levels {} to {} aren't a valid range, begin_level has to be at least end_level and at most {}", spec.begin_level, spec.end_level, MAX_LEVEL));
+        }
+        let size = spec.region.end - spec.region.begin;
+        if size.x <= 0 || size.y <= 0 {
+            return Err("Region is empty".to_string());
+        }
+
+        // every level has the output tiles covering the region, with pixels of the coarsest level reaching furthest
+        let tilespace = self.output_tilespace(spec);
+        if tilespace.size.x <= 0 || tilespace.size.y <= 0 {
+            return Err(format!("Output tiles of {:?} pixels are empty", tilespace.size));
+        }
+        let tile_size = tilespace.size.as_i64vec2();
+        let first = spec.region.begin.as_i64vec2().div_euclid(tile_size);
+        let last = (spec.region.end.as_i64vec2() - 1).div_euclid(tile_size);
+        let tiles = (last - first + 1).x * (last - first + 1).y * (spec.begin_level - spec.end_level + 1) as i64;
+        if tiles > MAX_JOB_TILES {
+            return Err(format!("Region and levels cover {} output tiles, more than the {} a job may write", tiles, MAX_JOB_TILES));
+        }
+        let scale = 1i64 << spec.begin_level;
+        let reach = [first, last + 1].iter().map(|t| (*t * tile_size * scale).abs().max_element()).max().unwrap_or(0);
+        if reach + tilespace.offset.as_i64vec2().abs().max_element() > i32::MAX as i64 {
+            return Err(format!("Region {:?} at level {} is outside the pixels a tilespace can address", spec.region, spec.begin_level));
+        }
+        match &spec.input {
+            JobInput::Dataset(name) => {
+                if !self.datasets.contains_key(name) {
+                    return Err(format!("No dataset named {}", name));
+                }
+            },
+            // Requests may only point at remote datasets, local ones have to be in the server config
+            JobInput::Source { source, .. } => {
+                if !source.is_remote() {
+                    return Err("Sources have to be remote, local data has to be a dataset of the server".to_string());
+                }
+                if !source.http().map(|http| http.only_literal_secrets()).unwrap_or(true) {
+                    return Err("Secrets in requests have to be given as literals".to_string());
+                }
+            }
+        }
+        self.resolve_output(&spec.output)?;
+        Ok(())
+    }
+
+    fn output_tilespace(&self, spec: &RetilingSpec) -> Tilespace {
+        spec.tilespace.clone().unwrap_or(Tilespace {
+            size: spec.codec.format.size,
+            offset: IVec2::ZERO,
+            georef: None
+        })
+    }
+
+    // The sink with its path under the output directory. Paths have to be relative and stay inside it
+    fn resolve_output(&self, output: &TileSinkConfig) -> Result<TileSinkConfig, String> {
+        let confine = |path: &str| -> Result<String, String> {
+            let relative = Path::new(path);
+            if path.is_empty() || path.contains("://") || !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+                return Err(format!("Output path {} has to be relative to the output directory and can't contain ..", path));
+            }
+            Ok(Path::new(self.config.output_dir.as_str()).join(relative).to_string_lossy().into_owned())
+        };
+        Ok(match output {
+            TileSinkConfig::Files { tile_uri_format, max_zoom } => TileSinkConfig::Files {
+                tile_uri_format: confine(tile_uri_format)?,
+                max_zoom: *max_zoom
+            },
+            TileSinkConfig::MBTiles { path, max_zoom } => TileSinkConfig::MBTiles {
+                path: confine(path)?,
+                max_zoom: *max_zoom
+            },
+            TileSinkConfig::PMTiles { path, max_zoom } => TileSinkConfig::PMTiles {
+                path: confine(path)?,
+                max_zoom: *max_zoom
+            }
+        })
+    }
+
+    fn update(&self, id: u64, f: impl FnOnce(&mut JobRecord)) {
+        if let Some(record) = self.records.lock().unwrap().iter_mut().find(|r| r.id == id) {
+            f(record);
+            // no receivers isn't an error
+            let _ = self.events.send(JobEvent::of(record));
+        }
+    }
+
+    // Written to a temporary file first so a crash can't leave half a history
+    fn save(&self) {
+        let text = match serde_json::to_string_pretty(&*self.records.lock().unwrap()) {
+            Ok(text) => text,
+            Err(e) => { println!("Couldn't serialize job history: {}", e); return; }
+        };
+        let temp_path = format!("{}.tmp", self.config.history_path);
+        let res
+            =fs::write(temp_path.as_str(), text)
+            .and_then(|_| fs::rename(temp_path.as_str(), self.config.history_path.as_str()));
+        if let Err(e) = res {
+            println!("Couldn't save job history to {}: {}", self.config.history_path, e);
+        }
+    }
+
+    async fn work(self: Arc<Self>, mut receiver: mpsc::UnboundedReceiver<u64>) {
+        while let Some(id) = receiver.recv().await {
+            // jobs cancelled while queued are skipped, the state is checked and changed under one lock so a cancel can't slip in between
+            let cancel = Arc::new(AtomicBool::new(false));
+            let spec = {
+                let mut records = self.records.lock().unwrap();
+                match records.iter_mut().find(|r| r.id == id) {
+                    Some(record) if record.state == JobState::Queued => {
+                        record.state = JobState::Running;
+                        record.started = Some(now());
+                        self.cancel_flags.lock().unwrap().insert(id, cancel.clone());
+                        let _ = self.events.send(JobEvent::of(record));
+                        record.spec.clone()
+                    },
+                    _ => continue
+                }
+            };
+            self.save();
+            println!("Job {}: started", id);
+
+            // in a task of its own so a job that panics fails by itself instead of taking the worker down
+            let queue = self.clone();
+            let res = match tokio::spawn(async move { queue.execute(id, &spec, cancel).await }).await {
+                Ok(res) => res,
+                Err(e) => Err(format!("Job stopped unexpectedly: {}", e))
+            };
+
+            self.cancel_flags.lock().unwrap().remove(&id);
+            self.update(id, |r| {
+                r.finished = Some(now());
+                match res {
+                    Ok(summary) => {
+                        r.state = match summary.cancelled {
+                            true  => JobState::Cancelled,
+                            false => JobState::Completed
+                        };
+                        r.summary = Some(summary);
+                    },
+                    Err(e) => {
+                        r.state = JobState::Failed;
+                        r.error = Some(e);
+                    }
+                }
+                println!("Job {}: {:?}", id, r.state);
+            });
+            self.save();
+        }
+    }
+
+    async fn execute(self: &Arc<Self>, id: u64, spec: &RetilingSpec, cancel: Arc<AtomicBool>) -> Result<RetilingSummary, String> {
+        let mut dp = match &spec.input {
+            JobInput::Dataset(name) => {
+                let dataset = self.datasets.get(name).ok_or_else(|| format!("No dataset named {}", name))?;
+                dataset.config.open_provider().await?
+            },
+            JobInput::Source { source, codec: Some(codec) } => DatasetProvider::open(source, *codec).await?,
+            JobInput::Source { source, codec: None } => DatasetProvider::open_described(source).await?
+        };
+        let dw = DatasetWriter::open(&self.resolve_output(&spec.output)?, spec.codec, self.output_tilespace(spec), spec.filetype)?;
+
+        let jobs = gen_jobs(&dp, &dw, spec.region, spec.begin_level, spec.end_level);
+
+        let queue = self.clone();
+        let mut last_event: Option<Instant> = None;
+        let summary = process_all_jobs_observed(&mut dp, &dw, &jobs, &mut |progress| {
+            let cancelled = cancel.load(Ordering::Relaxed);
+            let due = last_event.map(|t| t.elapsed() >= EVENT_INTERVAL).unwrap_or(true);
+            if due || cancelled || progress.is_done() {
+                queue.update(id, |r| r.progress = progress.clone());
+                last_event = Some(Instant::now());
+            }
+            !cancelled
+        }).await;
+        Ok(summary)
+    }
+}
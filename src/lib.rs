@@ -10,4 +10,21 @@ pub mod serde_json_warp;
 pub mod network_util;
 pub mod dataset;
 pub mod dataset_writer;
-pub mod sample_accumulator;
\ No newline at end of file
+pub mod sample_accumulator;
+pub mod cache_simulator;
+pub mod tile_source;
+pub mod tile_sink;
+pub mod mbtiles;
+pub mod pmtiles;
+pub mod range_reader;
+pub mod cog;
+pub mod manifest;
+pub mod checksum;
+pub mod tile_server;
+pub mod geotiff;
+pub mod sampling;
+pub mod capabilities;
+pub mod jobs;
+pub mod http_cache;
+pub mod mvt;
+pub mod contours;
\ No newline at end of file
@@ -1,4 +1,5 @@
-use crate::{image::{ImageCodec, ImageFormat, PixelEncoding}, retiling::process_all_jobs, util::math};
+use crate::{image::{ImageCodec, ImageFormat, PixelEncoding}, retiling::{process_all_jobs_observed, RetilingProgress}, util::math};
+use std::io::Write;
 use glam::*;
 
 pub mod config;
@@ -14,10 +15,98 @@ pub mod network_util;
 pub mod dataset;
 pub mod dataset_writer;
 pub mod sample_accumulator;
+pub mod cache_simulator;
+pub mod tile_source;
+pub mod tile_sink;
+pub mod mbtiles;
+pub mod pmtiles;
+pub mod range_reader;
+pub mod cog;
+pub mod manifest;
+pub mod checksum;
+pub mod tile_server;
+pub mod geotiff;
+pub mod sampling;
+pub mod capabilities;
+pub mod jobs;
+pub mod http_cache;
+pub mod mvt;
+pub mod contours;
+
+// Redrawn in place on stderr so it stays out of anything piped from stdout
+fn draw_progress(progress: &RetilingProgress) -> bool {
+    eprint!("\r{}", progress.bar(30));
+    let _ = std::io::stderr().flush();
+    true
+}
+
+// Vector tiles of a dataset's contours for every tile it has, z as in the server's tile URLs
+async fn write_contours(config_path: &str, name: &str, tile_uri_format: &str) -> Result<(), String> {
+    let config = tile_server::ServerConfig::load(config_path)?;
+    let dataset_config = config.datasets.get(name).ok_or_else(|| format!("No dataset named {}", name))?;
+    let dataset = tile_server::Dataset::open(name, dataset_config).await?;
+    let sink = tile_sink::FileTileSink::new(dataset::TileTemplate::new(tile_uri_format)?);
+
+    let mut dp = dataset.provider.lock().await;
+    let summary = contours::write_contour_tiles(&mut dp, &sink, &dataset.config.contours, &|level| dataset.url_zoom(level), &mut draw_progress).await;
+    eprintln!();
+    println!("{} contour tiles written, {} failed", summary.tiles_written, summary.failures.len());
+    for (coord, e) in summary.failures.iter() {
+        println!("{:?}: {}", coord, e);
+    }
+    Ok(())
+}
+
+// Misses and memory of every eviction policy at each capacity, replaying a job list saved with retiling::save_jobs
+fn simulate_cache(jobs_path: &str, tile_bytes: &str, capacities: &[String]) -> Result<(), String> {
+    let jobs = retiling::load_jobs(jobs_path).map_err(|e| format!("{}: {}", jobs_path, e))?;
+    let tile_bytes = tile_bytes.parse::<u64>().map_err(|_| format!("Tile size {} is not a number of bytes", tile_bytes))?;
+    let capacities
+        =capacities.iter()
+        .map(|c| c.parse::<usize>().map_err(|_| format!("Capacity {} is not a number of tiles", c)))
+        .collect::<Result<Vec<usize>, String>>()?;
+
+    let policies = [
+        cache_simulator::EvictionPolicy::Fifo,
+        cache_simulator::EvictionPolicy::Lru,
+        cache_simulator::EvictionPolicy::Belady,
+        cache_simulator::EvictionPolicy::CostAware
+    ];
+    let configurations = cache_simulator::CacheConfiguration::sweep(&policies[..], &capacities[..]);
+    let costs = cache_simulator::TileCosts::uniform(tile_bytes);
+    println!("{} jobs, {} tile accesses", jobs.len(), cache_simulator::access_sequence(&jobs[..]).len());
+    for report in cache_simulator::simulate_all(&jobs[..], &costs, &configurations[..]) {
+        println!("{}", report);
+    }
+    Ok(())
+}
 
 #[tokio::main]
 async fn main() {
-    
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() == 3 && args[1] == "serve" {
+        let res = match tile_server::ServerConfig::load(args[2].as_str()) {
+            Ok(config) => http_api::run(config).await,
+            Err(e) => Err(e)
+        };
+        if let Err(e) = res {
+            println!("{}", e);
+        }
+        return;
+    }
+    if args.len() == 5 && args[1] == "contours" {
+        if let Err(e) = write_contours(args[2].as_str(), args[3].as_str(), args[4].as_str()).await {
+            println!("{}", e);
+        }
+        return;
+    }
+    if args.len() >= 5 && args[1] == "simulate-cache" {
+        if let Err(e) = simulate_cache(args[2].as_str(), args[3].as_str(), &args[4..]) {
+            println!("{}", e);
+        }
+        return;
+    }
+
     let mut dp = match config::DatasetProvider::create(
         "https://spkit.org/datasets/srtm/remapped/{x:3}_{y:3}_{z:3}.hgt",
         ImageCodec::srtm(),
@@ -27,20 +116,27 @@ async fn main() {
         Err(_) => { return; }
     };
 
-    let dw = dataset_writer::DatasetWriter {
-        tile_uri_format: "./output/{x:3}_{y:3}_{z:3}.png".to_string(),
-        codec: ImageCodec {
+    let dw = match dataset_writer::DatasetWriter::open(
+        &tile_sink::TileSinkConfig::Files {
+            tile_uri_format: "./output/{x:3}_{y:3}_{z:3}.png".to_string(),
+            max_zoom: None
+        },
+        ImageCodec {
             filetype: image::ImageFiletype::PNG,
             format: ImageFormat {
                 encoding: PixelEncoding::srtm(),
                 size: ivec2(512, 512)
             }
         },
-        tilespace: dataset::Tilespace {
+        dataset::Tilespace {
             size: ivec2(512, 512),
-            offset: ivec2(0, 0)
+            offset: ivec2(0, 0),
+            georef: None
         },
-        filetype: image::ImageFiletype::PNG
+        image::ImageFiletype::PNG
+    ) {
+        Ok(dw) => dw,
+        Err(_) => { return; }
     };
 
     println!("Created Dataset Provider, generating jobs...");
@@ -54,7 +150,15 @@ async fn main() {
         0
     );
 
-    process_all_jobs(&mut dp, &dw, &jobs).await;
+    let summary = process_all_jobs_observed(&mut dp, &dw, &jobs, &mut draw_progress).await;
+    eprintln!();
+    println!(
+        "Processed {} jobs: {} tiles written, {} input tiles missing, {} fetch failures, {} corrupt fetches",
+        summary.jobs, summary.tiles_written, summary.tiles_missing, summary.failures.len(), summary.corrupt_fetches
+    );
+    for output_coord in summary.failed_outputs.iter() {
+        println!("Not written because of failed inputs: {:?}", output_coord);
+    }
 
     //let s = serde_json::to_string(&preview_request).unwrap();
     //println!("{}", s);
@@ -0,0 +1,258 @@
+use serde::{Serialize, Deserialize};
+use glam::*;
+use std::collections::HashMap;
+use std::fs;
+use std::iter::FromIterator;
+use std::path::{Path, PathBuf};
+
+use crate::checksum::Checksum;
+use crate::dataset::TileTemplate;
+use crate::network_util::HttpClient;
+use crate::tile_source::FetchError;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct TileMetadata {
+    // encoded size in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    // of the encoded bytes, sha256:<hex> or crc32:<hex>
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    // sample range of the decoded tile
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>
+}
+
+impl TileMetadata {
+    pub fn sized(size: u64) -> Self {
+        TileMetadata { size: Some(size), ..Default::default() }
+    }
+
+    // Checks fetched bytes against the size and checksum, when the manifest has them
+    pub fn verify(&self, data: &[u8]) -> Result<(), String> {
+        if let Some(size) = self.size {
+            if data.len() as u64 != size {
+                return Err(format!("expected {} bytes but got {}", size, data.len()));
+            }
+        }
+        if let Some(checksum) = &self.checksum {
+            let checksum = Checksum::parse(checksum.as_str())?;
+            let actual = checksum.compute_like(data);
+            if actual != checksum {
+                return Err(format!("checksum mismatch, expected {} but got {}", checksum, actual));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ManifestEntry {
+    pub coord: IVec3,
+    #[serde(flatten)]
+    pub metadata: TileMetadata
+}
+
+// Either the plain list of coordinates or the extended format with metadata per tile
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+enum ManifestFile {
+    Coords(Vec<IVec3>),
+    Extended {
+        tiles: Vec<ManifestEntry>
+    }
+}
+
+// Tiles to try with HEAD requests when a server has no manifest, inclusive on both ends
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct ProbeRange {
+    pub min: IVec3,
+    pub max: IVec3
+}
+
+// A probe is one request per tile, past this a manifest is needed
+pub const MAX_PROBE_TILES: i64 = 100_000;
+// HEAD requests in flight at once, the client's per host limit applies on top
+const PROBE_CONCURRENCY: usize = 64;
+
+impl ProbeRange {
+    pub fn count(&self) -> i64 {
+        let extent = self.max.as_i64vec3() - self.min.as_i64vec3() + 1;
+        match extent.min_element() > 0 {
+            true  => extent.x.saturating_mul(extent.y).saturating_mul(extent.z),
+            false => 0
+        }
+    }
+
+    pub fn coords(&self) -> impl Iterator<Item = IVec3> + '_ {
+        (self.min.z..=self.max.z).flat_map(move |z| {
+            (self.min.y..=self.max.y).flat_map(move |y| {
+                (self.min.x..=self.max.x).map(move |x| ivec3(x, y, z))
+            })
+        })
+    }
+}
+
+// The tiles a source has, indexed by coordinate
+#[derive(Debug, Clone, Default)]
+pub struct Manifest {
+    tiles: HashMap<IVec3, TileMetadata>
+}
+
+impl FromIterator<(IVec3, TileMetadata)> for Manifest {
+    fn from_iter<I: IntoIterator<Item = (IVec3, TileMetadata)>>(iter: I) -> Self {
+        Manifest {
+            tiles: iter.into_iter().collect()
+        }
+    }
+}
+
+impl Manifest {
+    pub fn from_coords<I: IntoIterator<Item = IVec3>>(coords: I) -> Self {
+        Manifest {
+            tiles: coords.into_iter().map(|c| (c, TileMetadata::default())).collect()
+        }
+    }
+
+    pub fn from_json(bytes: &[u8]) -> Result<Self, String> {
+        Ok(match serde_json::from_slice::<ManifestFile>(bytes).map_err(|e| e.to_string())? {
+            ManifestFile::Coords(coords) => Manifest::from_coords(coords),
+            ManifestFile::Extended { tiles } => {
+                // bad checksums would otherwise only show up as failed fetches
+                for entry in tiles.iter() {
+                    if let Some(checksum) = &entry.metadata.checksum {
+                        Checksum::parse(checksum.as_str()).map_err(|e| format!("Tile {:?}: {}", entry.coord, e))?;
+                    }
+                }
+                Manifest {
+                    tiles: tiles.into_iter().map(|e| (e.coord, e.metadata)).collect()
+                }
+            }
+        })
+    }
+
+    // Always the extended format, sorted so the output is stable
+    pub fn to_json(&self) -> Result<String, String> {
+        let mut tiles: Vec<ManifestEntry>
+            =self.tiles.iter()
+            .map(|(&coord, metadata)| ManifestEntry { coord, metadata: metadata.clone() })
+            .collect();
+        tiles.sort_by_key(|e| (e.coord.z, e.coord.y, e.coord.x));
+        serde_json::to_string(&ManifestFile::Extended { tiles }).map_err(|e| e.to_string())
+    }
+
+    pub fn contains(&self, coord: IVec3) -> bool {
+        self.tiles.contains_key(&coord)
+    }
+    pub fn get(&self, coord: IVec3) -> Option<&TileMetadata> {
+        self.tiles.get(&coord)
+    }
+    pub fn insert(&mut self, coord: IVec3, metadata: TileMetadata) {
+        self.tiles.insert(coord, metadata);
+    }
+    pub fn len(&self) -> usize {
+        self.tiles.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.tiles.is_empty()
+    }
+    pub fn coords(&self) -> impl Iterator<Item = IVec3> + '_ {
+        self.tiles.keys().copied()
+    }
+
+    // Every file under the template's directory whose path the template matches
+    pub async fn scan_directory(template: &TileTemplate) -> Result<Self, String> {
+        let template = template.clone();
+        tokio::task::spawn_blocking(move || {
+            let prefix = template.template.literal_prefix();
+            let root = match prefix.rfind('/') {
+                Some(0) => PathBuf::from("/"),
+                Some(i) => PathBuf::from(&prefix[..i]),
+                None => PathBuf::from(".")
+            };
+            // paths are matched the way the template writes them, without a leading ./ when it has none
+            let strip_dot = !prefix.starts_with("./");
+
+            let mut res = Manifest::default();
+            let mut pending = vec![root];
+            while let Some(dir) = pending.pop() {
+                for entry in fs::read_dir(&dir).map_err(|e| format!("{}: {}", dir.display(), e))? {
+                    let entry = entry.map_err(|e| e.to_string())?;
+                    let file_type = entry.file_type().map_err(|e| e.to_string())?;
+                    if file_type.is_dir() {
+                        pending.push(entry.path());
+                        continue;
+                    }
+                    let path = entry.path();
+                    let path = match strip_dot {
+                        true  => path.strip_prefix("./").map(Path::to_path_buf).unwrap_or(path),
+                        false => path
+                    };
+                    if let Some(coord) = template.parse_coord(path.to_string_lossy().as_ref()) {
+                        let size = entry.metadata().map(|m| m.len()).ok();
+                        res.insert(coord, TileMetadata { size, ..Default::default() });
+                    }
+                }
+            }
+            Ok(res)
+        }).await.map_err(|e| e.to_string())?
+    }
+
+    // HEAD request for every tile in the range, PROBE_CONCURRENCY at a time
+    pub async fn probe(client: &HttpClient, template: &TileTemplate, range: ProbeRange) -> Result<Self, String> {
+        if range.count() > MAX_PROBE_TILES {
+            return Err(format!("Probe range of {} tiles is more than {}, list the tiles in a manifest instead", range.count(), MAX_PROBE_TILES));
+        }
+
+        let mut requests = tokio::task::JoinSet::new();
+        let mut coords = range.coords();
+        let mut res = Manifest::default();
+        loop {
+            while requests.len() < PROBE_CONCURRENCY {
+                let coord = match coords.next() {
+                    Some(coord) => coord,
+                    None => break
+                };
+                let uri = template.format(coord)?;
+                let client = client.clone();
+                requests.spawn(async move {
+                    (coord, client.head(uri.as_str()).await.map_err(|e| (uri, e)))
+                });
+            }
+
+            let result = match requests.join_next().await {
+                Some(result) => result,
+                None => break
+            };
+            match result.map_err(|e| e.to_string())? {
+                (coord, Ok(size)) => res.insert(coord, TileMetadata { size, ..Default::default() }),
+                (_, Err((_, FetchError::Missing))) => {},
+                (_, Err((uri, e))) => return Err(format!("Probing {}: {}", uri, e))
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_probe_ranges() {
+        assert_eq!(ProbeRange { min: ivec3(0, 0, 0), max: ivec3(1, 2, 0) }.count(), 6);
+        assert_eq!(ProbeRange { min: ivec3(1, 0, 0), max: ivec3(0, 2, 0) }.count(), 0);
+        assert_eq!(ProbeRange { min: IVec3::MIN, max: IVec3::MAX }.count(), i64::MAX);
+    }
+
+    #[tokio::test]
+    async fn refuses_probes_past_the_limit() {
+        let template = TileTemplate::new("http://127.0.0.1:1/{z}/{x}/{y}.png").unwrap();
+        let range = ProbeRange { min: ivec3(0, 0, 0), max: ivec3(1000, 1000, 0) };
+        let res = Manifest::probe(&HttpClient::shared(), &template, range).await;
+        assert!(res.unwrap_err().contains("manifest"));
+    }
+}
@@ -0,0 +1,273 @@
+use glam::*;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::sync::{Arc, Mutex};
+use std::path::Path;
+
+use crate::dataset::*;
+use crate::image::{ImageCodec, ImageFiletype};
+use crate::tile_sink::*;
+use crate::tile_source::*;
+use crate::manifest::*;
+
+// Commit every this many tiles instead of once per insert
+const TILES_PER_TRANSACTION: usize = 1000;
+
+// Past this the rows of a zoom don't fit an i32
+const MAX_ZOOM: i32 = 30;
+
+// TMS row of a tile, None outside of the tile pyramid
+fn tms_row(zoom: i32, x: i32, y: i32) -> Option<i32> {
+    match (0..=MAX_ZOOM).contains(&zoom) && (0..1 << zoom).contains(&x) && (0..1 << zoom).contains(&y) {
+        true  => Some(tms_flip_y(y, zoom)),
+        false => None
+    }
+}
+
+// The spec names pbf, jpg, png and webp and leaves other formats to their media types
+fn format_name(filetype: ImageFiletype) -> &'static str {
+    match filetype {
+        ImageFiletype::Raw  => "application/octet-stream",
+        ImageFiletype::PNG  => "png",
+        ImageFiletype::TIFF => "image/tiff"
+    }
+}
+
+#[derive(Debug)]
+struct MBTilesWriteState {
+    connection: Connection,
+    pending: usize,
+    extent: WrittenExtent
+}
+
+#[derive(Debug)]
+pub struct MBTilesSink {
+    pub path: String,
+    // written to the metadata table's json entry
+    pub info: DatasetInfo,
+    pub filetype: ImageFiletype,
+    state: Mutex<MBTilesWriteState>
+}
+
+fn sql_err(e: rusqlite::Error) -> String {
+    e.to_string()
+}
+
+impl MBTilesSink {
+    // Replaces the tiles and metadata of an existing file, like the other sinks overwrite theirs
+    pub fn create(path: &str, max_zoom: i32, codec: ImageCodec, tilespace: Tilespace, filetype: ImageFiletype) -> Result<Self, String> {
+        let connection = Connection::open(path).map_err(sql_err)?;
+        connection.execute_batch("
+            CREATE TABLE IF NOT EXISTS metadata (name TEXT, value TEXT, UNIQUE (name));
+            CREATE TABLE IF NOT EXISTS tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_data BLOB, UNIQUE (zoom_level, tile_column, tile_row));
+            BEGIN;
+            DELETE FROM metadata;
+            DELETE FROM tiles;
+        ").map_err(sql_err)?;
+
+        Ok(MBTilesSink {
+            path: path.to_string(),
+            info: DatasetInfo { codec, tilespace, max_zoom, nodata: None, extent: None },
+            filetype,
+            state: Mutex::new(MBTilesWriteState {
+                connection,
+                pending: 0,
+                extent: WrittenExtent::default()
+            })
+        })
+    }
+
+    fn metadata(&self, state: &MBTilesWriteState) -> Result<Vec<(&'static str, String)>, String> {
+        let name
+            =Path::new(self.path.as_str())
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_else(|| self.path.clone());
+
+        let mut res = vec![
+            ("name", name),
+            ("format", format_name(self.filetype).to_string()),
+            ("type", "baselayer".to_string()),
+            ("json", serde_json::to_string(&self.info).map_err(|e| e.to_string())?)
+        ];
+        if !state.extent.is_empty() {
+            res.push(("minzoom", state.extent.min_zoom.to_string()));
+            res.push(("maxzoom", state.extent.max_zoom.to_string()));
+        }
+        if let (Some(georef), Some(pixels)) = (self.info.tilespace.georef, state.extent.pixel_bounds) {
+            let [w, s, e, n] = georef.bounds(pixels);
+            res.push(("bounds", format!("{},{},{},{}", w, s, e, n)));
+        }
+        Ok(res)
+    }
+}
+
+impl TileSink for MBTilesSink {
+    fn write_tile(&self, coord: IVec3, data: &[u8]) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+
+        let zoom = level_to_zoom(coord.z, self.info.max_zoom);
+        let row
+            =tms_row(zoom, coord.x, coord.y)
+            .ok_or_else(|| format!("Tile {:?} is outside of the MBTiles tile pyramid at zoom {}", coord, zoom))?;
+        state.connection.execute(
+            "INSERT OR REPLACE INTO tiles (zoom_level, tile_column, tile_row, tile_data) VALUES (?1, ?2, ?3, ?4)",
+            params![zoom, coord.x, row, data]
+        ).map_err(sql_err)?;
+
+        state.extent.add(coord, zoom, &self.info.tilespace);
+
+        state.pending += 1;
+        if state.pending >= TILES_PER_TRANSACTION {
+            state.connection.execute_batch("COMMIT; BEGIN;").map_err(sql_err)?;
+            state.pending = 0;
+        }
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        for (name, value) in self.metadata(&state)? {
+            state.connection.execute(
+                "INSERT OR REPLACE INTO metadata (name, value) VALUES (?1, ?2)",
+                params![name, value]
+            ).map_err(sql_err)?;
+        }
+        state.connection.execute_batch("COMMIT; BEGIN;").map_err(sql_err)?;
+        state.pending = 0;
+        Ok(())
+    }
+}
+
+// Tiles written since the last commit are kept when a job stops before finish
+impl Drop for MBTilesSink {
+    fn drop(&mut self) {
+        if let Ok(state) = self.state.get_mut() {
+            if let Err(e) = state.connection.execute_batch("COMMIT;") {
+                println!("{}: not committing written tiles: {}", self.path, e);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct MBTilesTileSource {
+    pub path: String,
+    // level 0 of the dataset, read from the metadata written by MBTilesSink or the maxzoom entry
+    pub max_zoom: i32,
+    pub info: Option<DatasetInfo>,
+    connection: Arc<Mutex<Connection>>
+}
+
+impl MBTilesTileSource {
+    pub fn open(path: &str, max_zoom: Option<i32>) -> Result<Self, String> {
+        let connection = Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(sql_err)?;
+
+        let read_metadata = |name: &str| -> Result<Option<String>, String> {
+            connection.query_row("SELECT value FROM metadata WHERE name = ?1", params![name], |row| row.get(0))
+            .optional().map_err(sql_err)
+        };
+
+        let info: Option<DatasetInfo>
+            =read_metadata("json")?
+            .and_then(|json| serde_json::from_str(json.as_str()).ok());
+
+        let max_zoom = match (max_zoom, &info, read_metadata("maxzoom")?) {
+            (Some(max_zoom), _, _) => max_zoom,
+            (None, Some(info), _) => info.max_zoom,
+            (None, None, Some(max_zoom)) => max_zoom.parse().map_err(|_| format!("Invalid maxzoom {}", max_zoom))?,
+            (None, None, None) => return Err(format!("{} has no maxzoom, it has to be configured", path))
+        };
+
+        Ok(MBTilesTileSource {
+            path: path.to_string(),
+            max_zoom,
+            info,
+            connection: Arc::new(Mutex::new(connection))
+        })
+    }
+}
+
+impl TileSource for MBTilesTileSource {
+    fn fetch_tile(&self, coord: IVec3) -> FetchFuture<'_, Vec<u8>> {
+        let connection = self.connection.clone();
+        let zoom = level_to_zoom(coord.z, self.max_zoom);
+        Box::pin(async move {
+            let row = tms_row(zoom, coord.x, coord.y).ok_or(FetchError::Missing)?;
+            tokio::task::spawn_blocking(move || {
+                connection.lock().unwrap().query_row(
+                    "SELECT tile_data FROM tiles WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3",
+                    params![zoom, coord.x, row],
+                    |row| row.get::<_, Vec<u8>>(0)
+                ).map_err(|e| match e {
+                    rusqlite::Error::QueryReturnedNoRows => FetchError::Missing,
+                    e => FetchError::Failed(format!("Tile {:?}: {}", coord, e))
+                })
+            }).await.map_err(|e| FetchError::Failed(e.to_string()))?
+        })
+    }
+    fn dataset_info(&self) -> Option<DatasetInfo> {
+        self.info.clone()
+    }
+    fn list_tiles(&self) -> SourceFuture<'_, Manifest> {
+        let connection = self.connection.clone();
+        let max_zoom = self.max_zoom;
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || {
+                let connection = connection.lock().unwrap();
+                let mut statement
+                    =connection.prepare("SELECT zoom_level, tile_column, tile_row, length(tile_data) FROM tiles")
+                    .map_err(sql_err)?;
+                let rows = statement.query_map([], |row| {
+                    let zoom: i32 = row.get(0)?;
+                    let (x, y): (i32, i32) = (row.get(1)?, row.get(2)?);
+                    let coord = tms_row(zoom, x, y).map(|y| ivec3(x, y, zoom_to_level(zoom, max_zoom)));
+                    Ok((coord, zoom, TileMetadata::sized(row.get::<_, i64>(3)? as u64)))
+                }).map_err(sql_err)?;
+                let mut manifest = Manifest::default();
+                for row in rows {
+                    match row.map_err(sql_err)? {
+                        (Some(coord), _, metadata) => manifest.insert(coord, metadata),
+                        (None, zoom, _) => return Err(format!("Tile outside of the tile pyramid at zoom {}", zoom))
+                    };
+                }
+                Ok(manifest)
+            }).await.map_err(|e| e.to_string())?
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn keeps_tiles_written_before_a_stop() {
+        let path = std::env::temp_dir().join(format!("tiler-{}-stopped.mbtiles", std::process::id())).to_string_lossy().into_owned();
+        let codec = ImageCodec::srtm();
+        let tilespace = Tilespace { size: codec.format.size, offset: IVec2::ZERO, georef: None };
+        {
+            let sink = MBTilesSink::create(path.as_str(), 1, codec, tilespace.clone(), ImageFiletype::Raw).unwrap();
+            sink.write_tile(ivec3(1, 0, 0), b"tile").unwrap();
+            // level 2 is past zoom 0, and zoom 0 only has one tile
+            assert!(sink.write_tile(ivec3(0, 0, 2), b"tile").is_err());
+            assert!(sink.write_tile(ivec3(1, 0, 1), b"tile").is_err());
+        }
+
+        let source = MBTilesTileSource::open(path.as_str(), Some(1)).unwrap();
+        assert_eq!(source.fetch_tile(ivec3(1, 0, 0)).await.unwrap(), b"tile".to_vec());
+        assert_eq!(source.fetch_tile(ivec3(0, 0, 2)).await, Err(FetchError::Missing));
+        assert_eq!(source.list_tiles().await.unwrap().len(), 1);
+        drop(source);
+
+        // creating it again starts from an empty file
+        {
+            let sink = MBTilesSink::create(path.as_str(), 1, codec, tilespace, ImageFiletype::Raw).unwrap();
+            sink.write_tile(ivec3(0, 0, 1), b"other").unwrap();
+            sink.finish().unwrap();
+        }
+        let source = MBTilesTileSource::open(path.as_str(), Some(1)).unwrap();
+        assert_eq!(source.fetch_tile(ivec3(1, 0, 0)).await, Err(FetchError::Missing));
+        assert_eq!(source.list_tiles().await.unwrap().len(), 1);
+        let _ = std::fs::remove_file(path);
+    }
+}
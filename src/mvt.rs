@@ -0,0 +1,202 @@
+use glam::*;
+
+// Mapbox Vector Tile 2.1 encoding of line layers, enough of protobuf to write it
+
+const GEOM_LINESTRING: u64 = 2;
+const COMMAND_MOVE_TO: u32 = 1;
+const COMMAND_LINE_TO: u32 = 2;
+
+fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn key(out: &mut Vec<u8>, field: u32, wire_type: u32) {
+    varint(out, ((field << 3) | wire_type) as u64);
+}
+
+fn varint_field(out: &mut Vec<u8>, field: u32, value: u64) {
+    key(out, field, 0);
+    varint(out, value);
+}
+
+fn bytes_field(out: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    key(out, field, 2);
+    varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn packed_field(out: &mut Vec<u8>, field: u32, values: &[u32]) {
+    let mut packed = vec![];
+    for value in values.iter() {
+        varint(&mut packed, *value as u64);
+    }
+    bytes_field(out, field, &packed[..]);
+}
+
+fn zigzag(value: i32) -> u32 {
+    ((value << 1) ^ (value >> 31)) as u32
+}
+
+// Parameters of a command are deltas from the previous point, across all lines of a feature
+fn push_point(geometry: &mut Vec<u32>, cursor: &mut IVec2, point: IVec2) {
+    let delta = point - *cursor;
+    geometry.push(zigzag(delta.x));
+    geometry.push(zigzag(delta.y));
+    *cursor = point;
+}
+
+pub struct LineFeature {
+    pub properties: Vec<(String, f64)>,
+    // tile coordinates from 0 to the layer's extent, y down
+    pub lines: Vec<Vec<IVec2>>
+}
+
+pub struct LineLayer {
+    pub name: String,
+    pub extent: u32,
+    pub features: Vec<LineFeature>
+}
+
+impl LineLayer {
+    // Consecutive repeated points are dropped, lines left with fewer than two points and features without lines are left out
+    fn encode(&self) -> Vec<u8> {
+        let mut keys: Vec<&str> = vec![];
+        let mut values: Vec<u64> = vec![];
+        let mut features = vec![];
+
+        for (id, feature) in self.features.iter().enumerate() {
+            let mut geometry: Vec<u32> = vec![];
+            let mut cursor = IVec2::ZERO;
+            for line in feature.lines.iter() {
+                let mut points = line.clone();
+                points.dedup();
+                if points.len() < 2 {
+                    continue;
+                }
+                geometry.push(COMMAND_MOVE_TO | (1 << 3));
+                push_point(&mut geometry, &mut cursor, points[0]);
+                geometry.push(COMMAND_LINE_TO | ((points.len() as u32 - 1) << 3));
+                for point in points[1..].iter() {
+                    push_point(&mut geometry, &mut cursor, *point);
+                }
+            }
+            if geometry.is_empty() {
+                continue;
+            }
+
+            let mut tags: Vec<u32> = vec![];
+            for (name, value) in feature.properties.iter() {
+                let key_index = match keys.iter().position(|k| k == name) {
+                    Some(i) => i,
+                    None => { keys.push(name.as_str()); keys.len() - 1 }
+                };
+                // compared as bits so every value, NaN included, finds itself
+                let value_index = match values.iter().position(|v| *v == value.to_bits()) {
+                    Some(i) => i,
+                    None => { values.push(value.to_bits()); values.len() - 1 }
+                };
+                tags.push(key_index as u32);
+                tags.push(value_index as u32);
+            }
+
+            let mut encoded = vec![];
+            varint_field(&mut encoded, 1, id as u64 + 1);
+            packed_field(&mut encoded, 2, &tags[..]);
+            varint_field(&mut encoded, 3, GEOM_LINESTRING);
+            packed_field(&mut encoded, 4, &geometry[..]);
+            features.push(encoded);
+        }
+
+        let mut out = vec![];
+        varint_field(&mut out, 15, 2);
+        bytes_field(&mut out, 1, self.name.as_bytes());
+        for feature in features.iter() {
+            bytes_field(&mut out, 2, &feature[..]);
+        }
+        for name in keys.iter() {
+            bytes_field(&mut out, 3, name.as_bytes());
+        }
+        for bits in values.iter() {
+            // Value.double_value
+            let mut value = vec![];
+            key(&mut value, 3, 1);
+            value.extend_from_slice(&bits.to_le_bytes());
+            bytes_field(&mut out, 4, &value[..]);
+        }
+        varint_field(&mut out, 5, self.extent as u64);
+        out
+    }
+}
+
+pub fn encode(layers: &[LineLayer]) -> Vec<u8> {
+    let mut out = vec![];
+    for layer in layers.iter() {
+        bytes_field(&mut out, 3, &layer.encode()[..]);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(features: Vec<LineFeature>) -> LineLayer {
+        LineLayer { name: "contours".to_string(), extent: 4096, features }
+    }
+
+    #[test]
+    fn encodes_a_known_line() {
+        // the line from the spec's geometry example
+        let tile = encode(&[layer(vec![LineFeature {
+            properties: vec![("elevation".to_string(), 100.0)],
+            lines: vec![vec![ivec2(2, 2), ivec2(2, 10), ivec2(10, 10)]]
+        }])]);
+
+        let mut expected_layer = vec![0x78, 0x02, 0x0a, 0x08];
+        expected_layer.extend_from_slice(b"contours");
+        expected_layer.extend_from_slice(&[
+            0x12, 0x12,
+            0x08, 0x01,
+            0x12, 0x02, 0x00, 0x00,
+            0x18, 0x02,
+            0x22, 0x08, 9, 4, 4, 18, 0, 16, 16, 0
+        ]);
+        expected_layer.extend_from_slice(&[0x1a, 0x09]);
+        expected_layer.extend_from_slice(b"elevation");
+        expected_layer.extend_from_slice(&[0x22, 0x09, 0x19]);
+        expected_layer.extend_from_slice(&100f64.to_le_bytes());
+        expected_layer.extend_from_slice(&[0x28, 0x80, 0x20]);
+
+        let mut expected = vec![0x1a, expected_layer.len() as u8];
+        expected.extend_from_slice(&expected_layer[..]);
+        assert_eq!(tile, expected);
+    }
+
+    #[test]
+    fn continues_deltas_across_lines_and_drops_degenerate_ones() {
+        let encoded = layer(vec![
+            LineFeature {
+                properties: vec![],
+                lines: vec![vec![ivec2(0, 0), ivec2(1, 0), ivec2(1, 0)], vec![ivec2(5, 5)], vec![ivec2(1, 1), ivec2(1, 2)]]
+            },
+            LineFeature {
+                properties: vec![],
+                lines: vec![vec![ivec2(3, 3), ivec2(3, 3)]]
+            }
+        ]).encode();
+
+        let geometry = [9, 0, 0, 10, 2, 0, 9, 0, 2, 10, 0, 2];
+        let mut feature = vec![0x08, 0x01, 0x12, 0x00, 0x18, 0x02, 0x22, geometry.len() as u8];
+        feature.extend_from_slice(&geometry[..]);
+        let mut expected = vec![0x78, 0x02, 0x0a, 0x08];
+        expected.extend_from_slice(b"contours");
+        expected.extend_from_slice(&[0x12, feature.len() as u8]);
+        expected.extend_from_slice(&feature[..]);
+        expected.extend_from_slice(&[0x28, 0x80, 0x20]);
+        assert_eq!(encoded, expected);
+    }
+}
@@ -1,16 +1,460 @@
-use serde::de;
+use serde::{Serialize, Deserialize, de};
 use core::time::Duration;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::sync::Semaphore;
+use tokio::time::Instant;
+
+use crate::tile_source::FetchError;
+
+// Plain paths and file:// URIs refer to the local filesystem, anything with another scheme is fetched over the network
+pub fn local_path(uri: &str) -> Option<PathBuf> {
+    if let Some(rest) = uri.strip_prefix("file://") {
+        let rest = rest.strip_prefix("localhost").unwrap_or(rest);
+        return Some(PathBuf::from(urlencoding::decode(rest).ok()?.into_owned()));
+    }
+    match uri.contains("://") {
+        true  => None,
+        false => Some(PathBuf::from(uri))
+    }
+}
+
+// Credentials can be kept out of config files by naming an environment variable or a file to read them from
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub enum SecretValue {
+    Literal(String),
+    Env(String),
+    File(String)
+}
+
+impl fmt::Debug for SecretValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretValue::Literal(_) => write!(f, "Literal(<redacted>)"),
+            SecretValue::Env(name) => write!(f, "Env({:?})", name),
+            SecretValue::File(path) => write!(f, "File({:?})", path)
+        }
+    }
+}
+
+impl SecretValue {
+    pub fn resolve(&self) -> Result<String, String> {
+        match self {
+            SecretValue::Literal(value) => Ok(value.clone()),
+            SecretValue::Env(name) => std::env::var(name).map_err(|e| format!("Environment variable {}: {}", name, e)),
+            // files usually end with a newline
+            SecretValue::File(path) => std::fs::read_to_string(path)
+                .map(|value| value.trim_end_matches(&['\r', '\n'][..]).to_string())
+                .map_err(|e| format!("{}: {}", path, e))
+        }
+    }
+
+    // Env and File read from the machine doing the fetching
+    pub fn is_literal(&self) -> bool {
+        matches!(self, SecretValue::Literal(_))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum AuthConfig {
+    Bearer {
+        token: SecretValue
+    },
+    Basic {
+        username: String,
+        password: SecretValue
+    }
+}
+
+impl AuthConfig {
+    fn secrets(&self) -> Vec<&SecretValue> {
+        match self {
+            AuthConfig::Bearer { token } => vec![token],
+            AuthConfig::Basic { password, .. } => vec![password]
+        }
+    }
+}
+
+enum ResolvedAuth {
+    Bearer(String),
+    Basic(String, String)
+}
+
+impl fmt::Debug for ResolvedAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResolvedAuth::Bearer(_) => write!(f, "Bearer(<redacted>)"),
+            ResolvedAuth::Basic(username, _) => write!(f, "Basic({:?}, <redacted>)", username)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct HttpConfig {
+    pub timeout_secs: f64,
+    pub connect_timeout_secs: f64,
+    // attempts after the first one for transient failures (timeouts, 429 and 5xx responses)
+    pub retries: u32,
+    pub backoff_initial_ms: u64,
+    pub backoff_max_ms: u64,
+    pub max_concurrent_per_host: usize,
+    pub max_requests_per_second_per_host: Option<f64>,
+    pub auth: Option<AuthConfig>,
+    // sent with every request, manifest and tiles alike
+    pub headers: HashMap<String, SecretValue>
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            timeout_secs: 30.0,
+            connect_timeout_secs: 10.0,
+            retries: 4,
+            backoff_initial_ms: 250,
+            backoff_max_ms: 10000,
+            max_concurrent_per_host: 8,
+            max_requests_per_second_per_host: None,
+            auth: None,
+            headers: HashMap::new()
+        }
+    }
+}
+
+// What a client may set for fetches the server makes on its behalf, timeouts, retries and limits stay the server's
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct HttpCredentials {
+    pub auth: Option<AuthConfig>,
+    pub headers: HashMap<String, SecretValue>
+}
+
+impl HttpCredentials {
+    pub fn config(&self) -> HttpConfig {
+        HttpConfig {
+            auth: self.auth.clone(),
+            headers: self.headers.clone(),
+            ..HttpConfig::default()
+        }
+    }
+}
+
+impl HttpConfig {
+    // Whether every secret is given inline, configs coming from clients must not read the server's environment or files
+    pub fn only_literal_secrets(&self) -> bool {
+        self.auth.iter().flat_map(|auth| auth.secrets())
+        .chain(self.headers.values())
+        .all(|secret| secret.is_literal())
+    }
+
+    // Durations and rates that std::time would panic on or turn into an endless wait
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, secs) in [("timeout_secs", self.timeout_secs), ("connect_timeout_secs", self.connect_timeout_secs)] {
+            if wait_duration(secs).is_none() {
+                return Err(format!("{} of {} has to be a positive number of seconds up to {}", name, secs, MAX_WAIT_SECS));
+            }
+        }
+        match self.max_requests_per_second_per_host {
+            Some(rate) if wait_duration(1.0 / rate).is_none() =>
+                Err(format!("max_requests_per_second_per_host of {} has to be a positive number of at least {}", rate, 1.0 / MAX_WAIT_SECS)),
+            _ => Ok(())
+        }
+    }
+}
+
+// Longest timeout or wait between requests a config may ask for, a day
+const MAX_WAIT_SECS: f64 = 86400.0;
+
+fn wait_duration(secs: f64) -> Option<Duration> {
+    match secs > 0.0 && secs <= MAX_WAIT_SECS {
+        true  => Duration::try_from_secs_f64(secs).ok(),
+        false => None
+    }
+}
+
+#[derive(Debug)]
+struct HostLimits {
+    permits: Semaphore,
+    next_request: Mutex<Instant>
+}
+
+// Cheap to clone, clones share connections and per host limits
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    pub config: HttpConfig,
+    client: reqwest::Client,
+    // secrets are resolved once when the client is created
+    auth: Option<Arc<ResolvedAuth>>,
+    headers: reqwest::header::HeaderMap,
+    hosts: Arc<Mutex<HashMap<String, Arc<HostLimits>>>>
+}
+
+enum Attempt {
+    // status, Content-Length and body
+    Done(u16, Option<u64>, Vec<u8>),
+    // reason, Retry-After and whether it was a timeout
+    Retry(String, Option<Duration>, bool),
+    Fail(FetchError)
+}
+
+impl HttpClient {
+    pub fn new(config: HttpConfig) -> Result<Self, String> {
+        config.validate()?;
+        let auth = match &config.auth {
+            None => None,
+            Some(AuthConfig::Bearer { token }) => Some(Arc::new(ResolvedAuth::Bearer(token.resolve()?))),
+            Some(AuthConfig::Basic { username, password }) => Some(Arc::new(ResolvedAuth::Basic(username.clone(), password.resolve()?)))
+        };
+
+        let mut headers = reqwest::header::HeaderMap::new();
+        for (name, value) in config.headers.iter() {
+            let name
+                =reqwest::header::HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| format!("Header {}: {}", name, e))?;
+            let mut value
+                =reqwest::header::HeaderValue::from_str(value.resolve()?.as_str())
+                .map_err(|e| format!("Header {}: {}", name, e))?;
+            value.set_sensitive(true);
+            headers.insert(name, value);
+        }
+
+        Ok(HttpClient {
+            client: reqwest::Client::builder()
+                .timeout(wait_duration(config.timeout_secs).unwrap())
+                .connect_timeout(wait_duration(config.connect_timeout_secs).unwrap())
+                .build().map_err(|e| e.to_string())?,
+            config,
+            auth,
+            headers,
+            hosts: Arc::new(Mutex::new(HashMap::new()))
+        })
+    }
+
+    // Process wide client with the default configuration
+    pub fn shared() -> HttpClient {
+        static SHARED: OnceLock<HttpClient> = OnceLock::new();
+        SHARED.get_or_init(|| HttpClient::new(HttpConfig::default()).unwrap()).clone()
+    }
+
+    fn host_limits(&self, uri: &str) -> Arc<HostLimits> {
+        let host = reqwest::Url::parse(uri).ok().and_then(|u| u.host_str().map(|h| h.to_string())).unwrap_or_default();
+        self.hosts.lock().unwrap().entry(host).or_insert_with(|| Arc::new(HostLimits {
+            permits: Semaphore::new(self.config.max_concurrent_per_host.max(1)),
+            next_request: Mutex::new(Instant::now())
+        })).clone()
+    }
+
+    async fn wait_for_rate_limit(&self, limits: &HostLimits) {
+        if let Some(rate) = self.config.max_requests_per_second_per_host {
+            let slot = {
+                let mut next = limits.next_request.lock().unwrap();
+                let slot = (*next).max(Instant::now());
+                *next = slot + wait_duration(1.0 / rate).unwrap();
+                slot
+            };
+            tokio::time::sleep_until(slot).await;
+        }
+    }
+
+    // exponential backoff with full jitter
+    fn backoff(&self, attempt: u32) -> Duration {
+        let max = self.config.backoff_max_ms as f64;
+        let ceiling = (self.config.backoff_initial_ms as f64 * 2f64.powi(attempt as i32)).min(max);
+        Duration::from_millis((ceiling * rand::random::<f64>()) as u64)
+    }
+
+    async fn attempt(&self, request: reqwest::RequestBuilder) -> Attempt {
+        let response = match request.send().await {
+            Ok(response) => response,
+            Err(e) if e.is_timeout() || e.is_connect() || e.is_request() => return Attempt::Retry(e.to_string(), None, e.is_timeout()),
+            Err(e) => return Attempt::Fail(FetchError::Failed(e.to_string()))
+        };
+
+        let status = response.status();
+        let retry_after
+            =response.headers().get("Retry-After")
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_retry_after);
+
+        if status == reqwest::StatusCode::NOT_FOUND || status == reqwest::StatusCode::GONE {
+            return Attempt::Fail(FetchError::Missing);
+        }
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            || status == reqwest::StatusCode::REQUEST_TIMEOUT
+            || status.is_server_error() {
+            let timed_out = status == reqwest::StatusCode::REQUEST_TIMEOUT || status == reqwest::StatusCode::GATEWAY_TIMEOUT;
+            return Attempt::Retry(format!("{} returned {}", response.url(), status), retry_after, timed_out);
+        }
+        if !status.is_success() {
+            return Attempt::Fail(FetchError::Failed(format!("{} returned {}", response.url(), status)));
+        }
+
+        // from the header, the body of a HEAD response is always empty
+        let content_length
+            =response.headers().get("Content-Length")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        match response.bytes().await {
+            Ok(bytes) => Attempt::Done(status.as_u16(), content_length, bytes.to_vec()),
+            // connection dropped mid body
+            Err(e) => Attempt::Retry(e.to_string(), None, e.is_timeout())
+        }
+    }
+
+    // range is the first and last byte, inclusive
+    async fn send(&self, method: reqwest::Method, uri: &str, range: Option<(u64, u64)>) -> Result<(u16, Option<u64>, Vec<u8>), FetchError> {
+        let limits = self.host_limits(uri);
+        let mut attempt = 0;
+        loop {
+            let result = {
+                let _permit = limits.permits.acquire().await.map_err(|e| FetchError::Failed(e.to_string()))?;
+                self.wait_for_rate_limit(&limits).await;
+
+                let mut request = self.client.request(method.clone(), uri).headers(self.headers.clone());
+                request = match self.auth.as_deref() {
+                    None => request,
+                    Some(ResolvedAuth::Bearer(token)) => request.bearer_auth(token),
+                    Some(ResolvedAuth::Basic(username, password)) => request.basic_auth(username, Some(password))
+                };
+                if let Some((first, last)) = range {
+                    request = request.header("Range", format!("bytes={}-{}", first, last));
+                }
+                self.attempt(request).await
+            };
+
+            match result {
+                Attempt::Done(status, content_length, bytes) => return Ok((status, content_length, bytes)),
+                Attempt::Fail(e) => return Err(e),
+                Attempt::Retry(reason, retry_after, timed_out) => {
+                    if attempt >= self.config.retries {
+                        let reason = format!("{} (gave up after {} attempts)", reason, attempt + 1);
+                        return Err(match timed_out {
+                            true  => FetchError::Timeout(reason),
+                            false => FetchError::Failed(reason)
+                        });
+                    }
+                    // a server asking for a longer wait than backoff_max_ms doesn't get to stall the fetch
+                    let wait = match retry_after {
+                        Some(retry_after) => retry_after.min(Duration::from_millis(self.config.backoff_max_ms)),
+                        None => self.backoff(attempt)
+                    };
+                    tokio::time::sleep(wait).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    pub async fn get(&self, uri: &str) -> Result<Vec<u8>, FetchError> {
+        Ok(self.send(reqwest::Method::GET, uri, None).await?.2)
+    }
+
+    // Whether the resource exists and its Content-Length if the server sent one
+    pub async fn head(&self, uri: &str) -> Result<Option<u64>, FetchError> {
+        Ok(self.send(reqwest::Method::HEAD, uri, None).await?.1)
+    }
+
+    pub async fn get_range(&self, uri: &str, offset: u64, length: u64) -> Result<Vec<u8>, FetchError> {
+        if length == 0 {
+            return Ok(vec![]);
+        }
+        let last
+            =offset.checked_add(length - 1)
+            .ok_or_else(|| FetchError::Failed(format!("{}: range {}+{} is past the largest offset", uri, offset, length)))?;
+        let (status, _, bytes) = self.send(reqwest::Method::GET, uri, Some((offset, last))).await?;
+
+        // a server ignoring the range sends the whole file for every read, only a file that is exactly the range is taken
+        if status != 206 && !(offset == 0 && bytes.len() as u64 == length) {
+            return Err(FetchError::Failed(format!("{} doesn't support range requests, it returned {}", uri, status)));
+        }
+        if bytes.len() as u64 != length {
+            return Err(FetchError::Failed(format!("{}: range {}+{} returned {} bytes", uri, offset, length, bytes.len())));
+        }
+        Ok(bytes)
+    }
+}
+
+// Retry-After is either a number of seconds or an HTTP date
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    match value.trim().parse::<u64>() {
+        Ok(secs) => Some(Duration::from_secs(secs)),
+        Err(_) => {
+            let date = httpdate::parse_http_date(value.trim()).ok()?;
+            // a date in the past means now
+            Some(date.duration_since(std::time::SystemTime::now()).unwrap_or(Duration::ZERO))
+        }
+    }
+}
+
+pub async fn fetch_bytes_with(client: &HttpClient, uri: &str) -> Result<Vec<u8>, FetchError> {
+    match local_path(uri) {
+        Some(path) => {
+            tokio::fs::read(&path).await
+            .map_err(|er| match er.kind() {
+                std::io::ErrorKind::NotFound => FetchError::Missing,
+                _ => FetchError::Failed(format!("{}: {}", path.display(), er))
+            })
+        },
+        None => client.get(uri).await
+    }
+}
+
+pub async fn fetch_bytes(uri: &str) -> Result<Vec<u8>, String> {
+    fetch_bytes_with(&HttpClient::shared(), uri).await
+    .map_err(|er| format!("{}: {}", uri, er))
+}
+
+pub async fn parse_json_from_uri_with<T>(client: &HttpClient, uri: &str) -> Result<T, String>
+where T: de::DeserializeOwned {
+    let bytes = fetch_bytes_with(client, uri).await.map_err(|er| format!("{}: {}", uri, er))?;
+
+    serde_json::from_slice::<T>(&bytes[..]).map_err(|er| { format!("{}: {}", uri, er) })
+}
 
 pub async fn parse_json_from_uri<T>(uri: &str) -> Result<T, String>
 where T: de::DeserializeOwned {
-    let text
-        =reqwest::Client::builder()
-        .timeout(Duration::from_secs(30))
-        .build().unwrap()
-        .get(uri)
-        .send().await.map_err(|er| { er.to_string() })?
-        .error_for_status().map_err(|er| { er.to_string() })?
-        .text().await.map_err(|er| { er.to_string() })?;
-
-    serde_json::from_str::<T>(text.as_str()).map_err(|er| { er.to_string() })
-}
\ No newline at end of file
+    parse_json_from_uri_with(&HttpClient::shared(), uri).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_durations_std_cant_hold() {
+        assert!(HttpConfig::default().validate().is_ok());
+        for config in [
+            HttpConfig { timeout_secs: -1.0, ..Default::default() },
+            HttpConfig { connect_timeout_secs: f64::NAN, ..Default::default() },
+            HttpConfig { timeout_secs: f64::INFINITY, ..Default::default() },
+            HttpConfig { timeout_secs: 1e20, ..Default::default() },
+            HttpConfig { connect_timeout_secs: 0.0, ..Default::default() },
+            HttpConfig { max_requests_per_second_per_host: Some(0.0), ..Default::default() },
+            HttpConfig { max_requests_per_second_per_host: Some(-1.0), ..Default::default() },
+            HttpConfig { max_requests_per_second_per_host: Some(1e-20), ..Default::default() }
+        ] {
+            assert!(config.validate().is_err());
+            assert!(HttpClient::new(config).is_err());
+        }
+        assert!(HttpConfig { timeout_secs: MAX_WAIT_SECS, max_requests_per_second_per_host: Some(1e20), ..Default::default() }.validate().is_ok());
+    }
+
+    #[tokio::test]
+    async fn rejects_ranges_past_the_largest_offset() {
+        let client = HttpClient::shared();
+        assert!(matches!(client.get_range("http://127.0.0.1:9/", u64::MAX, 2).await, Err(FetchError::Failed(_))));
+        assert_eq!(client.get_range("http://127.0.0.1:9/", u64::MAX, 0).await, Ok(vec![]));
+    }
+
+    #[test]
+    fn parses_both_forms_of_retry_after() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), Some(Duration::ZERO));
+        let later = std::time::SystemTime::now() + Duration::from_secs(600);
+        let wait = parse_retry_after(httpdate::fmt_http_date(later).as_str()).unwrap();
+        assert!(wait > Duration::from_secs(590) && wait <= Duration::from_secs(600));
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+}
@@ -0,0 +1,726 @@
+use glam::*;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::sync::Mutex;
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+
+use crate::checksum::sha256;
+use crate::manifest::Manifest;
+use crate::dataset::*;
+use crate::image::{ImageCodec, ImageFiletype};
+use crate::range_reader::*;
+use crate::network_util::HttpClient;
+use crate::tile_sink::*;
+use crate::tile_source::*;
+
+// PMTiles v3, see https://github.com/protomaps/PMTiles/blob/main/spec/v3/spec.md
+const HEADER_SIZE: usize = 127;
+// header and root directory have to fit in the first 16k so readers can fetch both with one request
+const ROOT_SIZE: usize = 16384;
+const MAX_DIRECTORY_DEPTH: usize = 3;
+// directories and metadata decompressing past this are rejected rather than read into memory
+const MAX_INTERNAL_SIZE: usize = 32 << 20;
+
+const COMPRESSION_NONE: u8 = 1;
+const COMPRESSION_GZIP: u8 = 2;
+
+const TILE_TYPE_UNKNOWN: u8 = 0;
+const TILE_TYPE_PNG: u8 = 2;
+
+// Tile ids of zoom 32 and up don't fit a u64
+const MAX_ZOOM: i32 = 31;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub tile_id: u64,
+    pub offset: u64,
+    pub length: u32,
+    // 0 means the entry points at a leaf directory
+    pub run_length: u32
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Header {
+    pub root_offset: u64,
+    pub root_length: u64,
+    pub metadata_offset: u64,
+    pub metadata_length: u64,
+    pub leaf_offset: u64,
+    pub leaf_length: u64,
+    pub data_offset: u64,
+    pub data_length: u64,
+    pub addressed_tiles: u64,
+    pub tile_entries: u64,
+    pub tile_contents: u64,
+    pub clustered: bool,
+    pub internal_compression: u8,
+    pub tile_compression: u8,
+    pub tile_type: u8,
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    // (west, south, east, north)
+    pub bounds: [f64; 4],
+    pub center_zoom: u8,
+    pub center: DVec2
+}
+
+fn rotate(n: u64, x: &mut u64, y: &mut u64, rx: u64, ry: u64) {
+    if ry == 0 {
+        if rx == 1 {
+            *x = n - 1 - *x;
+            *y = n - 1 - *y;
+        }
+        std::mem::swap(x, y);
+    }
+}
+
+// Tiles are numbered by zoom and then along a Hilbert curve within each zoom
+pub fn zxy_to_tile_id(z: u8, x: u64, y: u64) -> u64 {
+    let acc = ((1u64 << (2 * z as u64)) - 1) / 3;
+    let n = 1u64 << z;
+    let (mut x, mut y) = (x, y);
+    let mut d = 0;
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = ((x & s) > 0) as u64;
+        let ry = ((y & s) > 0) as u64;
+        d += s * s * ((3 * rx) ^ ry);
+        rotate(n, &mut x, &mut y, rx, ry);
+        s /= 2;
+    }
+    acc + d
+}
+
+// Id of a tile in the pyramid, None for zooms past MAX_ZOOM and x or y outside 0 to 2^zoom
+pub fn pyramid_tile_id(zoom: i32, x: i32, y: i32) -> Option<u64> {
+    if !(0..=MAX_ZOOM).contains(&zoom) || x < 0 || y < 0 || x as u64 >= 1u64 << zoom || y as u64 >= 1u64 << zoom {
+        return None;
+    }
+    Some(zxy_to_tile_id(zoom as u8, x as u64, y as u64))
+}
+
+// Number of tile ids in zooms 0 through max_zoom
+pub fn pyramid_tile_count(max_zoom: i32) -> u64 {
+    (0..=max_zoom.min(MAX_ZOOM)).map(|z| 1u64 << (2 * z)).sum()
+}
+
+// None for ids past the last zoom
+pub fn tile_id_to_zxy(tile_id: u64) -> Option<(u8, u64, u64)> {
+    let mut acc = 0;
+    for z in 0..32u8 {
+        let num_tiles = 1u64 << (2 * z as u64);
+        if acc + num_tiles > tile_id {
+            let n = 1u64 << z;
+            let mut t = tile_id - acc;
+            let (mut x, mut y) = (0, 0);
+            let mut s = 1;
+            while s < n {
+                let rx = 1 & (t / 2);
+                let ry = 1 & (t ^ rx);
+                rotate(s, &mut x, &mut y, rx, ry);
+                x += s * rx;
+                y += s * ry;
+                t /= 4;
+                s *= 2;
+            }
+            return Some((z, x, y));
+        }
+        acc += num_tiles;
+    }
+    None
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Result<u64, String> {
+    let mut res = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = *data.get(*pos).ok_or("Truncated PMTiles directory")?;
+        *pos += 1;
+        res |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(res);
+        }
+        shift += 7;
+        if shift > 63 {
+            return Err("Invalid varint in PMTiles directory".to_string());
+        }
+    }
+}
+
+fn gzip(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(data).map_err(|e| e.to_string())?;
+    encoder.finish().map_err(|e| e.to_string())
+}
+
+fn decompress(data: Vec<u8>, compression: u8, limit: usize) -> Result<Vec<u8>, String> {
+    let res = match compression {
+        0 | COMPRESSION_NONE => data,
+        COMPRESSION_GZIP => {
+            let mut res = vec![];
+            GzDecoder::new(&data[..]).take(limit as u64 + 1).read_to_end(&mut res).map_err(|e| e.to_string())?;
+            res
+        },
+        other => return Err(format!("Unsupported PMTiles compression {}", other))
+    };
+    match res.len() > limit {
+        true  => Err(format!("PMTiles data decompresses to more than {} bytes", limit)),
+        false => Ok(res)
+    }
+}
+
+fn serialize_directory(entries: &[Entry]) -> Result<Vec<u8>, String> {
+    let mut res = vec![];
+    write_varint(&mut res, entries.len() as u64);
+    let mut last_id = 0;
+    for e in entries {
+        write_varint(&mut res, e.tile_id - last_id);
+        last_id = e.tile_id;
+    }
+    for e in entries { write_varint(&mut res, e.run_length as u64); }
+    for e in entries { write_varint(&mut res, e.length as u64); }
+    for (i, e) in entries.iter().enumerate() {
+        if i > 0 && e.offset == entries[i - 1].offset + entries[i - 1].length as u64 {
+            write_varint(&mut res, 0);
+        } else {
+            write_varint(&mut res, e.offset + 1);
+        }
+    }
+    gzip(&res[..])
+}
+
+fn deserialize_directory(data: &[u8]) -> Result<Vec<Entry>, String> {
+    let mut pos = 0;
+    let count = read_varint(data, &mut pos)?;
+    // every entry takes at least a byte for each of its four fields
+    if count > data.len() as u64 / 4 {
+        return Err(format!("PMTiles directory of {} bytes can't have {} entries", data.len(), count));
+    }
+    let count = count as usize;
+    let overflow = || "PMTiles directory entry is out of range".to_string();
+    let mut entries = vec![Entry { tile_id: 0, offset: 0, length: 0, run_length: 0 }; count];
+    let mut last_id = 0u64;
+    for e in entries.iter_mut() {
+        last_id = last_id.checked_add(read_varint(data, &mut pos)?).ok_or_else(overflow)?;
+        e.tile_id = last_id;
+    }
+    for e in entries.iter_mut() { e.run_length = read_varint(data, &mut pos)? as u32; }
+    for e in entries.iter_mut() { e.length = read_varint(data, &mut pos)? as u32; }
+    for i in 0..count {
+        let value = read_varint(data, &mut pos)?;
+        entries[i].offset = match (value, i) {
+            (0, i) if i > 0 => entries[i - 1].offset.checked_add(entries[i - 1].length as u64).ok_or_else(overflow)?,
+            (value, _) => value.checked_sub(1).ok_or_else(overflow)?
+        };
+    }
+    Ok(entries)
+}
+
+impl Header {
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut res = Vec::with_capacity(HEADER_SIZE);
+        res.extend_from_slice(b"PMTiles");
+        res.push(3);
+        for v in [
+            self.root_offset, self.root_length, self.metadata_offset, self.metadata_length,
+            self.leaf_offset, self.leaf_length, self.data_offset, self.data_length,
+            self.addressed_tiles, self.tile_entries, self.tile_contents
+        ].iter() {
+            res.extend_from_slice(&v.to_le_bytes());
+        }
+        res.extend_from_slice(&[
+            self.clustered as u8, self.internal_compression, self.tile_compression,
+            self.tile_type, self.min_zoom, self.max_zoom
+        ]);
+        let e7 = |v: f64| ((v * 1e7).round() as i32).to_le_bytes();
+        for v in self.bounds.iter() {
+            res.extend_from_slice(&e7(*v));
+        }
+        res.push(self.center_zoom);
+        res.extend_from_slice(&e7(self.center.x));
+        res.extend_from_slice(&e7(self.center.y));
+        res
+    }
+
+    pub fn deserialize(data: &[u8]) -> Result<Self, String> {
+        if data.len() < HEADER_SIZE || &data[0..7] != b"PMTiles" {
+            return Err("Not a PMTiles archive".to_string());
+        }
+        if data[7] != 3 {
+            return Err(format!("Unsupported PMTiles version {}", data[7]));
+        }
+        let u64_at = |i: usize| u64::from_le_bytes(data[i..i + 8].try_into().unwrap());
+        let e7_at = |i: usize| i32::from_le_bytes(data[i..i + 4].try_into().unwrap()) as f64 / 1e7;
+        Ok(Header {
+            root_offset: u64_at(8),
+            root_length: u64_at(16),
+            metadata_offset: u64_at(24),
+            metadata_length: u64_at(32),
+            leaf_offset: u64_at(40),
+            leaf_length: u64_at(48),
+            data_offset: u64_at(56),
+            data_length: u64_at(64),
+            addressed_tiles: u64_at(72),
+            tile_entries: u64_at(80),
+            tile_contents: u64_at(88),
+            clustered: data[96] == 1,
+            internal_compression: data[97],
+            tile_compression: data[98],
+            tile_type: data[99],
+            min_zoom: data[100],
+            max_zoom: data[101],
+            bounds: [e7_at(102), e7_at(106), e7_at(110), e7_at(114)],
+            center_zoom: data[118],
+            center: dvec2(e7_at(119), e7_at(123))
+        })
+    }
+}
+
+// Splits entries into leaf directories, growing the leaves until the root fits next to the header
+fn build_directories(entries: &[Entry]) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let root = serialize_directory(entries)?;
+    if root.len() <= ROOT_SIZE - HEADER_SIZE {
+        return Ok((root, vec![]));
+    }
+
+    let mut leaf_size = 4096;
+    loop {
+        let mut root_entries = vec![];
+        let mut leaves = vec![];
+        for chunk in entries.chunks(leaf_size) {
+            let leaf = serialize_directory(chunk)?;
+            root_entries.push(Entry {
+                tile_id: chunk[0].tile_id,
+                offset: leaves.len() as u64,
+                length: leaf.len() as u32,
+                run_length: 0
+            });
+            leaves.extend_from_slice(&leaf[..]);
+        }
+        let root = serialize_directory(&root_entries[..])?;
+        if root.len() <= ROOT_SIZE - HEADER_SIZE {
+            return Ok((root, leaves));
+        }
+        leaf_size *= 2;
+    }
+}
+
+#[derive(Debug)]
+struct PMTilesWriteState {
+    // tile contents in the order they were written, reordered by tile id on finish
+    contents: File,
+    contents_length: u64,
+    // sha256 of the contents to their offset, identical tiles are only stored once
+    deduplicated: HashMap<[u8; 32], u64>,
+    // tile id to (offset, length) in contents
+    tiles: HashMap<u64, (u64, u32)>,
+    extent: WrittenExtent
+}
+
+#[derive(Debug)]
+pub struct PMTilesSink {
+    pub path: String,
+    pub info: DatasetInfo,
+    pub filetype: ImageFiletype,
+    contents_path: String,
+    state: Mutex<PMTilesWriteState>
+}
+
+impl PMTilesSink {
+    pub fn create(path: &str, max_zoom: i32, codec: ImageCodec, tilespace: Tilespace, filetype: ImageFiletype) -> Result<Self, String> {
+        let contents_path = format!("{}.contents", path);
+        let contents
+            =OpenOptions::new().read(true).write(true).create(true).truncate(true)
+            .open(contents_path.as_str())
+            .map_err(|e| format!("{}: {}", contents_path, e))?;
+
+        Ok(PMTilesSink {
+            path: path.to_string(),
+            info: DatasetInfo { codec, tilespace, max_zoom, nodata: None, extent: None },
+            filetype,
+            contents_path,
+            state: Mutex::new(PMTilesWriteState {
+                contents,
+                contents_length: 0,
+                deduplicated: HashMap::new(),
+                tiles: HashMap::new(),
+                extent: WrittenExtent::default()
+            })
+        })
+    }
+
+    fn header(&self, state: &PMTilesWriteState) -> Header {
+        let bounds = match (self.info.tilespace.georef, state.extent.pixel_bounds) {
+            (Some(georef), Some(pixels)) => georef.bounds(pixels),
+            _ => [-180.0, -85.0, 180.0, 85.0]
+        };
+        // an archive without tiles still needs zooms a reader accepts
+        let (min_zoom, max_zoom) = match state.extent.is_empty() {
+            true  => (0, 0),
+            false => (state.extent.min_zoom as u8, state.extent.max_zoom as u8)
+        };
+        Header {
+            internal_compression: COMPRESSION_GZIP,
+            tile_compression: COMPRESSION_NONE,
+            tile_type: match self.filetype {
+                ImageFiletype::PNG => TILE_TYPE_PNG,
+                _ => TILE_TYPE_UNKNOWN
+            },
+            min_zoom,
+            max_zoom,
+            bounds,
+            center_zoom: min_zoom,
+            center: dvec2((bounds[0] + bounds[2]) / 2.0, (bounds[1] + bounds[3]) / 2.0),
+            ..Header::default()
+        }
+    }
+}
+
+impl TileSink for PMTilesSink {
+    fn write_tile(&self, coord: IVec3, data: &[u8]) -> Result<(), String> {
+        let zoom = level_to_zoom(coord.z, self.info.max_zoom);
+        let tile_id
+            =pyramid_tile_id(zoom, coord.x, coord.y)
+            .ok_or_else(|| format!("Tile {:?} is outside of the PMTiles tile pyramid at zoom {}", coord, zoom))?;
+        let length: u32 = data.len().try_into().map_err(|_| format!("Tile {:?} of {} bytes is too large for PMTiles", coord, data.len()))?;
+
+        let key = sha256(data);
+
+        let mut state = self.state.lock().unwrap();
+        let offset = match state.deduplicated.get(&key) {
+            Some(&offset) => offset,
+            None => {
+                let offset = state.contents_length;
+                state.contents.seek(SeekFrom::Start(offset)).map_err(|e| e.to_string())?;
+                state.contents.write_all(data).map_err(|e| e.to_string())?;
+                state.contents_length += data.len() as u64;
+                state.deduplicated.insert(key, offset);
+                offset
+            }
+        };
+        state.tiles.insert(tile_id, (offset, length));
+        state.extent.add(coord, zoom, &self.info.tilespace);
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+
+        let mut ids: Vec<u64> = state.tiles.keys().cloned().collect();
+        ids.sort_unstable();
+
+        // Lay tile data out in tile id order so the archive is clustered
+        let mut data_offsets = HashMap::<u64, u64>::new();
+        let mut data = Vec::<(u64, u32)>::new();
+        let mut data_length = 0;
+        let mut entries = Vec::<Entry>::new();
+        for id in ids.iter() {
+            let (contents_offset, length) = state.tiles[id];
+            let offset = *data_offsets.entry(contents_offset).or_insert_with(|| {
+                data.push((contents_offset, length));
+                data_length += length as u64;
+                data_length - length as u64
+            });
+            match entries.last_mut() {
+                Some(last) if last.tile_id + last.run_length as u64 == *id && last.offset == offset && last.length == length => {
+                    last.run_length += 1;
+                },
+                _ => entries.push(Entry { tile_id: *id, offset, length, run_length: 1 })
+            }
+        }
+
+        let (root, leaves) = build_directories(&entries[..])?;
+        let metadata = gzip(serde_json::to_string(&self.info).map_err(|e| e.to_string())?.as_bytes())?;
+
+        let mut header = self.header(&state);
+        header.root_offset = HEADER_SIZE as u64;
+        header.root_length = root.len() as u64;
+        header.metadata_offset = header.root_offset + header.root_length;
+        header.metadata_length = metadata.len() as u64;
+        header.leaf_offset = header.metadata_offset + header.metadata_length;
+        header.leaf_length = leaves.len() as u64;
+        header.data_offset = header.leaf_offset + header.leaf_length;
+        header.data_length = data_length;
+        header.addressed_tiles = ids.len() as u64;
+        header.tile_entries = entries.len() as u64;
+        header.tile_contents = data.len() as u64;
+        header.clustered = true;
+
+        let mut out = BufWriter::new(File::create(self.path.as_str()).map_err(|e| format!("{}: {}", self.path, e))?);
+        let mut write = |bytes: &[u8]| out.write_all(bytes).map_err(|e| e.to_string());
+        write(&header.serialize()[..])?;
+        write(&root[..])?;
+        write(&metadata[..])?;
+        write(&leaves[..])?;
+        let mut buf = vec![];
+        for (contents_offset, length) in data {
+            buf.resize(length as usize, 0);
+            state.contents.seek(SeekFrom::Start(contents_offset)).map_err(|e| e.to_string())?;
+            state.contents.read_exact(&mut buf[..]).map_err(|e| e.to_string())?;
+            write(&buf[..])?;
+        }
+        out.flush().map_err(|e| e.to_string())
+    }
+}
+
+impl Drop for PMTilesSink {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(self.contents_path.as_str());
+    }
+}
+
+#[derive(Debug)]
+pub struct PMTilesTileSource {
+    pub header: Header,
+    // level 0 of the dataset, read from the metadata written by PMTilesSink or the header's max zoom
+    pub max_zoom: i32,
+    pub info: Option<DatasetInfo>,
+    reader: Box<dyn RangeReader>,
+    root: Vec<Entry>,
+    leaves: Mutex<HashMap<(u64, u64), Vec<Entry>>>
+}
+
+impl PMTilesTileSource {
+    pub async fn open(uri: &str, max_zoom: Option<i32>, client: HttpClient) -> Result<Self, String> {
+        let reader = open_range_reader(uri, client);
+        let header = Header::deserialize(&reader.read_range(0, HEADER_SIZE as u64).await?[..])?;
+
+        let root = deserialize_directory(&decompress(
+            reader.read_range(header.root_offset, header.root_length).await?,
+            header.internal_compression,
+            MAX_INTERNAL_SIZE
+        )?[..])?;
+
+        let info: Option<DatasetInfo> = match header.metadata_length {
+            0 => None,
+            length => {
+                let metadata = decompress(reader.read_range(header.metadata_offset, length).await?, header.internal_compression, MAX_INTERNAL_SIZE)?;
+                serde_json::from_slice(&metadata[..]).ok()
+            }
+        };
+
+        Ok(PMTilesTileSource {
+            max_zoom: max_zoom.or(info.as_ref().map(|i| i.max_zoom)).unwrap_or(header.max_zoom as i32),
+            header,
+            info,
+            reader,
+            root,
+            leaves: Mutex::new(HashMap::new())
+        })
+    }
+
+    async fn read_leaf(&self, offset: u64, length: u64) -> Result<Vec<Entry>, String> {
+        if let Some(entries) = self.leaves.lock().unwrap().get(&(offset, length)) {
+            return Ok(entries.clone());
+        }
+        let entries = deserialize_directory(&decompress(
+            self.reader.read_range(self.header.leaf_offset + offset, length).await?,
+            self.header.internal_compression,
+            MAX_INTERNAL_SIZE
+        )?[..])?;
+        self.leaves.lock().unwrap().insert((offset, length), entries.clone());
+        Ok(entries)
+    }
+
+    async fn find_tile(&self, tile_id: u64) -> Result<Option<Entry>, String> {
+        let mut entries = self.root.clone();
+        for _ in 0..MAX_DIRECTORY_DEPTH {
+            // last entry starting at or before the tile id
+            let i = match entries.binary_search_by_key(&tile_id, |e| e.tile_id) {
+                Ok(i) => i,
+                Err(0) => return Ok(None),
+                Err(i) => i - 1
+            };
+            let entry = entries[i];
+            if entry.run_length == 0 {
+                entries = self.read_leaf(entry.offset, entry.length as u64).await?;
+            } else if tile_id < entry.tile_id + entry.run_length as u64 {
+                return Ok(Some(entry));
+            } else {
+                return Ok(None);
+            }
+        }
+        Err("PMTiles directories are nested too deep".to_string())
+    }
+
+    async fn collect_entries(&self, entries: &[Entry], depth: usize, res: &mut Vec<IVec3>) -> Result<(), String> {
+        for entry in entries {
+            if entry.run_length == 0 {
+                if depth + 1 >= MAX_DIRECTORY_DEPTH {
+                    return Err("PMTiles directories are nested too deep".to_string());
+                }
+                let leaf = self.read_leaf(entry.offset, entry.length as u64).await?;
+                Box::pin(self.collect_entries(&leaf[..], depth + 1, res)).await?;
+                continue;
+            }
+            // a run can't reach past the zooms this archive has
+            let end = entry.tile_id.saturating_add(entry.run_length as u64).min(pyramid_tile_count(self.max_zoom));
+            for tile_id in entry.tile_id..end {
+                let (z, x, y) = tile_id_to_zxy(tile_id).ok_or_else(|| format!("PMTiles tile id {} is out of range", tile_id))?;
+                res.push(ivec3(x as i32, y as i32, zoom_to_level(z as i32, self.max_zoom)));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TileSource for PMTilesTileSource {
+    fn fetch_tile(&self, coord: IVec3) -> FetchFuture<'_, Vec<u8>> {
+        Box::pin(async move {
+            let tile_id
+                =pyramid_tile_id(level_to_zoom(coord.z, self.max_zoom), coord.x, coord.y)
+                .ok_or(FetchError::Missing)?;
+            let entry
+                =self.find_tile(tile_id).await?
+                .ok_or(FetchError::Missing)?;
+            Ok(decompress(
+                self.reader.read_range(self.header.data_offset + entry.offset, entry.length as u64).await?,
+                self.header.tile_compression,
+                self.info.as_ref().map_or(MAX_INTERNAL_SIZE, |i| i.codec.format.raw_size())
+            )?)
+        })
+    }
+    fn dataset_info(&self) -> Option<DatasetInfo> {
+        self.info.clone()
+    }
+    fn list_tiles(&self) -> SourceFuture<'_, Manifest> {
+        Box::pin(async move {
+            let mut res = vec![];
+            self.collect_entries(&self.root[..], 0, &mut res).await?;
+            Ok(Manifest::from_coords(res))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("tiler-{}-{}", std::process::id(), name)).to_string_lossy().into_owned()
+    }
+
+    fn sink(path: &str) -> PMTilesSink {
+        let codec = ImageCodec::srtm();
+        let tilespace = Tilespace { size: codec.format.size, offset: IVec2::ZERO, georef: None };
+        PMTilesSink::create(path, 2, codec, tilespace, ImageFiletype::Raw).unwrap()
+    }
+
+    #[test]
+    fn numbers_tiles_along_the_hilbert_curve() {
+        assert_eq!(zxy_to_tile_id(0, 0, 0), 0);
+        // zoom 1 from the spec: (0, 0), (0, 1), (1, 1), (1, 0)
+        assert_eq!([(0, 0), (0, 1), (1, 1), (1, 0)].iter().map(|&(x, y)| zxy_to_tile_id(1, x, y)).collect::<Vec<_>>(), vec![1, 2, 3, 4]);
+        assert_eq!(zxy_to_tile_id(2, 0, 0), 5);
+        for z in 0..=6u8 {
+            for x in 0..1u64 << z {
+                for y in 0..1u64 << z {
+                    assert_eq!(tile_id_to_zxy(zxy_to_tile_id(z, x, y)), Some((z, x, y)));
+                }
+            }
+        }
+        assert_eq!(tile_id_to_zxy(zxy_to_tile_id(31, (1 << 31) - 1, 5)), Some((31, (1 << 31) - 1, 5)));
+        assert_eq!(tile_id_to_zxy(u64::MAX), None);
+    }
+
+    #[test]
+    fn rejects_tiles_outside_the_pyramid() {
+        assert_eq!(pyramid_tile_id(1, 1, 1), Some(3));
+        assert_eq!(pyramid_tile_id(1, 2, 0), None);
+        assert_eq!(pyramid_tile_id(1, 0, -1), None);
+        assert_eq!(pyramid_tile_id(-1, 0, 0), None);
+        assert_eq!(pyramid_tile_id(32, 0, 0), None);
+
+        let path = temp_path("outside.pmtiles");
+        let sink = sink(path.as_str());
+        // level 1 is zoom 1, which has 2x2 tiles
+        assert!(sink.write_tile(ivec3(2, 0, 1), b"a").is_err());
+        assert!(sink.write_tile(ivec3(0, 0, 3), b"a").is_err());
+        assert!(sink.write_tile(ivec3(1, 1, 1), b"a").is_ok());
+    }
+
+    #[test]
+    fn directories_round_trip() {
+        let entries = vec![
+            Entry { tile_id: 0, offset: 0, length: 10, run_length: 1 },
+            Entry { tile_id: 1, offset: 10, length: 5, run_length: 3 },
+            Entry { tile_id: 7, offset: 0, length: 10, run_length: 1 },
+            Entry { tile_id: 1000, offset: 400, length: 1, run_length: 0 }
+        ];
+        let data = decompress(serialize_directory(&entries[..]).unwrap(), COMPRESSION_GZIP, MAX_INTERNAL_SIZE).unwrap();
+        assert_eq!(deserialize_directory(&data[..]).unwrap(), entries);
+
+        // a count larger than the directory could hold
+        let mut data = vec![];
+        write_varint(&mut data, 1 << 40);
+        assert!(deserialize_directory(&data[..]).is_err());
+    }
+
+    #[test]
+    fn stops_decompressing_at_the_limit() {
+        let data = gzip(&vec![0; 1000][..]).unwrap();
+        assert_eq!(decompress(data.clone(), COMPRESSION_GZIP, 1000).unwrap().len(), 1000);
+        assert!(decompress(data, COMPRESSION_GZIP, 999).is_err());
+        assert!(decompress(vec![0; 10], COMPRESSION_NONE, 9).is_err());
+
+        assert_eq!(pyramid_tile_count(-1), 0);
+        assert_eq!(pyramid_tile_count(0), 1);
+        assert_eq!(pyramid_tile_count(2), 21);
+        assert_eq!(tile_id_to_zxy(pyramid_tile_count(MAX_ZOOM)), None);
+        assert!(tile_id_to_zxy(pyramid_tile_count(MAX_ZOOM) - 1).is_some());
+    }
+
+    #[tokio::test]
+    async fn archives_round_trip() {
+        let path = temp_path("round-trip.pmtiles");
+        let sink = sink(path.as_str());
+        let tiles = [
+            (ivec3(0, 0, 2), b"top".to_vec()),
+            (ivec3(0, 0, 1), b"same".to_vec()),
+            (ivec3(1, 0, 1), b"same".to_vec()),
+            (ivec3(3, 2, 0), b"bottom".to_vec())
+        ];
+        for (coord, data) in tiles.iter() {
+            sink.write_tile(*coord, &data[..]).unwrap();
+        }
+        sink.finish().unwrap();
+
+        let source = PMTilesTileSource::open(path.as_str(), None, HttpClient::shared()).await.unwrap();
+        assert_eq!((source.header.min_zoom, source.header.max_zoom), (0, 2));
+        // the two identical tiles share their contents
+        assert_eq!(source.header.tile_contents, 3);
+        assert_eq!(source.max_zoom, 2);
+
+        let manifest = source.list_tiles().await.unwrap();
+        assert_eq!(manifest.len(), tiles.len());
+        for (coord, data) in tiles.iter() {
+            assert!(manifest.contains(*coord));
+            assert_eq!(source.fetch_tile(*coord).await.unwrap(), *data);
+        }
+        assert_eq!(source.fetch_tile(ivec3(1, 1, 1)).await, Err(FetchError::Missing));
+        assert_eq!(source.fetch_tile(ivec3(5, 0, 1)).await, Err(FetchError::Missing));
+        let _ = fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn writes_empty_archives() {
+        let path = temp_path("empty.pmtiles");
+        let sink = sink(path.as_str());
+        sink.finish().unwrap();
+
+        let source = PMTilesTileSource::open(path.as_str(), None, HttpClient::shared()).await.unwrap();
+        assert_eq!((source.header.min_zoom, source.header.max_zoom), (0, 0));
+        assert!(source.list_tiles().await.unwrap().is_empty());
+        let _ = fs::remove_file(path);
+    }
+}
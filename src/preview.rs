@@ -1,43 +1,135 @@
 use std::io::Cursor;
 use glam::*;
 use num::*;
+use serde::{Serialize, Deserialize};
 use crate::image::*;
+use crate::config::DatasetProvider;
+use crate::tile_source::FetchError;
+use crate::util::math::Dabb2;
 use ::image as image_ext;
 
-pub fn color_map(scalar: f32) -> Vec3 {
-    // From "Why we use bad color maps and what you can do about it" (Kenneth Moreland)
-    // Page 5, Figure 8
-    let colors = [
-        vec3(  0.0,   0.0,   0.0) / 255.0,
-        vec3(  0.0,  24.0, 168.0) / 255.0,
-        vec3( 99.0,   0.0, 228.0) / 255.0,
-        vec3(220.0,  20.0,  60.0) / 255.0,
-        vec3(255.0, 117.0,  56.0) / 255.0,
-        vec3(238.0, 210.0,  20.0) / 255.0,
-        vec3(255.0, 255.0, 255.0) / 255.0
-    ];
-    let positions = [
-        0.0,
-        0.22,
-        0.35,
-        0.47,
-        0.65,
-        0.84,
-        1.0
-    ];
-    assert!(positions.len() == colors.len());
-    if scalar <= 0.0 {
-        return colors[0];
-    }
-    if scalar >= 1.0 {
-        return colors[colors.len() - 1];
-    }
-    for i in 0..colors.len() {
-        if scalar >= positions[i] && scalar < positions[i + 1] {
-            return colors[i].lerp(colors[i + 1], (scalar - positions[i]) / (positions[i + 1] - positions[i]));
+// From "Why we use bad color maps and what you can do about it" (Kenneth Moreland)
+// Page 5, Figure 8
+const MORELAND: [(f32, [f32; 3]); 7] = [
+    (0.0 , [  0.0,   0.0,   0.0]),
+    (0.22, [  0.0,  24.0, 168.0]),
+    (0.35, [ 99.0,   0.0, 228.0]),
+    (0.47, [220.0,  20.0,  60.0]),
+    (0.65, [255.0, 117.0,  56.0]),
+    (0.84, [238.0, 210.0,  20.0]),
+    (1.0 , [255.0, 255.0, 255.0])
+];
+
+// Sampled from matplotlib's viridis
+const VIRIDIS: [(f32, [f32; 3]); 9] = [
+    (0.0  , [ 68.0,   1.0,  84.0]),
+    (0.125, [ 71.0,  44.0, 122.0]),
+    (0.25 , [ 59.0,  81.0, 139.0]),
+    (0.375, [ 44.0, 113.0, 142.0]),
+    (0.5  , [ 33.0, 144.0, 141.0]),
+    (0.625, [ 39.0, 173.0, 129.0]),
+    (0.75 , [ 92.0, 200.0,  99.0]),
+    (0.875, [170.0, 220.0,  50.0]),
+    (1.0  , [253.0, 231.0,  37.0])
+];
+
+// Sea, lowland green, highland brown and snow, after matplotlib's terrain
+const TERRAIN: [(f32, [f32; 3]); 6] = [
+    (0.0 , [ 51.0,  51.0, 153.0]),
+    (0.15, [  0.0, 153.0, 255.0]),
+    (0.25, [  0.0, 204.0, 102.0]),
+    (0.5 , [255.0, 255.0, 153.0]),
+    (0.75, [128.0,  92.0,  84.0]),
+    (1.0 , [255.0, 255.0, 255.0])
+];
+
+const GREYSCALE: [(f32, [f32; 3]); 2] = [
+    (0.0, [  0.0,   0.0,   0.0]),
+    (1.0, [255.0, 255.0, 255.0])
+];
+
+// Blue to red through grey, for values either side of a midpoint. Moreland's cool to warm
+const DIVERGING: [(f32, [f32; 3]); 3] = [
+    (0.0, [ 59.0,  76.0, 192.0]),
+    (0.5, [221.0, 221.0, 221.0]),
+    (1.0, [180.0,   4.0,  38.0])
+];
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NamedColorMap {
+    Moreland, Viridis, Terrain, Greyscale, Diverging
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct ColorStop {
+    // 0 to 1 along the range
+    pub position: f32,
+    // 0 to 255 per channel
+    pub color: Vec3
+}
+
+// A name like "viridis" or a list of stops in increasing position
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum ColorMap {
+    Named(NamedColorMap),
+    Stops(Vec<ColorStop>)
+}
+
+impl Default for ColorMap {
+    fn default() -> Self {
+        ColorMap::Named(NamedColorMap::Moreland)
+    }
+}
+
+fn gradient(stops: &[(f32, [f32; 3])], scalar: f32) -> Vec3 {
+    let color = |i: usize| Vec3::from(stops[i].1) / 255.0;
+    if scalar <= stops[0].0 {
+        return color(0);
+    }
+    for i in 0..stops.len() - 1 {
+        if scalar >= stops[i].0 && scalar < stops[i + 1].0 {
+            return color(i).lerp(color(i + 1), (scalar - stops[i].0) / (stops[i + 1].0 - stops[i].0));
+        }
+    }
+    color(stops.len() - 1)
+}
+
+impl ColorMap {
+    pub fn validate(&self) -> Result<(), String> {
+        if let ColorMap::Stops(stops) = self {
+            if stops.len() < 2 {
+                return Err("A color map needs at least two stops".to_string());
+            }
+            if stops.windows(2).any(|w| w[1].position < w[0].position) {
+                return Err("Color map stops have to be in increasing position".to_string());
+            }
+            if stops.iter().any(|s| !(0.0..=1.0).contains(&s.position)) {
+                return Err("Color map stop positions have to be between 0 and 1".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    // 0 to 1 per channel, scalar is clamped to the ends of the map
+    pub fn sample(&self, scalar: f32) -> Vec3 {
+        match self {
+            ColorMap::Named(NamedColorMap::Moreland) => gradient(&MORELAND, scalar),
+            ColorMap::Named(NamedColorMap::Viridis) => gradient(&VIRIDIS, scalar),
+            ColorMap::Named(NamedColorMap::Terrain) => gradient(&TERRAIN, scalar),
+            ColorMap::Named(NamedColorMap::Greyscale) => gradient(&GREYSCALE, scalar),
+            ColorMap::Named(NamedColorMap::Diverging) => gradient(&DIVERGING, scalar),
+            ColorMap::Stops(stops) => {
+                let stops: Vec<(f32, [f32; 3])> = stops.iter().map(|s| (s.position, s.color.to_array())).collect();
+                gradient(&stops[..], scalar)
+            }
         }
     }
-    return colors[colors.len() - 1];
+}
+
+pub fn color_map(scalar: f32) -> Vec3 {
+    gradient(&MORELAND, scalar)
 }
 
 fn to_rgb_u8(color: Vec3) -> UVec3 {
@@ -56,19 +148,50 @@ pub fn transform_pixel(val: i16, min: f32, inv_range: f32) -> (u8, u8, u8) {
     (colorized.x as u8, colorized.y as u8, colorized.z as u8)
 }
 
+// How the value range of a preview is found when it isn't given
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Stretch {
+    #[default]
+    MinMax,
+    // ignores the given percent of samples at either end, so outliers don't flatten the rest
+    Percentile { low: f32, high: f32 }
+}
+
+// None without samples
+pub fn stretch_range(values: &[f32], stretch: Stretch) -> Option<Vec2> {
+    let mut values: Vec<f32> = values.iter().copied().filter(|v| v.is_finite()).collect();
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let at = |percent: f32| values[((percent.clamp(0.0, 100.0) / 100.0 * (values.len() - 1) as f32).round()) as usize];
+    let range = match stretch {
+        Stretch::MinMax => vec2(values[0], values[values.len() - 1]),
+        Stretch::Percentile { low, high } => vec2(at(low), at(100.0 - high))
+    };
+    // a flat tile would divide by zero
+    match range.x < range.y {
+        true  => Some(range),
+        false => Some(vec2(range.x, range.x + 1.0))
+    }
+}
+
 pub fn make_preview(image: &impl Image, min: f32, max: f32) -> Option<ImageOwned> {
-    let data = image.backing();
+    make_preview_mapped(image, min, max, &ColorMap::default())
+}
 
-    let mut res_data = vec![0u8; data.len() / 2 * 3];
+pub fn make_preview_mapped(image: &impl Image, min: f32, max: f32, map: &ColorMap) -> Option<ImageOwned> {
+    let values = sample_values(image).ok()?;
+
+    let mut res_data = vec![0u8; values.len() * 3];
 
     let inv_range = 1.0  / (max - min);
-    for i in 0..(data.len() / 2) {
-        let val: i16 = (data[i * 2] as i16) << 8 | (data[i * 2 + 1] as i16);
-        
-        let (r, g, b) = transform_pixel(val, min, inv_range);
-        res_data[i * 3] = r;
-        res_data[i * 3 + 1] = g;
-        res_data[i * 3 + 2] = b;
+    for (i, val) in values.iter().enumerate() {
+        let colorized = to_rgb_u8(map.sample((val - min) * inv_range));
+        res_data[i * 3] = colorized.x as u8;
+        res_data[i * 3 + 1] = colorized.y as u8;
+        res_data[i * 3 + 2] = colorized.z as u8;
     }
 
     let a = image_ext::RgbImage::from_raw(image.get_format().size.x as u32, image.get_format().size.y as u32, res_data)?;
@@ -84,4 +207,210 @@ pub fn make_preview(image: &impl Image, min: f32, max: f32) -> Option<ImageOwned
         },
         data: asdf.into_inner()
     })
-}
\ No newline at end of file
+}
+
+// Gradient from min to max, left to right or bottom to top when vertical. Holds PNG bytes like make_preview
+pub fn make_legend(map: &ColorMap, size: IVec2, vertical: bool) -> Option<ImageOwned> {
+    let mut res_data = vec![0u8; size.x.max(0) as usize * size.y.max(0) as usize * 3];
+    for y in 0..size.y {
+        for x in 0..size.x {
+            let scalar = match vertical {
+                true  => 1.0 - (y as f32 + 0.5) / size.y as f32,
+                false => (x as f32 + 0.5) / size.x as f32
+            };
+            let colorized = to_rgb_u8(map.sample(scalar));
+            let i = (y * size.x + x) as usize * 3;
+            res_data[i] = colorized.x as u8;
+            res_data[i + 1] = colorized.y as u8;
+            res_data[i + 2] = colorized.z as u8;
+        }
+    }
+
+    let d = image_ext::DynamicImage::ImageRgb8(image_ext::RgbImage::from_raw(size.x as u32, size.y as u32, res_data)?);
+    let mut png = Cursor::new(vec![]);
+    d.write_to(&mut png, image_ext::ImageOutputFormat::Png).ok()?;
+
+    Some(ImageOwned {
+        format: ImageFormat {
+            encoding: PixelEncoding::color(),
+            size
+        },
+        data: png.into_inner()
+    })
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[serde(default)]
+pub struct HillshadeOptions {
+    // degrees clockwise from north
+    pub azimuth: f32,
+    // degrees above the horizon
+    pub altitude: f32,
+    // elevation multiplier, exaggerates relief above 1
+    pub z_factor: f32,
+    // averages light from four azimuths around azimuth, fewer slopes end up in full shadow
+    pub multidirectional: bool,
+    // shades the colour ramp instead of rendering grey
+    pub blend: bool,
+    // ground size of a pixel in elevation units, derived from the dataset's georeference when not given
+    pub cell_size: Option<f32>
+}
+
+impl Default for HillshadeOptions {
+    fn default() -> Self {
+        HillshadeOptions {
+            azimuth: 315.0,
+            altitude: 45.0,
+            z_factor: 1.0,
+            multidirectional: false,
+            blend: false,
+            cell_size: None
+        }
+    }
+}
+
+// First channel of every pixel, samples are native endian unless the encoding says they're swapped
+pub fn sample_values(image: &impl Image) -> Result<Vec<f32>, String> {
+    let encoding = image.get_format().encoding;
+    let read = sample_reader(encoding)?;
+    Ok(image.backing().chunks_exact(encoding.pixel_size()).map(|px| read(px) as f32).collect())
+}
+
+// Horn's method, 0 in full shadow to 1 facing the sun
+fn shade(window: [f32; 9], cell_size: Vec2, options: &HillshadeOptions) -> f32 {
+    let [a, b, c, d, _, f, g, h, i] = window;
+    let dzdx = ((c + 2.0 * f + i) - (a + 2.0 * d + g)) / (8.0 * cell_size.x);
+    let dzdy = ((g + 2.0 * h + i) - (a + 2.0 * b + c)) / (8.0 * cell_size.y);
+    let slope = (options.z_factor * (dzdx * dzdx + dzdy * dzdy).sqrt()).atan();
+    let aspect = dzdy.atan2(-dzdx);
+    let zenith = (90.0 - options.altitude).to_radians();
+
+    let lit = |azimuth: f32| {
+        let azimuth = (450.0 - azimuth).to_radians();
+        (zenith.cos() * slope.cos() + zenith.sin() * slope.sin() * (azimuth - aspect).cos()).max(0.0)
+    };
+    match options.multidirectional {
+        true  => [-45.0, 0.0, 45.0, 90.0].iter().map(|offset| lit(options.azimuth + offset)).sum::<f32>() / 4.0,
+        false => lit(options.azimuth)
+    }
+}
+
+// elevation has a one pixel border on every side of size. Same as make_preview, the result holds PNG bytes
+pub fn make_hillshade(elevation: &[f32], size: IVec2, cell_size: Vec2, options: &HillshadeOptions, min: f32, max: f32, map: &ColorMap) -> Option<ImageOwned> {
+    let stride = size.x as usize + 2;
+    if elevation.len() != stride * (size.y as usize + 2) {
+        return None;
+    }
+    let channels = if options.blend { 3 } else { 1 };
+    let mut res_data = vec![0u8; size.x as usize * size.y as usize * channels];
+
+    let inv_range = 1.0 / (max - min);
+    for y in 0..size.y as usize {
+        for x in 0..size.x as usize {
+            let at = |dx: usize, dy: usize| elevation[(y + dy) * stride + x + dx];
+            let window = [
+                at(0, 0), at(1, 0), at(2, 0),
+                at(0, 1), at(1, 1), at(2, 1),
+                at(0, 2), at(1, 2), at(2, 2)
+            ];
+            let lit = shade(window, cell_size, options);
+            let i = (y * size.x as usize + x) * channels;
+            match options.blend {
+                true => {
+                    let colour = to_rgb_u8(map.sample((window[4] - min) * inv_range) * lit);
+                    res_data[i] = colour.x as u8;
+                    res_data[i + 1] = colour.y as u8;
+                    res_data[i + 2] = colour.z as u8;
+                },
+                false => res_data[i] = clamp(lit * 255.0, 0.0, 255.0) as u8
+            }
+        }
+    }
+
+    let d = match options.blend {
+        true  => image_ext::DynamicImage::ImageRgb8(image_ext::RgbImage::from_raw(size.x as u32, size.y as u32, res_data)?),
+        false => image_ext::DynamicImage::ImageLuma8(image_ext::GrayImage::from_raw(size.x as u32, size.y as u32, res_data)?)
+    };
+    let mut png = Cursor::new(vec![]);
+    d.write_to(&mut png, image_ext::ImageOutputFormat::Png).ok()?;
+
+    Some(ImageOwned {
+        format: ImageFormat {
+            encoding: PixelEncoding { channels: channels as i32, ..PixelEncoding::color() },
+            size
+        },
+        data: png.into_inner()
+    })
+}
+
+// Hillshade of a tile with the border taken from its neighbours so slopes are continuous across tiles,
+// where the dataset has no neighbour the tile's own edge is repeated
+pub async fn render_hillshade_tile(dp: &mut DatasetProvider, coord: IVec3, options: &HillshadeOptions, min: f32, max: f32, map: &ColorMap) -> Result<ImageOwned, FetchError> {
+    let tile = dp.tile_region(coord);
+    let padded = Dabb2::bounds(tile.begin - 1, tile.end + 1);
+    let (image, covered) = dp.read_region_covered(padded, coord.z).await?;
+    let inside = |p: IVec2, r: &Dabb2| p.cmpge(r.begin).all() && p.cmplt(r.end).all();
+    if !covered.iter().any(|r| inside(tile.begin, r)) {
+        return Err(FetchError::Missing);
+    }
+
+    let values = sample_values(&image).map_err(FetchError::Invalid)?;
+    let size = padded.end - padded.begin;
+    let index = |p: IVec2| ((p.y - padded.begin.y) * size.x + p.x - padded.begin.x) as usize;
+    let mut elevation = values.clone();
+    for p in &padded {
+        if !covered.iter().any(|r| inside(p, r)) {
+            elevation[index(p)] = values[index(p.clamp(tile.begin, tile.end - 1))];
+        }
+    }
+
+    let cell_size = match options.cell_size {
+        Some(cell_size) => Vec2::splat(cell_size),
+        None => dp.region_georef(padded, coord.z)
+            .map(|georef| georef.pixel_size_meters(size.as_dvec2() / 2.0).as_vec2())
+            .unwrap_or(Vec2::ONE)
+    };
+    make_hillshade(&elevation[..], tile.end - tile.begin, cell_size, options, min, max, map)
+    .ok_or_else(|| FetchError::Invalid(format!("Couldn't render hillshade of {:?}", coord)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2x2 .hgt samples as the file stores them, big endian: 1000, -5, 0 and 8848
+    const HGT: [u8; 8] = [0x03, 0xe8, 0xff, 0xfb, 0x00, 0x00, 0x22, 0x90];
+
+    // samples are read in the byte order the codec gives, a dataset of .hgt files says they're big endian
+    fn srtm_codec(filetype: ImageFiletype) -> ImageCodec {
+        let encoding = PixelEncoding { swap_endian: cfg!(target_endian = "little"), ..PixelEncoding::srtm() };
+        ImageCodec {
+            format: ImageFormat { encoding, size: ivec2(2, 2) },
+            filetype
+        }
+    }
+
+    #[test]
+    fn reads_srtm_samples_big_endian() {
+        let image = ImageOwned::decode_new(srtm_codec(ImageFiletype::Raw), &HGT).unwrap();
+        assert_eq!(sample_values(&image).unwrap(), vec![1000.0, -5.0, 0.0, 8848.0]);
+    }
+
+    #[test]
+    fn srtm_samples_survive_png() {
+        let image = ImageOwned::decode_new(srtm_codec(ImageFiletype::Raw), &HGT).unwrap();
+        let png = image.compress(ImageFiletype::PNG).unwrap();
+        let decoded = ImageOwned::decode_new(srtm_codec(ImageFiletype::PNG), &png[..]).unwrap();
+        assert_eq!(decoded.data, image.data);
+    }
+
+    #[test]
+    fn previews_srtm_samples_by_value() {
+        let image = ImageOwned::decode_new(srtm_codec(ImageFiletype::Raw), &HGT).unwrap();
+        let map = ColorMap::Named(NamedColorMap::Greyscale);
+        let preview = make_preview_mapped(&image, 0.0, 1000.0, &map).unwrap();
+        let rgb = image_ext::load_from_memory(&preview.data[..]).unwrap().into_rgb8().into_raw();
+        // 1000 at the top of the range, -5 clamped to the bottom
+        assert_eq!(&rgb[..6], &[255, 255, 255, 0, 0, 0]);
+    }
+}
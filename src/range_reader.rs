@@ -0,0 +1,56 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::network_util::*;
+use crate::tile_source::SourceFuture;
+
+// Random access into a single large file, either local or behind HTTP range requests
+pub trait RangeReader: Send + Sync + fmt::Debug {
+    fn read_range(&self, offset: u64, length: u64) -> SourceFuture<'_, Vec<u8>>;
+}
+
+pub fn open_range_reader(uri: &str, client: HttpClient) -> Box<dyn RangeReader> {
+    match local_path(uri) {
+        Some(path) => Box::new(FileRangeReader { path: path.to_string_lossy().into_owned() }),
+        None => Box::new(HttpRangeReader { uri: uri.to_string(), client })
+    }
+}
+
+#[derive(Debug)]
+pub struct FileRangeReader {
+    pub path: String
+}
+
+impl RangeReader for FileRangeReader {
+    fn read_range(&self, offset: u64, length: u64) -> SourceFuture<'_, Vec<u8>> {
+        let path = self.path.clone();
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || {
+                let mut file = File::open(path.as_str()).map_err(|e| format!("{}: {}", path, e))?;
+                file.seek(SeekFrom::Start(offset)).map_err(|e| e.to_string())?;
+                let mut res = Vec::with_capacity(length as usize);
+                file.take(length).read_to_end(&mut res).map_err(|e| e.to_string())?;
+                if res.len() as u64 != length {
+                    return Err(format!("{}: range {}+{} is past the end of the file", path, offset, length));
+                }
+                Ok(res)
+            }).await.map_err(|e| e.to_string())?
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct HttpRangeReader {
+    pub uri: String,
+    client: HttpClient
+}
+
+impl RangeReader for HttpRangeReader {
+    fn read_range(&self, offset: u64, length: u64) -> SourceFuture<'_, Vec<u8>> {
+        Box::pin(async move {
+            self.client.get_range(self.uri.as_str(), offset, length).await
+            .map_err(|e| format!("{}: {}", self.uri, e))
+        })
+    }
+}
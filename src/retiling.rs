@@ -2,12 +2,14 @@ use crate::image::Image;
 use crate::util::math::*;
 use crate::config::*;
 use crate::dataset_writer::*;
-use crate::dataset::*;
 
 use glam::*;
 use serde::{Serialize, Deserialize};
 use std::vec::Vec;
+use std::fs;
+use std::time::Instant;
 use crate::sample_accumulator::*;
+use crate::tile_source::FetchError;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SampleRegion {
@@ -21,15 +23,103 @@ pub struct Job {
     pub sample_regions: Vec<SampleRegion>
 }
 
-async fn add_samples_templated<T>(dp: &mut DatasetProvider, dw: &DatasetWriter, job: &Job, samples: &mut SampleAccumulator)
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RetilingSummary {
+    pub jobs: usize,
+    pub tiles_written: usize,
+    // input tiles the source doesn't have, expected at dataset edges
+    pub tiles_missing: usize,
+    // input tiles that couldn't be fetched or decoded, the output tiles depending on them were not written
+    pub failures: Vec<(IVec3, String)>,
+    pub failed_outputs: Vec<IVec3>,
+    // fetches that didn't match the manifest's checksums, tiles that never matched are also in failures
+    pub corrupt_fetches: usize,
+    // stopped by the observer before every job ran
+    #[serde(default)]
+    pub cancelled: bool
+}
+
+// Where a run is at, handed to observers before the first job and after every job
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct RetilingProgress {
+    pub jobs_total: usize,
+    pub jobs_done: usize,
+    pub tiles_written: usize,
+    pub tiles_missing: usize,
+    pub failures: usize,
+    pub tiles_fetched: usize,
+    pub bytes_fetched: u64,
+    pub elapsed_secs: f64,
+    // extrapolated from the pace so far, None until a job is done
+    pub eta_secs: Option<f64>
+}
+
+impl RetilingProgress {
+    pub fn of(summary: &RetilingSummary, jobs_total: usize, dp: &DatasetProvider, fetched_before: (usize, u64), start: Instant) -> Self {
+        let elapsed_secs = start.elapsed().as_secs_f64();
+        RetilingProgress {
+            jobs_total,
+            jobs_done: summary.jobs,
+            tiles_written: summary.tiles_written,
+            tiles_missing: summary.tiles_missing,
+            failures: summary.failures.len(),
+            tiles_fetched: dp.tiles_fetched - fetched_before.0,
+            bytes_fetched: dp.bytes_fetched - fetched_before.1,
+            elapsed_secs,
+            eta_secs: match summary.jobs {
+                0 => None,
+                done => Some(elapsed_secs / done as f64 * (jobs_total - done) as f64)
+            }
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.jobs_done >= self.jobs_total
+    }
+
+    // One line for a terminal, like [#####     ] 50/100 jobs, 48 tiles written, 120 fetched (1.2 MB), 0 failures, ETA 0:12
+    pub fn bar(&self, width: usize) -> String {
+        let filled = match self.jobs_total {
+            0 => width,
+            total => width * self.jobs_done / total
+        };
+        let eta = match self.eta_secs {
+            Some(secs) => format!("{}:{:02}", secs as u64 / 60, secs as u64 % 60),
+            None => "-".to_string()
+        };
+        format!(
+            "[{}{}] {}/{} jobs, {} tiles written, {} fetched ({:.1} MB), {} failures, ETA {}",
+            "#".repeat(filled), " ".repeat(width - filled),
+            self.jobs_done, self.jobs_total, self.tiles_written, self.tiles_fetched,
+            self.bytes_fetched as f64 / 1e6, self.failures, eta
+        )
+    }
+}
+
+// Returning false stops the run
+pub type RetilingObserver<'a> = dyn FnMut(&RetilingProgress) -> bool + Send + 'a;
+
+async fn add_samples_templated<T>(dp: &mut DatasetProvider, dw: &DatasetWriter, job: &Job, samples: &mut SampleAccumulator, summary: &mut RetilingSummary)
     where T: num::NumCast + num::cast::AsPrimitive<i64> + num::Integer {
     let output_pixel_begin = dw.tilespace.tile_pixels_level(job.output_coord).begin;
+    let mut failed = false;
 
     for region in job.sample_regions.iter() {
         let divisor = 1 >> region.input_coord.z;
 
-        if let Err(_) = dp.cache_resource(region.input_coord).await {
-            continue;
+        match dp.cache_resource(region.input_coord).await {
+            Ok(()) => {},
+            Err(FetchError::Missing) => {
+                summary.tiles_missing += 1;
+                continue;
+            },
+            Err(e) => {
+                println!("Failed to fetch {:?}: {}", region.input_coord, e);
+                summary.failures.push((region.input_coord, e.to_string()));
+                failed = true;
+                continue;
+            }
         }
 
         // ugh. Limitation of 
@@ -48,35 +138,62 @@ async fn add_samples_templated<T>(dp: &mut DatasetProvider, dw: &DatasetWriter,
         }
     }
 
+    // A partial tile would look valid, leave it out so a rerun can fill it in
+    if failed {
+        summary.failed_outputs.push(job.output_coord);
+        return;
+    }
+
     if samples.num_samples != 0 {
-        println!("Generated {} with {} samples", dw.get_resource_uri(job.output_coord), samples.num_samples);
-        if let Err(str) = dw.write_tile(job.output_coord, &samples.resolve_templated::<T>(dw.codec.format.encoding)) {
-            println!("Unexpected tile write error: {}", str);
+        match dw.write_tile(job.output_coord, &samples.resolve_templated::<T>(dw.codec.format.encoding)) {
+            Ok(()) => summary.tiles_written += 1,
+            Err(str) => println!("Unexpected tile write error: {}", str)
         }
     }
 
     return;
 }
 
-pub async fn process_all_jobs_templated<T: num::NumCast + num::cast::AsPrimitive<i64> + num::Integer>(dp: &mut DatasetProvider, dw: &DatasetWriter, jobs: &Vec<Job>) {
+pub async fn process_all_jobs_templated<T: num::NumCast + num::cast::AsPrimitive<i64> + num::Integer>(dp: &mut DatasetProvider, dw: &DatasetWriter, jobs: &Vec<Job>, observer: &mut RetilingObserver<'_>) -> RetilingSummary {
     let mut samples = SampleAccumulator::new(dw.codec.format.size);
+    let mut summary = RetilingSummary::default();
+    let corrupt_fetches = dp.corrupt_fetches;
+    let fetched_before = (dp.tiles_fetched, dp.bytes_fetched);
+    let start = Instant::now();
+    // the first call lets observers show the total and cancel before anything is written
+    let mut proceed = observer(&RetilingProgress::of(&summary, jobs.len(), dp, fetched_before, start));
     for job in jobs.iter() {
-        add_samples_templated::<T>(dp, dw, job, &mut samples).await;
+        if !proceed {
+            summary.cancelled = true;
+            break;
+        }
+        add_samples_templated::<T>(dp, dw, job, &mut samples, &mut summary).await;
         samples.clear();
+        summary.jobs += 1;
+        proceed = observer(&RetilingProgress::of(&summary, jobs.len(), dp, fetched_before, start));
     }
+    if let Err(str) = dw.finish() {
+        println!("Unexpected dataset finish error: {}", str);
+    }
+    summary.corrupt_fetches = dp.corrupt_fetches - corrupt_fetches;
+    summary
+}
+
+pub async fn process_all_jobs(dp: &mut DatasetProvider, dw: &DatasetWriter, jobs: &Vec<Job>) -> RetilingSummary {
+    process_all_jobs_observed(dp, dw, jobs, &mut |_| true).await
 }
 
-pub async fn process_all_jobs(dp: &mut DatasetProvider, dw: &DatasetWriter, jobs: &Vec<Job>) {
+pub async fn process_all_jobs_observed(dp: &mut DatasetProvider, dw: &DatasetWriter, jobs: &Vec<Job>, observer: &mut RetilingObserver<'_>) -> RetilingSummary {
     let encoding = dw.codec.format.encoding;
     match (encoding.bit_depth, encoding.signed) {
-        (8 , true ) => process_all_jobs_templated::<i8 >(dp, dw, jobs).await,
-        (16, true ) => process_all_jobs_templated::<i16>(dp, dw, jobs).await,
-        (32, true ) => process_all_jobs_templated::<i32>(dp, dw, jobs).await,
-        (64, true ) => process_all_jobs_templated::<i64>(dp, dw, jobs).await,
-        (8 , false) => process_all_jobs_templated::<u8 >(dp, dw, jobs).await,
-        (16, false) => process_all_jobs_templated::<u16>(dp, dw, jobs).await,
-        (32, false) => process_all_jobs_templated::<u32>(dp, dw, jobs).await,
-        (64, false) => process_all_jobs_templated::<u64>(dp, dw, jobs).await,
+        (8 , true ) => process_all_jobs_templated::<i8 >(dp, dw, jobs, observer).await,
+        (16, true ) => process_all_jobs_templated::<i16>(dp, dw, jobs, observer).await,
+        (32, true ) => process_all_jobs_templated::<i32>(dp, dw, jobs, observer).await,
+        (64, true ) => process_all_jobs_templated::<i64>(dp, dw, jobs, observer).await,
+        (8 , false) => process_all_jobs_templated::<u8 >(dp, dw, jobs, observer).await,
+        (16, false) => process_all_jobs_templated::<u16>(dp, dw, jobs, observer).await,
+        (32, false) => process_all_jobs_templated::<u32>(dp, dw, jobs, observer).await,
+        (64, false) => process_all_jobs_templated::<u64>(dp, dw, jobs, observer).await,
         _ => panic!()
     }
 }
@@ -94,9 +211,7 @@ pub fn gen_jobs(dp: &DatasetProvider, dw: &DatasetWriter, pixel_region: Dabb2, b
                 .get_covered_tiles(out_pixel_region)
                 .into_iter()
                 .filter(|input_coord| {
-                    dp.manifest.iter().any(|coord| {
-                        *coord == ivec3(input_coord.x, input_coord.y, 0)
-                    })
+                    dp.manifest.contains(ivec3(input_coord.x, input_coord.y, 0))
                 })
                 .map(|input_coord| {
                     let input_texel_region = dp.tilespace.tile_pixels(input_coord);
@@ -116,4 +231,13 @@ pub fn gen_jobs(dp: &DatasetProvider, dw: &DatasetWriter, pixel_region: Dabb2, b
             }
         }).collect::<Vec<Job>>()
     }).collect()
-}
\ No newline at end of file
+}
+pub fn save_jobs(path: &str, jobs: &[Job]) -> Result<(), String> {
+    fs::write(path, serde_json::to_string(jobs).map_err(|e| e.to_string())?)
+    .map_err(|e| e.to_string())
+}
+
+pub fn load_jobs(path: &str) -> Result<Vec<Job>, String> {
+    let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    serde_json::from_str(text.as_str()).map_err(|e| e.to_string())
+}
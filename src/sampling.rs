@@ -0,0 +1,257 @@
+use serde::{Serialize, Deserialize};
+use glam::*;
+
+use crate::config::DatasetProvider;
+use crate::tile_source::FetchError;
+use crate::util::math::*;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Interpolation {
+    #[default]
+    Nearest,
+    // weighted over the four surrounding pixel centers, nodata neighbours are left out of the weights
+    Bilinear
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct PointSample {
+    pub lon: f64,
+    pub lat: f64,
+    // None where the dataset has no data
+    pub value: Option<f64>,
+    pub nodata: bool
+}
+
+impl PointSample {
+    fn new(geo: DVec2, value: Option<f64>) -> Self {
+        PointSample {
+            lon: geo.x,
+            lat: geo.y,
+            value,
+            nodata: value.is_none()
+        }
+    }
+}
+
+// Pixel of geo in the grid of level, continuous with pixel centers at .5
+fn level_pixel(dp: &DatasetProvider, geo: DVec2, level: i32) -> Result<DVec2, FetchError> {
+    let georef = dp.tilespace.georef.ok_or_else(|| FetchError::Invalid("Dataset has no georeference".to_string()))?;
+    Ok(georef.geo_to_pixel(geo) / 2f64.powi(level.max(0)))
+}
+
+pub async fn sample_point(dp: &mut DatasetProvider, geo: DVec2, level: i32, interpolation: Interpolation) -> Result<PointSample, FetchError> {
+    let pixel = level_pixel(dp, geo, level)?;
+    if !pixel.is_finite() {
+        return Ok(PointSample::new(geo, None));
+    }
+
+    let value = match interpolation {
+        Interpolation::Nearest => dp.pixel_value(pixel.floor().as_ivec2(), level).await?,
+        Interpolation::Bilinear => {
+            let centered = pixel - 0.5;
+            let base = centered.floor();
+            let t = centered - base;
+            let base = base.as_ivec2();
+
+            let mut sum = 0.0;
+            let mut weights = 0.0;
+            for (offset, weight) in [
+                (ivec2(0, 0), (1.0 - t.x) * (1.0 - t.y)),
+                (ivec2(1, 0), t.x * (1.0 - t.y)),
+                (ivec2(0, 1), (1.0 - t.x) * t.y),
+                (ivec2(1, 1), t.x * t.y)
+            ] {
+                if let Some(value) = dp.pixel_value(base + offset, level).await? {
+                    sum += value * weight;
+                    weights += weight;
+                }
+            }
+            // only neighbours with zero weight having data doesn't count as data
+            match weights > 0.0 {
+                true  => Some(sum / weights),
+                false => None
+            }
+        }
+    };
+    Ok(PointSample::new(geo, value))
+}
+
+// Samples in the order of points. They're looked up grouped by tile so the cache only has to hold a few tiles at a time
+pub async fn sample_points(dp: &mut DatasetProvider, points: &[DVec2], level: i32, interpolation: Interpolation) -> Result<Vec<PointSample>, FetchError> {
+    let size = dp.codec.format.size;
+    let mut order: Vec<(IVec2, usize)> = Vec::with_capacity(points.len());
+    for (i, geo) in points.iter().enumerate() {
+        let pixel = level_pixel(dp, *geo, level)?;
+        let tile = match pixel.is_finite() {
+            true  => pixel.floor().as_ivec2().floor_on_interval(size) / size,
+            false => IVec2::ZERO
+        };
+        order.push((tile, i));
+    }
+    order.sort_by_key(|(tile, i)| (tile.y, tile.x, *i));
+
+    let mut res = vec![None; points.len()];
+    for (_, i) in order {
+        res[i] = Some(sample_point(dp, points[i], level, interpolation).await?);
+    }
+    Ok(res.into_iter().flatten().collect())
+}
+
+const EARTH_RADIUS: f64 = 6_371_008.8;
+
+// A profile longer than this many samples is refused rather than allocated
+pub const MAX_PROFILE_SAMPLES: usize = 100_000;
+
+// Great circle distance in meters between two (lon, lat)
+pub fn haversine(a: DVec2, b: DVec2) -> f64 {
+    let (lat_a, lat_b) = (a.y.to_radians(), b.y.to_radians());
+    let d_lat = lat_b - lat_a;
+    let d_lon = (b.x - a.x).to_radians();
+    let h = (d_lat / 2.0).sin().powi(2) + lat_a.cos() * lat_b.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS * h.sqrt().min(1.0).asin()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GeoJsonLineString {
+    #[serde(rename = "type")]
+    pub kind: String,
+    // (lon, lat) with an optional altitude, which is ignored
+    pub coordinates: Vec<Vec<f64>>
+}
+
+// A list of (lon, lat), a GeoJSON LineString, or a GeoJSON Feature holding one
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ProfileLine {
+    Coords(Vec<DVec2>),
+    Geometry(GeoJsonLineString),
+    Feature {
+        geometry: GeoJsonLineString
+    }
+}
+
+impl ProfileLine {
+    pub fn vertices(&self) -> Result<Vec<DVec2>, String> {
+        let geometry = match self {
+            ProfileLine::Coords(coords) => return Ok(coords.clone()),
+            ProfileLine::Geometry(geometry) => geometry,
+            ProfileLine::Feature { geometry } => geometry
+        };
+        if geometry.kind != "LineString" {
+            return Err(format!("Expected a LineString but got a {}", geometry.kind));
+        }
+        geometry.coordinates.iter().map(|c| match c[..] {
+            [lon, lat, ..] => Ok(dvec2(lon, lat)),
+            _ => Err("LineString positions need a longitude and latitude".to_string())
+        }).collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProfileSpacing {
+    // distance between samples, the last sample is always at the end of the line
+    Meters(f64),
+    // evenly spread, including both ends
+    Samples(usize)
+}
+
+impl ProfileSpacing {
+    // (distance along the line, position) of every sample. Positions are interpolated in lon/lat within a segment
+    pub fn positions(&self, vertices: &[DVec2]) -> Result<Vec<(f64, DVec2)>, String> {
+        if vertices.len() < 2 {
+            return Err("A line needs at least two points".to_string());
+        }
+        let mut cumulative = vec![0.0];
+        for w in vertices.windows(2) {
+            cumulative.push(cumulative[cumulative.len() - 1] + haversine(w[0], w[1]));
+        }
+        let length = cumulative[cumulative.len() - 1];
+
+        let distances: Vec<f64> = match *self {
+            ProfileSpacing::Meters(meters) => {
+                if meters.is_nan() || meters <= 0.0 {
+                    return Err(format!("Spacing of {} meters has to be positive", meters));
+                }
+                let steps = (length / meters).ceil();
+                if steps >= MAX_PROFILE_SAMPLES as f64 {
+                    return Err(format!("A {:.0} meter line at {} meter spacing is more than {} samples", length, meters, MAX_PROFILE_SAMPLES));
+                }
+                (0..steps as usize).map(|i| i as f64 * meters).chain(std::iter::once(length)).collect()
+            },
+            ProfileSpacing::Samples(samples) => {
+                if !(2..=MAX_PROFILE_SAMPLES).contains(&samples) {
+                    return Err(format!("Sample count has to be between 2 and {}", MAX_PROFILE_SAMPLES));
+                }
+                (0..samples).map(|i| length * i as f64 / (samples - 1) as f64).collect()
+            }
+        };
+
+        let mut segment = 0;
+        Ok(distances.into_iter().map(|distance| {
+            while segment + 2 < cumulative.len() && cumulative[segment + 1] < distance {
+                segment += 1;
+            }
+            let span = cumulative[segment + 1] - cumulative[segment];
+            let t = match span > 0.0 {
+                true  => ((distance - cumulative[segment]) / span).clamp(0.0, 1.0),
+                false => 0.0
+            };
+            (distance, vertices[segment].lerp(vertices[segment + 1], t))
+        }).collect())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct ProfilePoint {
+    // meters from the start of the line
+    pub distance: f64,
+    pub lon: f64,
+    pub lat: f64,
+    pub elevation: Option<f64>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Profile {
+    pub points: Vec<ProfilePoint>,
+    // meters
+    pub length: f64,
+    // summed climbs and drops between consecutive samples with data
+    pub ascent: f64,
+    pub descent: f64
+}
+
+// Samples at positions from ProfileSpacing::positions, looked up through sample_points so a line across many tiles fetches each once
+pub async fn sample_profile(dp: &mut DatasetProvider, positions: &[(f64, DVec2)], level: i32, interpolation: Interpolation) -> Result<Profile, FetchError> {
+    let geo: Vec<DVec2> = positions.iter().map(|(_, geo)| *geo).collect();
+    let samples = sample_points(dp, &geo[..], level, interpolation).await?;
+
+    let mut ascent = 0.0;
+    let mut descent = 0.0;
+    let mut previous = None;
+    for sample in samples.iter() {
+        if let Some(value) = sample.value {
+            if let Some(previous) = previous {
+                let climb = value - previous;
+                match climb > 0.0 {
+                    true  => ascent += climb,
+                    false => descent -= climb
+                }
+            }
+            previous = Some(value);
+        }
+    }
+
+    Ok(Profile {
+        points: positions.iter().zip(samples.iter()).map(|((distance, geo), sample)| ProfilePoint {
+            distance: *distance,
+            lon: geo.x,
+            lat: geo.y,
+            elevation: sample.value
+        }).collect(),
+        length: positions.last().map(|(distance, _)| *distance).unwrap_or(0.0),
+        ascent,
+        descent
+    })
+}
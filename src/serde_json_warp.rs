@@ -1,6 +1,11 @@
 use serde::de;
 use warp::{reject, Filter, Rejection};
 
+// The query string is missing or isn't valid JSON for T
+#[derive(Debug)]
+pub struct InvalidQuery(pub String);
+impl reject::Reject for InvalidQuery {}
+
 pub fn query<T>() -> impl Filter<Extract = (T,), Error = Rejection> + Clone
 where T: de::DeserializeOwned + Send + 'static {
     warp::query::raw()
@@ -10,9 +15,9 @@ where T: de::DeserializeOwned + Send + 'static {
     .and_then(|query: String| async move {
         let decoded
         =urlencoding::decode(query.as_str())
-        .map_err(|_| { reject() })?;
+        .map_err(|err| { reject::custom(InvalidQuery(err.to_string())) })?;
         
         serde_json::from_str::<T>(decoded.into_owned().as_str())
-        .map_err(|_err| { reject() })
+        .map_err(|err| { reject::custom(InvalidQuery(err.to_string())) })
     })
 }
\ No newline at end of file
@@ -0,0 +1,151 @@
+use serde::{Serialize, Deserialize};
+use glam::*;
+use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::Mutex;
+
+use crate::config::DatasetProvider;
+use crate::contours::ContourOptions;
+use crate::http_cache::CachingConfig;
+use crate::image::ImageCodec;
+use crate::jobs::JobsConfig;
+use crate::preview::{ColorMap, HillshadeOptions};
+use crate::tile_source::TileSourceConfig;
+
+fn default_address() -> SocketAddr {
+    ([127, 0, 0, 1], 3000).into()
+}
+
+fn default_cache_tiles() -> usize {
+    64
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DatasetConfig {
+    pub source: TileSourceConfig,
+    // defaults to the codec the source describes itself with
+    pub codec: Option<ImageCodec>,
+    // decoded tiles kept in memory
+    #[serde(default = "default_cache_tiles")]
+    pub cache_tiles: usize,
+    // loaded at startup when present, see DatasetProvider::save_cache
+    pub cache_snapshot: Option<String>,
+    // z in tile URLs is a web zoom with this as level 0, defaults to the source's own max zoom. Without either z is the level
+    pub max_zoom: Option<i32>,
+    // sample range mapped onto the colour map for rendered tiles
+    #[serde(default)]
+    pub preview_range: Option<Vec2>,
+    #[serde(default)]
+    pub color_map: ColorMap,
+    // overrides the nodata value the source describes itself with
+    #[serde(default)]
+    pub nodata: Option<f64>,
+    // lighting for tiles under /hillshade
+    #[serde(default)]
+    pub hillshade: HillshadeOptions,
+    // lines of tiles under /contours, and the default for contours of regions
+    #[serde(default)]
+    pub contours: ContourOptions
+}
+
+impl DatasetConfig {
+    // A provider with its own cache, without the snapshot
+    pub async fn open_provider(&self) -> Result<DatasetProvider, String> {
+        let mut provider = match self.codec {
+            Some(codec) => DatasetProvider::open(&self.source, codec).await?,
+            None => DatasetProvider::open_described(&self.source).await?
+        };
+        provider.set_cache_capacity(self.cache_tiles.max(1));
+        provider.nodata = self.nodata.or(provider.nodata);
+        Ok(provider)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerConfig {
+    #[serde(default = "default_address")]
+    pub address: SocketAddr,
+    // how clients reach the server in TileJSON and WMTS documents, like https://tiles.example.com. Defaults to the Host header
+    #[serde(default)]
+    pub public_url: Option<String>,
+    // retiling over HTTP under /jobs, disabled without
+    #[serde(default)]
+    pub jobs: Option<JobsConfig>,
+    #[serde(default)]
+    pub caching: CachingConfig,
+    pub datasets: HashMap<String, DatasetConfig>
+}
+
+impl ServerConfig {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        serde_json::from_str(text.as_str()).map_err(|e| format!("{}: {}", path, e))
+    }
+}
+
+#[derive(Debug)]
+pub struct Dataset {
+    pub name: String,
+    pub config: DatasetConfig,
+    pub max_zoom: Option<i32>,
+    // Last-Modified of its tiles, they can't change while the server runs since the manifest is read once
+    pub opened: SystemTime,
+    // held across fetches, requests for the same dataset take turns on its cache
+    pub provider: Mutex<DatasetProvider>
+}
+
+impl Dataset {
+    pub async fn open(name: &str, config: &DatasetConfig) -> Result<Self, String> {
+        config.color_map.validate()?;
+        config.contours.validate()?;
+        let mut provider = config.open_provider().await?;
+
+        if let Some(path) = &config.cache_snapshot {
+            // a stale or missing snapshot only costs the warm start
+            if let Err(e) = provider.load_cache(path.as_str()) {
+                println!("Dataset {}: not loading cache snapshot {}: {}", name, path, e);
+            }
+        }
+
+        let max_zoom = config.max_zoom.or(provider.source.dataset_info().map(|info| info.max_zoom));
+
+        Ok(Dataset {
+            name: name.to_string(),
+            config: config.clone(),
+            max_zoom,
+            opened: SystemTime::now(),
+            provider: Mutex::new(provider)
+        })
+    }
+
+    // z in tile URLs for a level
+    pub fn url_zoom(&self, level: i32) -> i32 {
+        match self.max_zoom {
+            Some(max_zoom) => max_zoom - level,
+            None => level
+        }
+    }
+
+    // Dataset coordinate for a z/x/y in a tile URL
+    pub fn url_coord(&self, z: i32, x: i32, y: i32) -> IVec3 {
+        match self.max_zoom {
+            Some(max_zoom) => ivec3(x, y, max_zoom - z),
+            None => ivec3(x, y, z)
+        }
+    }
+}
+
+pub type Datasets = Arc<HashMap<String, Arc<Dataset>>>;
+
+pub async fn open_datasets(config: &ServerConfig) -> Result<Datasets, String> {
+    let mut res = HashMap::new();
+    for (name, dataset) in config.datasets.iter() {
+        let dataset = Dataset::open(name, dataset).await.map_err(|e| format!("Dataset {}: {}", name, e))?;
+        println!("Registered dataset {} with {} tiles", name, dataset.provider.lock().await.manifest.len());
+        res.insert(name.clone(), Arc::new(dataset));
+    }
+    Ok(Arc::new(res))
+}
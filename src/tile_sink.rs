@@ -0,0 +1,121 @@
+use serde::{Serialize, Deserialize};
+use glam::*;
+use std::fmt;
+use std::fs;
+
+use crate::dataset::*;
+use crate::image::{ImageCodec, ImageFiletype};
+use crate::mbtiles::MBTilesSink;
+use crate::pmtiles::PMTilesSink;
+use crate::util::math::*;
+
+// Where a DatasetWriter puts its encoded tiles
+pub trait TileSink: Send + Sync + fmt::Debug {
+    fn write_tile(&self, coord: IVec3, data: &[u8]) -> Result<(), String>;
+    // Called after a run, sinks that keep an index or metadata bring it up to date here
+    fn finish(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum TileSinkConfig {
+    // max_zoom makes z in the template a web zoom, see TileTemplate
+    Files {
+        tile_uri_format: String,
+        #[serde(default)]
+        max_zoom: Option<i32>
+    },
+    MBTiles {
+        path: String,
+        max_zoom: i32
+    },
+    PMTiles {
+        path: String,
+        max_zoom: i32
+    }
+}
+
+impl TileSinkConfig {
+    pub fn open(&self, codec: ImageCodec, tilespace: &Tilespace, filetype: ImageFiletype) -> Result<Box<dyn TileSink>, String> {
+        Ok(match self {
+            TileSinkConfig::Files { tile_uri_format, max_zoom } =>
+                Box::new(FileTileSink::new(TileTemplate::new(tile_uri_format)?.with_max_zoom(*max_zoom))),
+            TileSinkConfig::MBTiles { path, max_zoom } =>
+                Box::new(MBTilesSink::create(path, *max_zoom, codec, tilespace.clone(), filetype)?),
+            TileSinkConfig::PMTiles { path, max_zoom } =>
+                Box::new(PMTilesSink::create(path, *max_zoom, codec, tilespace.clone(), filetype)?),
+        })
+    }
+}
+
+// Zoom range and area covered by the tiles written so far, for archive metadata
+#[derive(Debug, Copy, Clone)]
+pub struct WrittenExtent {
+    pub min_zoom: i32,
+    pub max_zoom: i32,
+    // level 0 pixels
+    pub pixel_bounds: Option<Dabb2>
+}
+
+impl Default for WrittenExtent {
+    fn default() -> Self {
+        WrittenExtent {
+            min_zoom: i32::MAX,
+            max_zoom: i32::MIN,
+            pixel_bounds: None
+        }
+    }
+}
+
+impl WrittenExtent {
+    pub fn add(&mut self, coord: IVec3, zoom: i32, tilespace: &Tilespace) {
+        self.min_zoom = self.min_zoom.min(zoom);
+        self.max_zoom = self.max_zoom.max(zoom);
+        let pixels = tilespace.tile_pixels_level(coord);
+        self.pixel_bounds = Some(match self.pixel_bounds {
+            Some(b) => Dabb2::bounds(b.begin.min(pixels.begin), b.end.max(pixels.end)),
+            None => pixels
+        });
+    }
+    pub fn is_empty(&self) -> bool {
+        self.min_zoom > self.max_zoom
+    }
+}
+
+#[derive(Debug)]
+pub struct FileTileSink {
+    pub template: TileTemplate
+}
+
+impl FileTileSink {
+    pub fn new(template: TileTemplate) -> Self {
+        FileTileSink { template }
+    }
+}
+
+impl TileURIProvider for FileTileSink {
+    fn get_resource_uri(&self, coord: IVec3) -> Result<String, String> {
+        self.template.format(coord)
+    }
+}
+
+impl TileSink for FileTileSink {
+    fn write_tile(&self, coord: IVec3, data: &[u8]) -> Result<(), String> {
+        let path = self.get_resource_uri(coord)?;
+        // templates like {z}/{x}/{y}.png spread tiles over directories that don't exist yet
+        if let Some(parent) = std::path::Path::new(path.as_str()).parent() {
+            fs::create_dir_all(parent).map_err(|io_er| io_er.to_string())?;
+        }
+        fs::write(path, data)
+        .map_err(|io_er| io_er.to_string())
+    }
+}
+
+pub fn filetype_extension(filetype: ImageFiletype) -> &'static str {
+    match filetype {
+        ImageFiletype::Raw  => "raw",
+        ImageFiletype::PNG  => "png",
+        ImageFiletype::TIFF => "tiff"
+    }
+}
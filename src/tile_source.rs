@@ -0,0 +1,353 @@
+use serde::{Serialize, Deserialize};
+use glam::*;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::future::Future;
+use std::io::{Read, Seek, SeekFrom};
+use std::pin::Pin;
+
+use crate::dataset::*;
+use crate::network_util::*;
+use crate::mbtiles::MBTilesTileSource;
+use crate::pmtiles::PMTilesTileSource;
+use crate::cog::CogTileSource;
+use crate::manifest::*;
+
+pub type SourceFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, String>> + Send + 'a>>;
+pub type FetchFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, FetchError>> + Send + 'a>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FetchError {
+    // the source doesn't have this tile
+    Missing,
+    // the tile should be there but couldn't be retrieved
+    Failed(String),
+    // the server didn't answer in time, after retries
+    Timeout(String),
+    // the tile was retrieved but its bytes are unusable, e.g. a checksum mismatch or a decode failure
+    Invalid(String)
+}
+
+impl FetchError {
+    // Why the fetch failed, None for missing tiles
+    pub fn reason(&self) -> Option<&str> {
+        match self {
+            FetchError::Missing => None,
+            FetchError::Failed(reason) | FetchError::Timeout(reason) | FetchError::Invalid(reason) => Some(reason.as_str())
+        }
+    }
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FetchError::Missing => write!(f, "tile is missing"),
+            FetchError::Failed(reason) | FetchError::Invalid(reason) => write!(f, "{}", reason),
+            FetchError::Timeout(reason) => write!(f, "timed out: {}", reason)
+        }
+    }
+}
+
+impl From<String> for FetchError {
+    fn from(reason: String) -> Self {
+        FetchError::Failed(reason)
+    }
+}
+
+// Where a DatasetProvider gets its encoded tiles from
+pub trait TileSource: Send + Sync + fmt::Debug {
+    fn fetch_tile(&self, coord: IVec3) -> FetchFuture<'_, Vec<u8>>;
+    fn list_tiles(&self) -> SourceFuture<'_, Manifest>;
+    // Sources that describe their own layout, such as archives and COGs
+    fn dataset_info(&self) -> Option<DatasetInfo> {
+        None
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum TileSourceConfig {
+    // Without a manifest the tiles in probe are checked with HEAD requests. max_zoom makes z in the template a web zoom,
+    // see TileTemplate
+    Http {
+        tile_uri_format: String,
+        manifest_uri: Option<String>,
+        #[serde(default)]
+        probe: Option<ProbeRange>,
+        #[serde(default)]
+        http: HttpConfig,
+        #[serde(default)]
+        max_zoom: Option<i32>
+    },
+    // Without a manifest the directory is scanned for files matching the template
+    Local {
+        tile_path_format: String,
+        manifest_uri: Option<String>,
+        #[serde(default)]
+        max_zoom: Option<i32>
+    },
+    // uncompressed tar, tiles are looked up by entry name. Without a manifest entry names are matched against the template
+    Archive {
+        path: String,
+        tile_path_format: String,
+        manifest_entry: Option<String>
+    },
+    // max_zoom defaults to the one stored in the file's metadata
+    MBTiles {
+        path: String,
+        max_zoom: Option<i32>
+    },
+    // local path or URL, remote archives are read with range requests
+    PMTiles {
+        uri: String,
+        max_zoom: Option<i32>,
+        #[serde(default)]
+        http: HttpConfig
+    },
+    // Cloud Optimized GeoTIFF, local path or URL, internal tiles become the dataset's tiles and overviews its levels
+    Cog {
+        uri: String,
+        #[serde(default)]
+        http: HttpConfig
+    }
+}
+
+impl TileSourceConfig {
+    // Picks the local or HTTP source depending on what the tile template points at
+    pub fn from_uris(tile_uri_format: &str, manifest_uri: &str) -> Self {
+        match local_path(tile_uri_format) {
+            Some(path) => TileSourceConfig::Local {
+                tile_path_format: path.to_string_lossy().into_owned(),
+                manifest_uri: Some(manifest_uri.to_string()),
+                max_zoom: None
+            },
+            None => TileSourceConfig::Http {
+                tile_uri_format: tile_uri_format.to_string(),
+                manifest_uri: Some(manifest_uri.to_string()),
+                probe: None,
+                http: HttpConfig::default(),
+                max_zoom: None
+            }
+        }
+    }
+
+    // Whether every file the source reads is fetched over the network
+    pub fn is_remote(&self) -> bool {
+        match self {
+            TileSourceConfig::Http { tile_uri_format, manifest_uri, .. } =>
+                local_path(tile_uri_format).is_none() && manifest_uri.iter().all(|m| local_path(m).is_none()),
+            TileSourceConfig::PMTiles { uri, .. } | TileSourceConfig::Cog { uri, .. } => local_path(uri).is_none(),
+            TileSourceConfig::Local { .. } | TileSourceConfig::Archive { .. } | TileSourceConfig::MBTiles { .. } => false
+        }
+    }
+
+    pub fn http(&self) -> Option<&HttpConfig> {
+        match self {
+            TileSourceConfig::Http { http, .. } | TileSourceConfig::PMTiles { http, .. } | TileSourceConfig::Cog { http, .. } => Some(http),
+            _ => None
+        }
+    }
+
+    pub async fn open(&self) -> Result<Box<dyn TileSource>, String> {
+        Ok(match self {
+            TileSourceConfig::Http { tile_uri_format, manifest_uri, probe, http, max_zoom } =>
+                Box::new(HttpTileSource::new(
+                    TileTemplate::new(tile_uri_format)?.with_max_zoom(*max_zoom), manifest_uri.as_deref(), *probe, client_for(http)?
+                )?),
+            TileSourceConfig::Local { tile_path_format, manifest_uri, max_zoom } =>
+                Box::new(LocalTileSource::new(TileTemplate::new(tile_path_format)?.with_max_zoom(*max_zoom), manifest_uri.as_deref())),
+            TileSourceConfig::Archive { path, tile_path_format, manifest_entry } =>
+                Box::new(ArchiveTileSource::open(path, tile_path_format, manifest_entry.as_deref())?),
+            TileSourceConfig::MBTiles { path, max_zoom } =>
+                Box::new(MBTilesTileSource::open(path, *max_zoom)?),
+            TileSourceConfig::PMTiles { uri, max_zoom, http } =>
+                Box::new(PMTilesTileSource::open(uri, *max_zoom, client_for(http)?).await?),
+            TileSourceConfig::Cog { uri, http } =>
+                Box::new(CogTileSource::open(uri, client_for(http)?).await?),
+        })
+    }
+}
+
+// Sources with the default configuration share one client
+fn client_for(config: &HttpConfig) -> Result<HttpClient, String> {
+    match *config == HttpConfig::default() {
+        true  => Ok(HttpClient::shared()),
+        false => HttpClient::new(config.clone())
+    }
+}
+
+#[derive(Debug)]
+pub struct HttpTileSource {
+    pub template: TileTemplate,
+    pub manifest_uri: Option<String>,
+    pub probe: Option<ProbeRange>,
+    client: HttpClient
+}
+
+impl HttpTileSource {
+    pub fn new(template: TileTemplate, manifest_uri: Option<&str>, probe: Option<ProbeRange>, client: HttpClient) -> Result<Self, String> {
+        if manifest_uri.is_none() && probe.is_none() {
+            return Err(format!("{} needs a manifest or a probe range", template.template.source));
+        }
+        Ok(HttpTileSource {
+            template,
+            manifest_uri: manifest_uri.map(|m| m.to_string()),
+            probe,
+            client
+        })
+    }
+}
+
+impl TileSource for HttpTileSource {
+    fn fetch_tile(&self, coord: IVec3) -> FetchFuture<'_, Vec<u8>> {
+        Box::pin(async move {
+            let uri = self.template.format(coord)?;
+            self.client.get(uri.as_str()).await
+        })
+    }
+    fn list_tiles(&self) -> SourceFuture<'_, Manifest> {
+        Box::pin(async move {
+            match (&self.manifest_uri, self.probe) {
+                (Some(manifest_uri), _) => {
+                    let bytes
+                        =fetch_bytes_with(&self.client, manifest_uri.as_str()).await
+                        .map_err(|e| format!("{}: {}", manifest_uri, e))?;
+                    Manifest::from_json(&bytes[..]).map_err(|e| format!("{}: {}", manifest_uri, e))
+                },
+                (None, Some(probe)) => Manifest::probe(&self.client, &self.template, probe).await,
+                (None, None) => Err("No manifest or probe range".to_string())
+            }
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct LocalTileSource {
+    pub template: TileTemplate,
+    pub manifest_uri: Option<String>
+}
+
+impl LocalTileSource {
+    pub fn new(template: TileTemplate, manifest_uri: Option<&str>) -> Self {
+        LocalTileSource {
+            template,
+            manifest_uri: manifest_uri.map(|m| m.to_string())
+        }
+    }
+}
+
+impl TileSource for LocalTileSource {
+    fn fetch_tile(&self, coord: IVec3) -> FetchFuture<'_, Vec<u8>> {
+        Box::pin(async move {
+            let path = self.template.format(coord)?;
+            tokio::fs::read(path.as_str()).await
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => FetchError::Missing,
+                _ => FetchError::Failed(format!("{}: {}", path, e))
+            })
+        })
+    }
+    fn list_tiles(&self) -> SourceFuture<'_, Manifest> {
+        Box::pin(async move {
+            match &self.manifest_uri {
+                Some(manifest_uri) => {
+                    let bytes = fetch_bytes(manifest_uri.as_str()).await?;
+                    Manifest::from_json(&bytes[..]).map_err(|e| format!("{}: {}", manifest_uri, e))
+                },
+                None => Manifest::scan_directory(&self.template).await
+            }
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ArchiveTileSource {
+    pub path: String,
+    pub template: TileTemplate,
+    pub manifest_entry: Option<String>,
+    // entry name to (data offset, size)
+    entries: HashMap<String, (u64, u64)>
+}
+
+impl ArchiveTileSource {
+    pub fn open(path: &str, tile_path_format: &str, manifest_entry: Option<&str>) -> Result<Self, String> {
+        let template = TileTemplate::new(tile_path_format)?;
+
+        let mut archive = tar::Archive::new(File::open(path).map_err(|e| format!("{}: {}", path, e))?);
+        let mut entries = HashMap::new();
+        for entry in archive.entries().map_err(|e| e.to_string())? {
+            let entry = entry.map_err(|e| e.to_string())?;
+            let name = entry.path().map_err(|e| e.to_string())?.to_string_lossy().into_owned();
+            let name = name.strip_prefix("./").map(|n| n.to_string()).unwrap_or(name);
+            entries.insert(name, (entry.raw_file_position(), entry.size()));
+        }
+
+        Ok(ArchiveTileSource {
+            path: path.to_string(),
+            template,
+            manifest_entry: manifest_entry.map(|m| m.to_string()),
+            entries
+        })
+    }
+
+    pub async fn read_entry(&self, name: &str) -> Result<Vec<u8>, String> {
+        let &(offset, size)
+            =self.entries.get(name)
+            .ok_or(format!("{} has no entry {}", self.path, name))?;
+        let path = self.path.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let mut file = File::open(path.as_str()).map_err(|e| format!("{}: {}", path, e))?;
+            file.seek(SeekFrom::Start(offset)).map_err(|e| e.to_string())?;
+            let mut res = vec![0; size as usize];
+            file.read_exact(&mut res[..]).map_err(|e| e.to_string())?;
+            Ok(res)
+        }).await.map_err(|e| e.to_string())?
+    }
+}
+
+impl TileSource for ArchiveTileSource {
+    fn fetch_tile(&self, coord: IVec3) -> FetchFuture<'_, Vec<u8>> {
+        Box::pin(async move {
+            let name = self.template.format(coord)?;
+            if !self.entries.contains_key(name.as_str()) {
+                return Err(FetchError::Missing);
+            }
+            Ok(self.read_entry(name.as_str()).await?)
+        })
+    }
+    fn list_tiles(&self) -> SourceFuture<'_, Manifest> {
+        Box::pin(async move {
+            match &self.manifest_entry {
+                Some(manifest_entry) => Manifest::from_json(&self.read_entry(manifest_entry.as_str()).await?[..]),
+                None => {
+                    let mut res = Manifest::default();
+                    for (name, &(_, size)) in self.entries.iter() {
+                        if let Some(coord) = self.template.parse_coord(name.as_str()) {
+                            res.insert(coord, TileMetadata::sized(size));
+                        }
+                    }
+                    Ok(res)
+                }
+            }
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryTileSource {
+    pub tiles: HashMap<IVec3, Vec<u8>>
+}
+
+impl TileSource for MemoryTileSource {
+    fn fetch_tile(&self, coord: IVec3) -> FetchFuture<'_, Vec<u8>> {
+        Box::pin(async move {
+            self.tiles.get(&coord).cloned().ok_or(FetchError::Missing)
+        })
+    }
+    fn list_tiles(&self) -> SourceFuture<'_, Manifest> {
+        Box::pin(async move {
+            Ok(self.tiles.iter().map(|(&coord, data)| (coord, TileMetadata::sized(data.len() as u64))).collect())
+        })
+    }
+}
@@ -1,34 +1,220 @@
 use regex::Regex;
 use std::collections::HashMap;
+use std::convert::TryFrom;
 
 pub trait Formattable {
     fn format(&self, fmt: &str) -> Result<String, String>;
 }
 
+#[derive(Debug, Copy, Clone)]
+pub struct IntegerSpec {
+    pub sign: bool,
+    pub width: usize,
+    // Some(uppercase) for hex
+    pub hex: Option<bool>
+}
+
+// Spec is [+][width][x|X], e.g. "3" pads to 3 digits, "+3" always writes a sign and "4x" is 4 hex digits.
+// An empty spec writes the plain number. Without + the padding goes in front of a minus, -5 at width 3 is 0-5 like
+// templates have always written it
+impl IntegerSpec {
+    pub fn parse(fmt: &str) -> Result<Self, String> {
+        let (sign, rest) = match fmt.strip_prefix('+') {
+            Some(rest) => (true, rest),
+            None => (false, fmt)
+        };
+        let (hex, digits) = match rest.char_indices().last() {
+            Some((i, 'x')) => (Some(false), &rest[..i]),
+            Some((i, 'X')) => (Some(true), &rest[..i]),
+            _ => (None, rest)
+        };
+        let width: usize = match digits {
+            "" => 0,
+            digits => digits.parse().map_err(|_e| format!("Invalid i32 format {}, expected [+][width][x|X]", fmt))?
+        };
+        Ok(IntegerSpec { sign, width, hex })
+    }
+
+    // Regex matching what this spec writes
+    pub fn pattern(&self) -> String {
+        let digits = match self.hex {
+            None => "[0-9]",
+            Some(_) => "[0-9a-fA-F]"
+        };
+        match self.sign {
+            true  => format!("[+-]{}{{{},}}", digits, self.width.max(1)),
+            false => format!("\\+?{0}{{{1},}}|0*-{0}+", digits, self.width.max(1))
+        }
+    }
+
+    pub fn read(&self, text: &str) -> Option<i32> {
+        let text = match text.trim_start_matches('0') {
+            unpadded if unpadded.starts_with('-') => unpadded,
+            _ => text
+        };
+        let (negative, digits) = match text.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, text.strip_prefix('+').unwrap_or(text))
+        };
+        let radix = match self.hex {
+            None => 10,
+            Some(_) => 16
+        };
+        let magnitude = i64::from_str_radix(digits, radix).ok()?;
+        i32::try_from(if negative { -magnitude } else { magnitude }).ok()
+    }
+}
+
 impl Formattable for i32 {
-    fn format(&self, fmt: &str) -> Result<String, String> { 
-        let pad: usize = fmt.parse().map_err(|_e| "Format string for i32 must be an integer".to_string())?;
+    fn format(&self, fmt: &str) -> Result<String, String> {
+        let spec = IntegerSpec::parse(fmt)?;
 
-        Ok(format!("{1:0>0$}", pad, *self))
+        let sign_str = match (*self < 0, spec.sign) {
+            (true, _) => "-",
+            (false, true) => "+",
+            (false, false) => ""
+        };
+        let magnitude = self.unsigned_abs();
+        let digits = match spec.hex {
+            None => magnitude.to_string(),
+            Some(false) => format!("{:x}", magnitude),
+            Some(true) => format!("{:X}", magnitude)
+        };
+
+        Ok(match spec.sign {
+            true  => format!("{}{:0>2$}", sign_str, digits, spec.width),
+            false => format!("{:0>1$}", format!("{}{}", sign_str, digits), spec.width)
+        })
     }
 }
 
-pub fn format(template: &str, values: &HashMap<&str, &dyn Formattable>) -> Result<String, String> {
-    let mut res = template.to_string();
-    let re = Regex::new(r"\{(.*?):(.*?)\}").unwrap();
+impl Formattable for String {
+    fn format(&self, fmt: &str) -> Result<String, String> {
+        match fmt {
+            "" => Ok(self.clone()),
+            _ => Err(format!("Strings take no format, got {}", fmt))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Key {
+        key: String,
+        spec: String
+    }
+}
 
-    for c in re.captures_iter(template) {
-        let capture = c.get(0).unwrap().as_str();
-        let key = c.get(1).ok_or(format!("Invalid format string - missing key in {}", capture))?.as_str();
-        let value_fmt = c.get(2).ok_or(format!("Invalid format string - missing format in {}", capture))?.as_str();
+// A template parsed once. Keys are written as {key} or {key:spec}, {{ and }} are literal braces
+#[derive(Debug, Clone)]
+pub struct UriTemplate {
+    pub source: String,
+    segments: Vec<Segment>
+}
+
+impl UriTemplate {
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut segments = vec![];
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match (c, chars.peek()) {
+                ('{', Some('{')) | ('}', Some('}')) => {
+                    chars.next();
+                    literal.push(c);
+                },
+                ('{', _) => {
+                    let mut inner = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some('{') | None => return Err(format!("Invalid format string - unclosed {{ in {}", template)),
+                            Some(c) => inner.push(c)
+                        }
+                    }
+                    let (key, spec) = match inner.split_once(':') {
+                        Some((key, spec)) => (key, spec),
+                        None => (inner.as_str(), "")
+                    };
+                    if key.is_empty() {
+                        return Err(format!("Invalid format string - missing key in {{{}}}", inner));
+                    }
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Segment::Key { key: key.to_string(), spec: spec.to_string() });
+                },
+                ('}', _) => return Err(format!("Invalid format string - unmatched }} in {}", template)),
+                (c, _) => literal.push(c)
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        Ok(UriTemplate {
+            source: template.to_string(),
+            segments
+        })
+    }
 
-        let fmt = values.get(key).ok_or(format!("No value provided for key {}", key))?;
-        let replace = fmt.format(value_fmt)?;
-        
-        res = res.replace(capture, replace.as_str()).to_string();
+    // Text before the first placeholder
+    pub fn literal_prefix(&self) -> &str {
+        match self.segments.first() {
+            Some(Segment::Literal(literal)) => literal.as_str(),
+            _ => ""
+        }
     }
 
-    return Ok(res);
+    // (key, spec) of every placeholder in order
+    pub fn keys(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.segments.iter().filter_map(|s| match s {
+            Segment::Key { key, spec } => Some((key.as_str(), spec.as_str())),
+            Segment::Literal(_) => None
+        })
+    }
+
+    pub fn format(&self, values: &HashMap<&str, &dyn Formattable>) -> Result<String, String> {
+        let mut res = String::new();
+        for segment in self.segments.iter() {
+            match segment {
+                Segment::Literal(literal) => res.push_str(literal.as_str()),
+                Segment::Key { key, spec } => {
+                    let value = values.get(key.as_str()).ok_or(format!("No value provided for key {}", key))?;
+                    res.push_str(value.format(spec.as_str())?.as_str());
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    // Regex matching formatted URIs, pattern gives the regex for a (key, spec) and rejects the ones it doesn't know
+    pub fn reverse_regex<F>(&self, pattern: F) -> Result<Regex, String>
+    where F: Fn(&str, &str) -> Result<String, String> {
+        let mut res = "^".to_string();
+        for segment in self.segments.iter() {
+            match segment {
+                Segment::Literal(literal) => res.push_str(regex::escape(literal.as_str()).as_str()),
+                Segment::Key { key, spec } => res.push_str(format!("({})", pattern(key.as_str(), spec.as_str())?).as_str())
+            }
+        }
+        res.push('$');
+        Regex::new(res.as_str()).map_err(|e| e.to_string())
+    }
+
+    // (key, spec, text) of every placeholder if uri matches the regex from reverse_regex
+    pub fn captures<'a>(&'a self, regex: &Regex, uri: &'a str) -> Option<Vec<(&'a str, &'a str, &'a str)>> {
+        let captures = regex.captures(uri)?;
+        Some(self.keys().enumerate().filter_map(|(i, (key, spec))| {
+            captures.get(i + 1).map(|m| (key, spec, m.as_str()))
+        }).collect())
+    }
+}
+
+pub fn format(template: &str, values: &HashMap<&str, &dyn Formattable>) -> Result<String, String> {
+    UriTemplate::parse(template)?.format(values)
 }
 
 #[macro_export]
@@ -39,3 +225,56 @@ macro_rules! uri_fmt {
         crate::uri_format::format($fmt, &map)
     }};
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format_xy(template: &str, x: i32, y: i32) -> Result<String, String> {
+        let mut values = HashMap::<&str, &dyn Formattable>::new();
+        values.insert("x", &x);
+        values.insert("y", &y);
+        UriTemplate::parse(template)?.format(&values)
+    }
+
+    #[test]
+    fn writes_doubled_braces_as_literals() {
+        assert_eq!(format_xy("{{x}}/{x}/{{{y}}}", 1, 2).unwrap(), "{x}/1/{2}");
+        let template = UriTemplate::parse("{{{x}").unwrap();
+        assert_eq!(template.literal_prefix(), "{");
+        assert_eq!(template.keys().collect::<Vec<_>>(), vec![("x", "")]);
+    }
+
+    #[test]
+    fn rejects_malformed_templates() {
+        for template in ["{x", "x}", "{}", "{:3}", "{x{y}}", "}{x}"] {
+            assert!(UriTemplate::parse(template).is_err(), "{}", template);
+        }
+        assert!(format_xy("{z}", 1, 2).is_err());
+        assert!(format_xy("{x:abc}", 1, 2).is_err());
+        assert!(format_xy("{x:3y}", 1, 2).is_err());
+        assert!("a".to_string().format("3").is_err());
+    }
+
+    #[test]
+    fn integers_round_trip_with_padding_signs_and_hex() {
+        let cases: [(&str, i32, &str); 9] = [
+            ("", 5, "5"),
+            ("", -5, "-5"),
+            ("3", 5, "005"),
+            ("3", -5, "0-5"),
+            ("+3", 5, "+005"),
+            ("+3", -5, "-005"),
+            ("4x", 255, "00ff"),
+            ("X", 255, "FF"),
+            ("+x", -255, "-ff")
+        ];
+        for (spec, value, text) in cases.iter() {
+            assert_eq!(value.format(spec).unwrap(), *text, "{} with {}", value, spec);
+            let spec = IntegerSpec::parse(spec).unwrap();
+            assert!(Regex::new(format!("^(?:{})$", spec.pattern()).as_str()).unwrap().is_match(text), "{}", text);
+            assert_eq!(spec.read(text), Some(*value), "{}", text);
+        }
+        assert_eq!(IntegerSpec::parse("").unwrap().read("99999999999"), None);
+    }
+}